@@ -1,89 +1,330 @@
 use anyhow::Result;
+use log::debug;
+
+const FOUR_STEP_CYCLES: [u32; 4] = [7457, 14913, 22371, 29829];
+const FIVE_STEP_CYCLES: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+const DMC_PERIODS: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelState {
+    pub period: u16,
+    pub volume: u8,
+    pub length_counter: u8,
+    pub enabled: bool,
+}
 
-pub struct Apu {}
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ApuState {
+    pub pulse1: ChannelState,
+    pub pulse2: ChannelState,
+    pub triangle: ChannelState,
+    pub noise: ChannelState,
+    pub dmc: ChannelState,
+}
 
-impl Apu {
-    pub fn new() -> Self {
-        Self {}
-    }
+#[derive(Default)]
+struct Channel {
+    period: u16,
+    volume: u8,
+    length_counter: u8,
+    halt: bool,
+    enabled: bool,
+}
 
-    pub fn read_square_ch1_control1(&self) -> Result<u8> {
-        Ok(0)
+impl Channel {
+    fn load_length(&mut self, data: u8) {
+        if self.enabled {
+            self.length_counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
     }
 
-    pub fn read_square_ch1_control2(&self) -> Result<u8> {
-        Ok(0)
-    }
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
 
-    pub fn read_square_ch1_freq1(&self) -> Result<u8> {
-        Ok(0)
+        if !enabled {
+            self.length_counter = 0;
+        }
     }
 
-    pub fn read_square_ch1_freq2(&self) -> Result<u8> {
-        Ok(0)
+    fn clock_length(&mut self) {
+        if !self.halt && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
     }
 
-    pub fn read_square_ch2_control1(&self) -> Result<u8> {
-        Ok(0)
+    fn state(&self) -> ChannelState {
+        ChannelState {
+            period: self.period,
+            volume: self.volume,
+            length_counter: self.length_counter,
+            enabled: self.enabled,
+        }
     }
+}
+
+pub struct Apu {
+    cycles: u64,
+
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycles: u32,
+    frame_reset_delay: u8,
+
+    quarter_frames: u64,
+    half_frames: u64,
+
+    pulse1: Channel,
+    pulse2: Channel,
+    triangle: Channel,
+    noise: Channel,
+    dmc: Channel,
+
+    // サンプルの残りバイト数だけ数えて、再生が終わったときにIRQを立てる
+    // (メモリからの読み込みと出力はまだない)
+    dmc_irq_enabled: bool,
+    dmc_irq: bool,
+    dmc_loop: bool,
+    dmc_sample_length: u16,
+    dmc_bytes_remaining: u16,
+    dmc_timer: u16,
+    dmc_bits_remaining: u8,
+}
 
-    pub fn read_square_ch2_control2(&self) -> Result<u8> {
-        Ok(0)
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            cycles: 0,
+
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycles: 0,
+            frame_reset_delay: 0,
+
+            quarter_frames: 0,
+            half_frames: 0,
+
+            pulse1: Default::default(),
+            pulse2: Default::default(),
+            triangle: Default::default(),
+            noise: Default::default(),
+            dmc: Channel {
+                period: DMC_PERIODS[0],
+                ..Default::default()
+            },
+
+            dmc_irq_enabled: false,
+            dmc_irq: false,
+            dmc_loop: false,
+            dmc_sample_length: 1,
+            dmc_bytes_remaining: 0,
+            dmc_timer: 0,
+            dmc_bits_remaining: 8,
+        }
+    }
+
+    pub fn tick(&mut self) -> Result<()> {
+        self.cycles = self.cycles.wrapping_add(1);
+
+        self.step_dmc();
+
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+
+            if self.frame_reset_delay == 0 {
+                self.frame_cycles = 0;
+
+                // 5ステップモードにするとすぐにクロックされる
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+
+                return Ok(());
+            }
+        }
+
+        self.frame_cycles += 1;
+
+        if self.five_step {
+            self.step_five_step();
+        } else {
+            self.step_four_step();
+        }
+
+        Ok(())
     }
 
-    pub fn read_square_ch2_freq1(&self) -> Result<u8> {
-        Ok(0)
+    // リセットは$4015に0を書き、$4017に直前の値を書き直したのと同じになる
+    pub fn reset(&mut self) -> Result<()> {
+        self.write_voice_control(0)?;
+
+        let frame_counter = ((self.five_step as u8) << 7) | ((self.irq_inhibit as u8) << 6);
+
+        self.write_frame_counter(frame_counter)?;
+        self.frame_irq = false;
+
+        self.dmc.volume &= 0x01;
+
+        Ok(())
     }
 
-    pub fn read_square_ch2_freq2(&self) -> Result<u8> {
-        Ok(0)
+    fn step_four_step(&mut self) {
+        match self.frame_cycles {
+            c if c == FOUR_STEP_CYCLES[0] || c == FOUR_STEP_CYCLES[2] => {
+                self.clock_quarter_frame();
+            }
+            c if c == FOUR_STEP_CYCLES[1] => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            c if c == FOUR_STEP_CYCLES[3] => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+
+                if !self.irq_inhibit {
+                    self.frame_irq = true;
+                }
+
+                self.frame_cycles = 0;
+            }
+            _ => {}
+        }
     }
 
-    pub fn read_sign_control(&self) -> Result<u8> {
-        Ok(0)
+    fn step_five_step(&mut self) {
+        match self.frame_cycles {
+            c if c == FIVE_STEP_CYCLES[0] || c == FIVE_STEP_CYCLES[2] => {
+                self.clock_quarter_frame();
+            }
+            c if c == FIVE_STEP_CYCLES[1] => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            c if c == FIVE_STEP_CYCLES[4] => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+
+                self.frame_cycles = 0;
+            }
+            _ => {}
+        }
     }
 
-    pub fn read_sign_freq1(&self) -> Result<u8> {
-        Ok(0)
+    // 出力ユニットが8ビット出し終えるたびに、次のサンプルバイトを読む
+    fn step_dmc(&mut self) {
+        if self.dmc_timer > 0 {
+            self.dmc_timer -= 1;
+
+            return;
+        }
+
+        self.dmc_timer = self.dmc.period - 1;
+        self.dmc_bits_remaining -= 1;
+
+        if self.dmc_bits_remaining == 0 {
+            self.dmc_bits_remaining = 8;
+            self.fetch_dmc_byte();
+        }
     }
 
-    pub fn read_sign_freq2(&self) -> Result<u8> {
-        Ok(0)
+    fn fetch_dmc_byte(&mut self) {
+        if self.dmc_bytes_remaining == 0 {
+            return;
+        }
+
+        self.dmc_bytes_remaining -= 1;
+
+        if self.dmc_bytes_remaining == 0 {
+            if self.dmc_loop {
+                self.dmc_bytes_remaining = self.dmc_sample_length;
+            } else if self.dmc_irq_enabled {
+                self.dmc_irq = true;
+            }
+        }
     }
 
-    pub fn read_noise_control(&self) -> Result<u8> {
-        Ok(0)
+    fn clock_quarter_frame(&mut self) {
+        self.quarter_frames += 1;
     }
 
-    pub fn read_noise_rand(&self) -> Result<u8> {
-        Ok(0)
+    fn clock_half_frame(&mut self) {
+        self.half_frames += 1;
+
+        self.pulse1.clock_length();
+        self.pulse2.clock_length();
+        self.triangle.clock_length();
+        self.noise.clock_length();
     }
 
-    pub fn read_noise_duration(&self) -> Result<u8> {
-        Ok(0)
+    pub fn quarter_frames(&self) -> u64 {
+        self.quarter_frames
     }
 
-    pub fn read_dpcm_control1(&self) -> Result<u8> {
-        Ok(0)
+    pub fn half_frames(&self) -> u64 {
+        self.half_frames
     }
 
-    pub fn read_dpcm_control2(&self) -> Result<u8> {
-        Ok(0)
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc_irq
     }
 
-    pub fn read_dpcm_control3(&self) -> Result<u8> {
-        Ok(0)
+    // デバッガ向けのスナップショットで、$4015の読み込みとは違って副作用はない
+    pub fn debug_state(&self) -> ApuState {
+        ApuState {
+            pulse1: self.pulse1.state(),
+            pulse2: self.pulse2.state(),
+            triangle: self.triangle.state(),
+            noise: self.noise.state(),
+            dmc: self.dmc.state(),
+        }
     }
 
-    pub fn read_dpcm_control4(&self) -> Result<u8> {
-        Ok(0)
+    pub fn peek_voice_control(&self) -> u8 {
+        let mut status = ((self.dmc_irq as u8) << 7) | ((self.frame_irq as u8) << 6);
+
+        if self.dmc_bytes_remaining > 0 {
+            status |= 0x10;
+        }
+
+        for (i, channel) in [&self.pulse1, &self.pulse2, &self.triangle, &self.noise]
+            .iter()
+            .enumerate()
+        {
+            if channel.length_counter > 0 {
+                status |= 1 << i;
+            }
+        }
+
+        status
     }
 
-    pub fn read_voice_control(&self) -> Result<u8> {
-        Ok(0)
+    // 読み込みでクリアされるのはフレームIRQだけで、DMCのIRQは残る
+    pub fn read_voice_control(&mut self) -> Result<u8> {
+        let status = self.peek_voice_control();
+
+        self.frame_irq = false;
+
+        Ok(status)
     }
 
     pub fn write_square_ch1_control1(&mut self, data: u8) -> Result<()> {
+        self.pulse1.halt = (data >> 5) & 1 == 1;
+        self.pulse1.volume = data & 0x0F;
+
         Ok(())
     }
 
@@ -92,14 +333,22 @@ impl Apu {
     }
 
     pub fn write_square_ch1_freq1(&mut self, data: u8) -> Result<()> {
+        self.pulse1.period = (self.pulse1.period & 0x0700) | data as u16;
+
         Ok(())
     }
 
     pub fn write_square_ch1_freq2(&mut self, data: u8) -> Result<()> {
+        self.pulse1.period = (self.pulse1.period & 0x00FF) | ((data as u16 & 0x07) << 8);
+        self.pulse1.load_length(data);
+
         Ok(())
     }
 
     pub fn write_square_ch2_control1(&mut self, data: u8) -> Result<()> {
+        self.pulse2.halt = (data >> 5) & 1 == 1;
+        self.pulse2.volume = data & 0x0F;
+
         Ok(())
     }
 
@@ -108,42 +357,71 @@ impl Apu {
     }
 
     pub fn write_square_ch2_freq1(&mut self, data: u8) -> Result<()> {
+        self.pulse2.period = (self.pulse2.period & 0x0700) | data as u16;
+
         Ok(())
     }
 
     pub fn write_square_ch2_freq2(&mut self, data: u8) -> Result<()> {
+        self.pulse2.period = (self.pulse2.period & 0x00FF) | ((data as u16 & 0x07) << 8);
+        self.pulse2.load_length(data);
+
         Ok(())
     }
 
     pub fn write_sign_control(&mut self, data: u8) -> Result<()> {
+        self.triangle.halt = data >> 7 == 1;
+
         Ok(())
     }
 
     pub fn write_sign_freq1(&mut self, data: u8) -> Result<()> {
+        self.triangle.period = (self.triangle.period & 0x0700) | data as u16;
+
         Ok(())
     }
 
     pub fn write_sign_freq2(&mut self, data: u8) -> Result<()> {
+        self.triangle.period = (self.triangle.period & 0x00FF) | ((data as u16 & 0x07) << 8);
+        self.triangle.load_length(data);
+
         Ok(())
     }
 
     pub fn write_noise_control(&mut self, data: u8) -> Result<()> {
+        self.noise.halt = (data >> 5) & 1 == 1;
+        self.noise.volume = data & 0x0F;
+
         Ok(())
     }
 
     pub fn write_noise_rand(&mut self, data: u8) -> Result<()> {
+        self.noise.period = NOISE_PERIODS[(data & 0x0F) as usize];
+
         Ok(())
     }
 
     pub fn write_noise_duration(&mut self, data: u8) -> Result<()> {
+        self.noise.load_length(data);
+
         Ok(())
     }
 
     pub fn write_dpcm_control1(&mut self, data: u8) -> Result<()> {
+        self.dmc.period = DMC_PERIODS[(data & 0x0F) as usize];
+        self.dmc_irq_enabled = data >> 7 == 1;
+        self.dmc_loop = (data >> 6) & 1 == 1;
+
+        if !self.dmc_irq_enabled {
+            self.dmc_irq = false;
+        }
+
         Ok(())
     }
 
     pub fn write_dpcm_control2(&mut self, data: u8) -> Result<()> {
+        self.dmc.volume = data & 0x7F;
+
         Ok(())
     }
 
@@ -152,10 +430,210 @@ impl Apu {
     }
 
     pub fn write_dpcm_control4(&mut self, data: u8) -> Result<()> {
+        self.dmc_sample_length = ((data as u16) << 4) + 1;
+
         Ok(())
     }
 
     pub fn write_voice_control(&mut self, data: u8) -> Result<()> {
+        self.pulse1.set_enabled(data & 0x01 != 0);
+        self.pulse2.set_enabled(data & 0x02 != 0);
+        self.triangle.set_enabled(data & 0x04 != 0);
+        self.noise.set_enabled(data & 0x08 != 0);
+        self.dmc.set_enabled(data & 0x10 != 0);
+
+        self.dmc_irq = false;
+
+        // 止めると残りは0に、再生が終わっていれば先頭から読み直す
+        if data & 0x10 == 0 {
+            self.dmc_bytes_remaining = 0;
+        } else if self.dmc_bytes_remaining == 0 {
+            self.dmc_bytes_remaining = self.dmc_sample_length;
+
+            // バッファが空なので、すぐに最初のバイトを読む
+            self.fetch_dmc_byte();
+        }
+
         Ok(())
     }
+
+    pub fn write_frame_counter(&mut self, data: u8) -> Result<()> {
+        self.five_step = data >> 7 == 1;
+        self.irq_inhibit = (data >> 6) & 1 == 1;
+
+        if self.irq_inhibit {
+            self.frame_irq = false;
+        }
+
+        // リセットは書き込みから3〜4サイクル遅れて反映される
+        self.frame_reset_delay = if self.cycles & 1 == 0 { 3 } else { 4 };
+
+        debug!("WRITE FRAME COUNTER: {:#02X}", data);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn five_step_write_clocks_after_the_reset_delay() {
+        for &(data, clocked) in [(0x80, true), (0x00, false)].iter() {
+            let mut apu = Apu::new();
+
+            apu.write_voice_control(0x01).unwrap();
+            apu.write_square_ch1_freq2(0x00).unwrap();
+
+            assert_eq!(apu.debug_state().pulse1.length_counter, 10);
+
+            // 偶数サイクルでの書き込みなので、3サイクル後に反映される
+            apu.write_frame_counter(data).unwrap();
+
+            apu.tick().unwrap();
+            apu.tick().unwrap();
+
+            assert_eq!(apu.half_frames(), 0, "{:#04X}", data);
+
+            apu.tick().unwrap();
+
+            assert_eq!(apu.quarter_frames(), clocked as u64, "{:#04X}", data);
+            assert_eq!(apu.half_frames(), clocked as u64, "{:#04X}", data);
+            assert_eq!(
+                apu.debug_state().pulse1.length_counter,
+                10 - clocked as u8,
+                "{:#04X}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn debug_state_reflects_the_pulse1_registers() {
+        let mut apu = Apu::new();
+
+        apu.write_voice_control(0x01).unwrap();
+        apu.write_square_ch1_control1(0x3A).unwrap();
+        apu.write_square_ch1_freq1(0x34).unwrap();
+        apu.write_square_ch1_freq2(0x0D).unwrap();
+
+        let state = apu.debug_state();
+
+        assert_eq!(
+            state.pulse1,
+            ChannelState {
+                period: 0x0534,
+                volume: 0x0A,
+                length_counter: 254,
+                enabled: true,
+            }
+        );
+        assert_eq!(state.pulse2, ChannelState::default());
+
+        // $4015の読み込みと違って、何度読んでも変わらない
+        assert_eq!(apu.debug_state(), state);
+    }
+
+    #[test]
+    fn reset_silences_every_channel() {
+        let mut apu = Apu::new();
+
+        apu.write_voice_control(0x1F).unwrap();
+        apu.write_square_ch1_freq2(0x08).unwrap();
+        apu.write_square_ch2_freq2(0x08).unwrap();
+        apu.write_sign_freq2(0x08).unwrap();
+        apu.write_noise_duration(0x08).unwrap();
+
+        assert_eq!(apu.peek_voice_control() & 0x0F, 0x0F);
+
+        apu.reset().unwrap();
+
+        assert_eq!(apu.read_voice_control().unwrap(), 0x00);
+
+        let state = apu.debug_state();
+
+        for channel in [
+            state.pulse1,
+            state.pulse2,
+            state.triangle,
+            state.noise,
+            state.dmc,
+        ]
+        .iter()
+        {
+            assert!(!channel.enabled);
+            assert_eq!(channel.length_counter, 0);
+        }
+    }
+
+    #[test]
+    fn status_read_clears_only_the_frame_irq() {
+        let mut apu = Apu::new();
+
+        // 4ステップの最後でフレームIRQが立つ
+        apu.write_frame_counter(0x00).unwrap();
+
+        while !apu.irq() {
+            apu.tick().unwrap();
+        }
+
+        // IRQ有効・ループなしで1バイトのサンプルは、読み始めてすぐ終わる
+        apu.write_dpcm_control1(0x80).unwrap();
+        apu.write_dpcm_control4(0x00).unwrap();
+        apu.write_voice_control(0x10).unwrap();
+
+        assert_eq!(apu.peek_voice_control() & 0xC0, 0xC0);
+        assert_eq!(apu.read_voice_control().unwrap() & 0xC0, 0xC0);
+
+        assert_eq!(apu.read_voice_control().unwrap() & 0xC0, 0x80);
+        assert!(apu.irq());
+
+        // $4015への書き込みでDMCのIRQも落ちる
+        apu.write_voice_control(0x00).unwrap();
+
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn dmc_irq_fires_when_the_sample_ends() {
+        let mut apu = Apu::new();
+
+        // 周期54、17バイトのサンプル。最初の1バイトはすぐに読み、残りは8ビットごと
+        apu.write_frame_counter(0x40).unwrap();
+        apu.write_dpcm_control1(0x8F).unwrap();
+        apu.write_dpcm_control4(0x01).unwrap();
+        apu.write_voice_control(0x10).unwrap();
+
+        let sample_cycles = 16 * 8 * 54;
+
+        for _ in 0..(sample_cycles - 8 * 54) {
+            apu.tick().unwrap();
+        }
+
+        assert!(!apu.irq());
+        assert_eq!(apu.peek_voice_control() & 0x10, 0x10);
+
+        for _ in 0..(2 * 8 * 54) {
+            apu.tick().unwrap();
+        }
+
+        assert!(apu.irq());
+        assert_eq!(apu.peek_voice_control() & 0x90, 0x80);
+
+        // ループするサンプルは終わらずにIRQも立たない
+        let mut apu = Apu::new();
+
+        apu.write_frame_counter(0x40).unwrap();
+        apu.write_dpcm_control1(0xCF).unwrap();
+        apu.write_dpcm_control4(0x00).unwrap();
+        apu.write_voice_control(0x10).unwrap();
+
+        for _ in 0..(4 * 8 * 54) {
+            apu.tick().unwrap();
+        }
+
+        assert!(!apu.irq());
+        assert_eq!(apu.peek_voice_control() & 0x10, 0x10);
+    }
 }
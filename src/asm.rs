@@ -0,0 +1,411 @@
+use anyhow::{bail, Context, Result};
+
+use crate::cpu::AddrMode;
+
+const OPCODES: [(u8, &str, Option<AddrMode>); 151] = [
+    (0x00, "BRK", None),
+    (0x01, "ORA", Some(AddrMode::IndexedIndirectX)),
+    (0x05, "ORA", Some(AddrMode::ZeroPage)),
+    (0x06, "ASL", Some(AddrMode::ZeroPage)),
+    (0x08, "PHP", None),
+    (0x09, "ORA", Some(AddrMode::Immediate)),
+    (0x0A, "ASL", Some(AddrMode::Accumulator)),
+    (0x0D, "ORA", Some(AddrMode::Absolute)),
+    (0x0E, "ASL", Some(AddrMode::Absolute)),
+    (0x10, "BPL", Some(AddrMode::Relative)),
+    (0x11, "ORA", Some(AddrMode::IndirectIndexedY)),
+    (0x15, "ORA", Some(AddrMode::ZeroPageIndexedX)),
+    (0x16, "ASL", Some(AddrMode::ZeroPageIndexedX)),
+    (0x18, "CLC", None),
+    (0x19, "ORA", Some(AddrMode::AbsoluteIndexedY)),
+    (0x1D, "ORA", Some(AddrMode::AbsoluteIndexedX)),
+    (0x1E, "ASL", Some(AddrMode::AbsoluteIndexedX)),
+    (0x20, "JSR", Some(AddrMode::Absolute)),
+    (0x21, "AND", Some(AddrMode::IndexedIndirectX)),
+    (0x24, "BIT", Some(AddrMode::ZeroPage)),
+    (0x25, "AND", Some(AddrMode::ZeroPage)),
+    (0x26, "ROL", Some(AddrMode::ZeroPage)),
+    (0x28, "PLP", None),
+    (0x29, "AND", Some(AddrMode::Immediate)),
+    (0x2A, "ROL", Some(AddrMode::Accumulator)),
+    (0x2C, "BIT", Some(AddrMode::Absolute)),
+    (0x2D, "AND", Some(AddrMode::Absolute)),
+    (0x2E, "ROL", Some(AddrMode::Absolute)),
+    (0x30, "BMI", Some(AddrMode::Relative)),
+    (0x31, "AND", Some(AddrMode::IndirectIndexedY)),
+    (0x35, "AND", Some(AddrMode::ZeroPageIndexedX)),
+    (0x36, "ROL", Some(AddrMode::ZeroPageIndexedX)),
+    (0x38, "SEC", None),
+    (0x39, "AND", Some(AddrMode::AbsoluteIndexedY)),
+    (0x3D, "AND", Some(AddrMode::AbsoluteIndexedX)),
+    (0x3E, "ROL", Some(AddrMode::AbsoluteIndexedX)),
+    (0x40, "RTI", None),
+    (0x41, "EOR", Some(AddrMode::IndexedIndirectX)),
+    (0x45, "EOR", Some(AddrMode::ZeroPage)),
+    (0x46, "LSR", Some(AddrMode::ZeroPage)),
+    (0x48, "PHA", None),
+    (0x49, "EOR", Some(AddrMode::Immediate)),
+    (0x4A, "LSR", Some(AddrMode::Accumulator)),
+    (0x4C, "JMP", Some(AddrMode::Absolute)),
+    (0x4D, "EOR", Some(AddrMode::Absolute)),
+    (0x4E, "LSR", Some(AddrMode::Absolute)),
+    (0x50, "BVC", Some(AddrMode::Relative)),
+    (0x51, "EOR", Some(AddrMode::IndirectIndexedY)),
+    (0x55, "EOR", Some(AddrMode::ZeroPageIndexedX)),
+    (0x56, "LSR", Some(AddrMode::ZeroPageIndexedX)),
+    (0x58, "CLI", None),
+    (0x59, "EOR", Some(AddrMode::AbsoluteIndexedY)),
+    (0x5D, "EOR", Some(AddrMode::AbsoluteIndexedX)),
+    (0x5E, "LSR", Some(AddrMode::AbsoluteIndexedX)),
+    (0x60, "RTS", None),
+    (0x61, "ADC", Some(AddrMode::IndexedIndirectX)),
+    (0x65, "ADC", Some(AddrMode::ZeroPage)),
+    (0x66, "ROR", Some(AddrMode::ZeroPage)),
+    (0x68, "PLA", None),
+    (0x69, "ADC", Some(AddrMode::Immediate)),
+    (0x6A, "ROR", Some(AddrMode::Accumulator)),
+    (0x6C, "JMP", Some(AddrMode::Indirect)),
+    (0x6D, "ADC", Some(AddrMode::Absolute)),
+    (0x6E, "ROR", Some(AddrMode::Absolute)),
+    (0x70, "BVS", Some(AddrMode::Relative)),
+    (0x71, "ADC", Some(AddrMode::IndirectIndexedY)),
+    (0x75, "ADC", Some(AddrMode::ZeroPageIndexedX)),
+    (0x76, "ROR", Some(AddrMode::ZeroPageIndexedX)),
+    (0x78, "SEI", None),
+    (0x79, "ADC", Some(AddrMode::AbsoluteIndexedY)),
+    (0x7D, "ADC", Some(AddrMode::AbsoluteIndexedX)),
+    (0x7E, "ROR", Some(AddrMode::AbsoluteIndexedX)),
+    (0x81, "STA", Some(AddrMode::IndexedIndirectX)),
+    (0x84, "STY", Some(AddrMode::ZeroPage)),
+    (0x85, "STA", Some(AddrMode::ZeroPage)),
+    (0x86, "STX", Some(AddrMode::ZeroPage)),
+    (0x88, "DEY", None),
+    (0x8A, "TXA", None),
+    (0x8C, "STY", Some(AddrMode::Absolute)),
+    (0x8D, "STA", Some(AddrMode::Absolute)),
+    (0x8E, "STX", Some(AddrMode::Absolute)),
+    (0x90, "BCC", Some(AddrMode::Relative)),
+    (0x91, "STA", Some(AddrMode::IndirectIndexedY)),
+    (0x94, "STY", Some(AddrMode::ZeroPageIndexedX)),
+    (0x95, "STA", Some(AddrMode::ZeroPageIndexedX)),
+    (0x96, "STX", Some(AddrMode::ZeroPageIndexedY)),
+    (0x98, "TYA", None),
+    (0x99, "STA", Some(AddrMode::AbsoluteIndexedY)),
+    (0x9A, "TXS", None),
+    (0x9D, "STA", Some(AddrMode::AbsoluteIndexedX)),
+    (0xA0, "LDY", Some(AddrMode::Immediate)),
+    (0xA1, "LDA", Some(AddrMode::IndexedIndirectX)),
+    (0xA2, "LDX", Some(AddrMode::Immediate)),
+    (0xA4, "LDY", Some(AddrMode::ZeroPage)),
+    (0xA5, "LDA", Some(AddrMode::ZeroPage)),
+    (0xA6, "LDX", Some(AddrMode::ZeroPage)),
+    (0xA8, "TAY", None),
+    (0xA9, "LDA", Some(AddrMode::Immediate)),
+    (0xAA, "TAX", None),
+    (0xAC, "LDY", Some(AddrMode::Absolute)),
+    (0xAD, "LDA", Some(AddrMode::Absolute)),
+    (0xAE, "LDX", Some(AddrMode::Absolute)),
+    (0xB0, "BCS", Some(AddrMode::Relative)),
+    (0xB1, "LDA", Some(AddrMode::IndirectIndexedY)),
+    (0xB4, "LDY", Some(AddrMode::ZeroPageIndexedX)),
+    (0xB5, "LDA", Some(AddrMode::ZeroPageIndexedX)),
+    (0xB6, "LDX", Some(AddrMode::ZeroPageIndexedY)),
+    (0xB8, "CLV", None),
+    (0xB9, "LDA", Some(AddrMode::AbsoluteIndexedY)),
+    (0xBA, "TSX", None),
+    (0xBC, "LDY", Some(AddrMode::AbsoluteIndexedX)),
+    (0xBD, "LDA", Some(AddrMode::AbsoluteIndexedX)),
+    (0xBE, "LDX", Some(AddrMode::AbsoluteIndexedY)),
+    (0xC0, "CPY", Some(AddrMode::Immediate)),
+    (0xC1, "CMP", Some(AddrMode::IndexedIndirectX)),
+    (0xC4, "CPY", Some(AddrMode::ZeroPage)),
+    (0xC5, "CMP", Some(AddrMode::ZeroPage)),
+    (0xC6, "DEC", Some(AddrMode::ZeroPage)),
+    (0xC8, "INY", None),
+    (0xC9, "CMP", Some(AddrMode::Immediate)),
+    (0xCA, "DEX", None),
+    (0xCC, "CPY", Some(AddrMode::Absolute)),
+    (0xCD, "CMP", Some(AddrMode::Absolute)),
+    (0xCE, "DEC", Some(AddrMode::Absolute)),
+    (0xD0, "BNE", Some(AddrMode::Relative)),
+    (0xD1, "CMP", Some(AddrMode::IndirectIndexedY)),
+    (0xD5, "CMP", Some(AddrMode::ZeroPageIndexedX)),
+    (0xD6, "DEC", Some(AddrMode::ZeroPageIndexedX)),
+    (0xD8, "CLD", None),
+    (0xD9, "CMP", Some(AddrMode::AbsoluteIndexedY)),
+    (0xDD, "CMP", Some(AddrMode::AbsoluteIndexedX)),
+    (0xDE, "DEC", Some(AddrMode::AbsoluteIndexedX)),
+    (0xE0, "CPX", Some(AddrMode::Immediate)),
+    (0xE1, "SBC", Some(AddrMode::IndexedIndirectX)),
+    (0xE4, "CPX", Some(AddrMode::ZeroPage)),
+    (0xE5, "SBC", Some(AddrMode::ZeroPage)),
+    (0xE6, "INC", Some(AddrMode::ZeroPage)),
+    (0xE8, "INX", None),
+    (0xE9, "SBC", Some(AddrMode::Immediate)),
+    (0xEA, "NOP", None),
+    (0xEC, "CPX", Some(AddrMode::Absolute)),
+    (0xED, "SBC", Some(AddrMode::Absolute)),
+    (0xEE, "INC", Some(AddrMode::Absolute)),
+    (0xF0, "BEQ", Some(AddrMode::Relative)),
+    (0xF1, "SBC", Some(AddrMode::IndirectIndexedY)),
+    (0xF5, "SBC", Some(AddrMode::ZeroPageIndexedX)),
+    (0xF6, "INC", Some(AddrMode::ZeroPageIndexedX)),
+    (0xF8, "SED", None),
+    (0xF9, "SBC", Some(AddrMode::AbsoluteIndexedY)),
+    (0xFD, "SBC", Some(AddrMode::AbsoluteIndexedX)),
+    (0xFE, "INC", Some(AddrMode::AbsoluteIndexedX)),
+];
+
+const BRANCHES: [&str; 8] = ["BCC", "BCS", "BEQ", "BMI", "BNE", "BPL", "BVC", "BVS"];
+
+fn operand_len(mode: Option<AddrMode>) -> u16 {
+    match mode {
+        None | Some(AddrMode::Accumulator) => 0,
+        Some(AddrMode::Absolute)
+        | Some(AddrMode::AbsoluteIndexedX)
+        | Some(AddrMode::AbsoluteIndexedY)
+        | Some(AddrMode::Indirect) => 2,
+        _ => 1,
+    }
+}
+
+fn format_operand(mode: Option<AddrMode>, addr: u16, operand: u16) -> String {
+    match mode {
+        None => String::new(),
+        Some(AddrMode::Accumulator) => " A".to_string(),
+        Some(AddrMode::Immediate) => format!(" #${:02X}", operand),
+        Some(AddrMode::ZeroPage) => format!(" ${:02X}", operand),
+        Some(AddrMode::ZeroPageIndexedX) => format!(" ${:02X},X", operand),
+        Some(AddrMode::ZeroPageIndexedY) => format!(" ${:02X},Y", operand),
+        Some(AddrMode::Absolute) => format!(" ${:04X}", operand),
+        Some(AddrMode::AbsoluteIndexedX) => format!(" ${:04X},X", operand),
+        Some(AddrMode::AbsoluteIndexedY) => format!(" ${:04X},Y", operand),
+        Some(AddrMode::IndexedIndirectX) => format!(" (${:02X},X)", operand),
+        Some(AddrMode::IndirectIndexedY) => format!(" (${:02X}),Y", operand),
+        Some(AddrMode::Indirect) => format!(" (${:04X})", operand),
+        Some(AddrMode::Relative) => {
+            let target = addr.wrapping_add(2).wrapping_add(operand as i8 as u16);
+            format!(" ${:04X}", target)
+        }
+    }
+}
+
+pub fn disassemble_range(bytes: &[u8], base: u16) -> Vec<(u16, String)> {
+    let mut lines = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let addr = base.wrapping_add(i as u16);
+        let opecode = bytes[i];
+
+        let entry = OPCODES.iter().find(|(code, _, _)| *code == opecode);
+
+        let (mnemonic, mode) = match entry {
+            Some((_, mnemonic, mode)) if i + 1 + operand_len(*mode) as usize <= bytes.len() => {
+                (*mnemonic, *mode)
+            }
+            _ => {
+                lines.push((addr, format!(".DB ${:02X}", opecode)));
+                i += 1;
+                continue;
+            }
+        };
+
+        let len = operand_len(mode) as usize;
+        let operand = bytes[(i + 1)..(i + 1 + len)]
+            .iter()
+            .rev()
+            .fold(0u16, |operand, &byte| (operand << 8) | byte as u16);
+
+        lines.push((
+            addr,
+            format!("{}{}", mnemonic, format_operand(mode, addr, operand)),
+        ));
+
+        i += 1 + len;
+    }
+
+    lines
+}
+
+fn parse_hex(s: &str) -> Result<(u16, usize)> {
+    let digits = s
+        .strip_prefix('$')
+        .with_context(|| format!("expected a $-prefixed hex number: {}", s))?;
+
+    let value =
+        u16::from_str_radix(digits, 16).with_context(|| format!("invalid hex number {}", s))?;
+
+    Ok((value, digits.len()))
+}
+
+fn parse_operand(mnemonic: &str, operand: &str, addr: u16) -> Result<(Option<AddrMode>, u16)> {
+    if operand.is_empty() {
+        return Ok((None, 0));
+    }
+
+    if operand == "A" {
+        return Ok((Some(AddrMode::Accumulator), 0));
+    }
+
+    if let Some(value) = operand.strip_prefix('#') {
+        return Ok((Some(AddrMode::Immediate), parse_hex(value)?.0));
+    }
+
+    if let Some(value) = operand.strip_prefix('(') {
+        if let Some(value) = value.strip_suffix(",X)") {
+            return Ok((Some(AddrMode::IndexedIndirectX), parse_hex(value)?.0));
+        }
+
+        if let Some(value) = value.strip_suffix("),Y") {
+            return Ok((Some(AddrMode::IndirectIndexedY), parse_hex(value)?.0));
+        }
+
+        if let Some(value) = value.strip_suffix(')') {
+            return Ok((Some(AddrMode::Indirect), parse_hex(value)?.0));
+        }
+
+        bail!("invalid operand {}", operand);
+    }
+
+    if BRANCHES.contains(&mnemonic) {
+        let (target, _) = parse_hex(operand)?;
+        let offset = target.wrapping_sub(addr.wrapping_add(2)) as i16;
+
+        if offset < i8::MIN as i16 || offset > i8::MAX as i16 {
+            bail!("branch target {} out of range", operand);
+        }
+
+        return Ok((Some(AddrMode::Relative), offset as u8 as u16));
+    }
+
+    let (value, index) = match operand.find(',') {
+        Some(pos) => (&operand[..pos], Some(&operand[(pos + 1)..])),
+        None => (operand, None),
+    };
+
+    let (value, digits) = parse_hex(value)?;
+    let zero_page = digits <= 2;
+
+    let mode = match (index, zero_page) {
+        (None, true) => AddrMode::ZeroPage,
+        (None, false) => AddrMode::Absolute,
+        (Some("X"), true) => AddrMode::ZeroPageIndexedX,
+        (Some("X"), false) => AddrMode::AbsoluteIndexedX,
+        (Some("Y"), true) => AddrMode::ZeroPageIndexedY,
+        (Some("Y"), false) => AddrMode::AbsoluteIndexedY,
+        _ => bail!("invalid operand {}", operand),
+    };
+
+    Ok((Some(mode), value))
+}
+
+fn find_opecode(mnemonic: &str, mode: Option<AddrMode>) -> Option<u8> {
+    OPCODES
+        .iter()
+        .find(|(_, m, md)| *m == mnemonic && *md == mode)
+        .map(|(code, _, _)| *code)
+}
+
+pub fn assemble(line: &str, addr: u16) -> Result<Vec<u8>> {
+    let line = line.trim().to_ascii_uppercase();
+
+    let (mnemonic, operand) = match line.find(char::is_whitespace) {
+        Some(pos) => (&line[..pos], line[pos..].replace(char::is_whitespace, "")),
+        None => (&line[..], String::new()),
+    };
+
+    let (mode, value) = parse_operand(mnemonic, &operand, addr)?;
+
+    // ゼロページ版がない命令は絶対アドレスで、暗黙の命令はアキュムレータでも探す
+    let fallback = match mode {
+        Some(AddrMode::ZeroPage) => Some(AddrMode::Absolute),
+        Some(AddrMode::ZeroPageIndexedX) => Some(AddrMode::AbsoluteIndexedX),
+        Some(AddrMode::ZeroPageIndexedY) => Some(AddrMode::AbsoluteIndexedY),
+        None => Some(AddrMode::Accumulator),
+        _ => mode,
+    };
+
+    let (opecode, mode) = match find_opecode(mnemonic, mode) {
+        Some(opecode) => (opecode, mode),
+        None => match find_opecode(mnemonic, fallback) {
+            Some(opecode) => (opecode, fallback),
+            None => bail!("unsupported instruction {}", line),
+        },
+    };
+
+    let mut bytes = vec![opecode];
+
+    match operand_len(mode) {
+        1 => bytes.push(value as u8),
+        2 => bytes.extend_from_slice(&value.to_le_bytes()),
+        _ => {}
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembled_lines_disassemble_back() {
+        let source = [
+            "LDA #$42",
+            "STA $10",
+            "STA $0200,X",
+            "LDX $20,Y",
+            "ORA ($30,X)",
+            "EOR ($40),Y",
+            "ASL A",
+            "JMP ($FFFC)",
+            "JSR $C000",
+            "BNE $C000",
+            "BIT $2002",
+            "RTS",
+        ];
+
+        let mut bytes = Vec::new();
+
+        for line in source.iter() {
+            let addr = 0xC000 + bytes.len() as u16;
+
+            bytes.extend(assemble(line, addr).unwrap());
+        }
+
+        let lines = disassemble_range(&bytes, 0xC000);
+
+        assert_eq!(
+            lines
+                .iter()
+                .map(|(_, line)| line.as_str())
+                .collect::<Vec<_>>(),
+            source
+        );
+        assert_eq!(lines[1].0, 0xC002);
+        assert_eq!(lines[9].1, "BNE $C000");
+    }
+
+    #[test]
+    fn assemble_picks_the_shortest_valid_encoding() {
+        // 小文字でも読めて、ゼロページ版がなければ絶対アドレスになる
+        assert_eq!(assemble("lda $10", 0x8000).unwrap(), [0xA5, 0x10]);
+        assert_eq!(assemble("LDA $0010", 0x8000).unwrap(), [0xAD, 0x10, 0x00]);
+        assert_eq!(assemble("JMP $12", 0x8000).unwrap(), [0x4C, 0x12, 0x00]);
+        assert_eq!(assemble("LSR", 0x8000).unwrap(), [0x4A]);
+
+        assert!(assemble("BEQ $9000", 0x8000).is_err());
+        assert!(assemble("FOO $10", 0x8000).is_err());
+
+        // 知らない命令と途中で切れた命令はバイトのまま
+        assert_eq!(
+            disassemble_range(&[0x02, 0xAD, 0x00], 0x8000),
+            [
+                (0x8000, ".DB $02".to_string()),
+                (0x8001, ".DB $AD".to_string()),
+                (0x8002, "BRK".to_string()),
+            ]
+        );
+    }
+}
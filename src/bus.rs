@@ -1,16 +1,52 @@
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     rc::Rc,
-    sync::mpsc::{Receiver, Sender},
 };
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use log::debug;
 
-use crate::{apu::Apu, joypad::Joypad, mmc::Mmc, ppu::Ppu};
+use crate::{apu::Apu, joypad::Joypad, mmc::Mmc, nes::Accuracy, ppu::Ppu, rom::Mirroring};
 
-pub enum CpuBusEvent {
-    RequestDma(u16, u8),
+const OPEN_BUS_DECAY_FRAMES: u64 = 36;
+
+// コントローラーが駆動するのは下位5ビットだけで、上位3ビットは直前のバスの値が残る
+const JOYPAD_OPEN_BUS_MASK: u8 = 0xE0;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OpenBus {
+    value: u8,
+    refreshed: [u64; 8],
+}
+
+impl OpenBus {
+    pub fn value(&self) -> u8 {
+        self.value
+    }
+
+    pub fn refresh(&mut self, data: u8, mask: u8, frame: u64) {
+        self.value = (self.value & !mask) | (data & mask);
+
+        for (bit, refreshed) in self.refreshed.iter_mut().enumerate() {
+            if mask & (1 << bit) != 0 {
+                *refreshed = frame;
+            }
+        }
+    }
+
+    pub fn decay(&mut self, frame: u64) {
+        for (bit, refreshed) in self.refreshed.iter().enumerate() {
+            if frame.saturating_sub(*refreshed) >= OPEN_BUS_DECAY_FRAMES {
+                self.value &= !(1 << bit);
+            }
+        }
+    }
+}
+
+struct OamDma {
+    page: u8,
+    step: u16,
+    data: u8,
 }
 
 pub struct CpuBus {
@@ -20,8 +56,12 @@ pub struct CpuBus {
     joypad1: Rc<RefCell<Joypad>>,
     joypad2: Rc<RefCell<Joypad>>,
 
-    event: Receiver<CpuBusEvent>,
-    ppu_bus_sender: Sender<PpuBusEvent>,
+    open_bus: Cell<u8>,
+    status_read: Cell<bool>,
+
+    dma_page: Option<u8>,
+    dma: Option<OamDma>,
+    accuracy: Accuracy,
 
     pub cycles: u8,
     pub stalls: u16,
@@ -35,8 +75,7 @@ impl CpuBus {
         apu: Rc<RefCell<Apu>>,
         joypad1: Rc<RefCell<Joypad>>,
         joypad2: Rc<RefCell<Joypad>>,
-        event: Receiver<CpuBusEvent>,
-        ppu_bus_sender: Sender<PpuBusEvent>,
+        accuracy: Accuracy,
     ) -> Self {
         Self {
             mmc,
@@ -44,8 +83,11 @@ impl CpuBus {
             apu,
             joypad1,
             joypad2,
-            ppu_bus_sender,
-            event,
+            open_bus: Cell::new(0),
+            status_read: Cell::new(false),
+            dma_page: None,
+            dma: None,
+            accuracy,
             cycles: 0,
             stalls: 0,
             wram: [0xFF; 0x0800],
@@ -53,28 +95,80 @@ impl CpuBus {
     }
 
     pub fn tick(&mut self) -> Result<()> {
-        match self.event.try_recv() {
-            Ok(event) => match event {
-                CpuBusEvent::RequestDma(addr, oam_addr) => {
-                    debug!("RECEIVED REQUEST DMA: {:#04X}", oam_addr);
+        self.apu.borrow_mut().tick()?;
+        self.mmc.borrow_mut().tick()
+    }
+
+    // 命令は最初のサイクルでまとめて実行されるので、ここでは予約だけして
+    // 命令の残りのサイクルが終わってからstart_dmaで始める
+    fn oam_dma(&mut self, page: u8) -> Result<()> {
+        debug!("OAM DMA: {:#04X}", (page as u16) << 8);
 
-                    let mut result = Vec::with_capacity(0x0100);
+        self.dma_page = Some(page);
 
-                    for i in addr..(addr + 0x0100) {
-                        result.push(self.read(i)?);
-                    }
+        Ok(())
+    }
 
-                    self.ppu_bus_sender
-                        .send(PpuBusEvent::Dma(result, oam_addr))
-                        .context("failed to send ppu event")?;
+    pub fn dma_requested(&self) -> bool {
+        self.dma_page.is_some()
+    }
 
-                    self.stalls += 513 + if self.cycles % 2 == 0 { 0 } else { 1 };
+    // 呼ばれたサイクルが停止サイクルで、$4014への書き込みはその1つ前のサイクル
+    // 書き込みが奇数サイクルなら揃えるためにもう1サイクル待つ
+    pub fn start_dma(&mut self) -> Result<()> {
+        let page = match self.dma_page.take() {
+            Some(page) => page,
+            None => return Ok(()),
+        };
 
-                    Ok(())
-                }
-            },
-            _ => Ok(()),
+        let write_cycle = self.cycles.wrapping_sub(1);
+
+        self.stalls += if write_cycle % 2 == 0 { 0 } else { 1 };
+
+        if self.accuracy == Accuracy::Fast {
+            for i in 0..=0xFF {
+                let data = self.read(((page as u16) << 8) | i)?;
+                self.ppu.borrow_mut().write_oam_dma(data)?;
+            }
+
+            self.stalls += 512;
+
+            return Ok(());
+        }
+
+        self.dma = Some(OamDma {
+            page,
+            step: 0,
+            data: 0,
+        });
+
+        Ok(())
+    }
+
+    pub fn dma_active(&self) -> bool {
+        self.dma_page.is_some() || self.dma.is_some()
+    }
+
+    // 1サイクルごとにget(読み込み)とput(OAMへの書き込み)を交互に行う
+    pub fn step_dma(&mut self) -> Result<()> {
+        let mut dma = match self.dma.take() {
+            Some(dma) => dma,
+            None => return Ok(()),
+        };
+
+        if dma.step & 1 == 0 {
+            dma.data = self.read(((dma.page as u16) << 8) | (dma.step >> 1))?;
+        } else {
+            self.ppu.borrow_mut().write_oam_dma(dma.data)?;
+        }
+
+        dma.step += 1;
+
+        if dma.step < 0x0200 {
+            self.dma = Some(dma);
         }
+
+        Ok(())
     }
 
     pub fn nmi(&self) -> bool {
@@ -89,6 +183,14 @@ impl CpuBus {
         false
     }
 
+    pub fn take_status_read(&self) -> bool {
+        self.status_read.replace(false)
+    }
+
+    pub fn irq(&self) -> bool {
+        self.apu.borrow().irq() || self.mmc.borrow().irq()
+    }
+
     pub fn read_word(&self, addr: u16) -> Result<u16> {
         let low = self.read(addr)?;
         let high = self.read(addr.wrapping_add(1))?;
@@ -97,6 +199,14 @@ impl CpuBus {
     }
 
     pub fn read(&self, addr: u16) -> Result<u8> {
+        let data = self.read_mapped(addr)?;
+
+        self.open_bus.set(data);
+
+        Ok(data)
+    }
+
+    fn read_mapped(&self, addr: u16) -> Result<u8> {
         let addr = match addr {
             0x0800..=0x1FFF => (addr - 0x0800) % 0x0800,
             0x2008..=0x3FFF => 0x2000 + (addr - 0x2008) % 0x0008,
@@ -105,37 +215,109 @@ impl CpuBus {
 
         match addr {
             0x0000..=0x07FF => Ok(self.wram[addr as usize]),
-            0x2000 => self.ppu.borrow().read_ctrl(),
-            0x2001 => self.ppu.borrow().read_mask(),
-            0x2002 => self.ppu.borrow_mut().read_status(),
-            0x2004 => self.ppu.borrow().read_oam_data(),
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 => self.ppu.borrow().read_open_bus(),
+            0x2002 => {
+                self.status_read.set(true);
+                self.ppu.borrow_mut().read_status()
+            }
+            0x2004 => self.ppu.borrow_mut().read_oam_data(),
             0x2007 => self.ppu.borrow_mut().read_vram_data(),
-            0x4000 => self.apu.borrow().read_square_ch1_control1(),
-            0x4001 => self.apu.borrow().read_square_ch1_control2(),
-            0x4002 => self.apu.borrow().read_square_ch1_freq1(),
-            0x4003 => self.apu.borrow().read_square_ch1_freq2(),
-            0x4004 => self.apu.borrow().read_square_ch2_control1(),
-            0x4005 => self.apu.borrow().read_square_ch2_control2(),
-            0x4006 => self.apu.borrow().read_square_ch2_freq1(),
-            0x4007 => self.apu.borrow().read_square_ch2_freq2(),
-            0x4008 => self.apu.borrow().read_sign_control(),
-            0x400A => self.apu.borrow().read_sign_freq1(),
-            0x400B => self.apu.borrow().read_sign_freq2(),
-            0x400C => self.apu.borrow().read_noise_control(),
-            0x400E => self.apu.borrow().read_noise_rand(),
-            0x400F => self.apu.borrow().read_noise_duration(),
-            0x4010 => self.apu.borrow().read_dpcm_control1(),
-            0x4011 => self.apu.borrow().read_dpcm_control2(),
-            0x4012 => self.apu.borrow().read_dpcm_control3(),
-            0x4013 => self.apu.borrow().read_dpcm_control4(),
-            0x4014 => self.ppu.borrow().read_oam_dma(),
-            0x4015 => self.apu.borrow().read_voice_control(),
-            0x4016 => self.joypad1.borrow_mut().read(),
-            0x4017 => self.joypad2.borrow_mut().read(),
+            0x4000..=0x4014 => Ok(self.open_bus.get()),
+            0x4015 => self.apu.borrow_mut().read_voice_control(),
+            0x4016 => Ok(
+                self.joypad1.borrow_mut().read()? | (self.open_bus.get() & JOYPAD_OPEN_BUS_MASK)
+            ),
+            0x4017 => Ok(
+                self.joypad2.borrow_mut().read()? | (self.open_bus.get() & JOYPAD_OPEN_BUS_MASK)
+            ),
+            0x6000..=0x7FFF if !self.prg_ram_mapped(addr) => Ok(self.open_bus.get()),
             addr => self.mmc.borrow().read_cpu(addr),
         }
     }
 
+    pub fn ppu_position(&self) -> (u16, u16) {
+        self.ppu.borrow().position()
+    }
+
+    // デバッガ向けの読み込みで、レジスタの状態もオープンバスも変えない
+    pub fn peek(&self, addr: u16) -> Result<u8> {
+        let addr = match addr {
+            0x0800..=0x1FFF => (addr - 0x0800) % 0x0800,
+            0x2008..=0x3FFF => 0x2000 + (addr - 0x2008) % 0x0008,
+            _ => addr,
+        };
+
+        match addr {
+            0x0000..=0x07FF => Ok(self.wram[addr as usize]),
+            0x2000..=0x2007 => self.ppu.borrow().peek_register(addr),
+            0x4015 => Ok(self.apu.borrow().peek_voice_control()),
+            0x4016 => {
+                Ok(self.joypad1.borrow().peek() | (self.open_bus.get() & JOYPAD_OPEN_BUS_MASK))
+            }
+            0x4017 => {
+                Ok(self.joypad2.borrow().peek() | (self.open_bus.get() & JOYPAD_OPEN_BUS_MASK))
+            }
+            0x4000..=0x401F => Ok(self.open_bus.get()),
+            0x6000..=0x7FFF if !self.prg_ram_mapped(addr) => Ok(self.open_bus.get()),
+            addr => self.mmc.borrow().read_cpu(addr),
+        }
+    }
+
+    // PRG-RAMがない、またはヘッダーのサイズより先はどこにも繋がっていない
+    fn prg_ram_mapped(&self, addr: u16) -> bool {
+        self.mmc
+            .borrow()
+            .prg_ram()
+            .is_some_and(|prg_ram| ((addr - 0x6000) as usize) < prg_ram.len())
+    }
+
+    // ヘックスビューアなどで使うアドレスの説明
+    pub fn describe(addr: u16) -> &'static str {
+        match addr {
+            0x0000..=0x07FF => "WRAM",
+            0x0800..=0x1FFF => "WRAM mirror",
+            0x2000..=0x3FFF => match 0x2000 + (addr & 0x0007) {
+                0x2000 => "PPU PPUCTRL",
+                0x2001 => "PPU PPUMASK",
+                0x2002 => "PPU PPUSTATUS",
+                0x2003 => "PPU OAMADDR",
+                0x2004 => "PPU OAMDATA",
+                0x2005 => "PPU PPUSCROLL",
+                0x2006 => "PPU PPUADDR",
+                _ => "PPU PPUDATA",
+            },
+            0x4000 => "APU pulse1 ctrl",
+            0x4001 => "APU pulse1 sweep",
+            0x4002 => "APU pulse1 timer low",
+            0x4003 => "APU pulse1 timer high",
+            0x4004 => "APU pulse2 ctrl",
+            0x4005 => "APU pulse2 sweep",
+            0x4006 => "APU pulse2 timer low",
+            0x4007 => "APU pulse2 timer high",
+            0x4008 => "APU triangle ctrl",
+            0x400A => "APU triangle timer low",
+            0x400B => "APU triangle timer high",
+            0x400C => "APU noise ctrl",
+            0x400E => "APU noise period",
+            0x400F => "APU noise length",
+            0x4010 => "APU DMC ctrl",
+            0x4011 => "APU DMC load",
+            0x4012 => "APU DMC address",
+            0x4013 => "APU DMC length",
+            0x4014 => "OAMDMA",
+            0x4015 => "APU status",
+            0x4016 => "JOY1",
+            0x4017 => "JOY2 / APU frame counter",
+            0x4009 | 0x400D | 0x4018..=0x401F => "unused",
+            0x4020..=0x5FFF => "expansion",
+            0x6000..=0x7FFF => "PRG-RAM",
+            0x8000..=0xFFF9 => "PRG-ROM bank",
+            0xFFFA..=0xFFFB => "NMI vector",
+            0xFFFC..=0xFFFD => "RESET vector",
+            _ => "IRQ vector",
+        }
+    }
+
     pub fn write_word(&mut self, addr: u16, data: u16) -> Result<()> {
         let low = (data & 0x00FF) as u8;
         let high = (data >> 8) as u8;
@@ -147,6 +329,8 @@ impl CpuBus {
     }
 
     pub fn write(&mut self, addr: u16, data: u8) -> Result<()> {
+        self.open_bus.set(data);
+
         let addr = match addr {
             0x0800..=0x1FFF => (addr - 0x0800) % 0x0800,
             0x2008..=0x3FFF => 0x2000 + (addr - 0x2008) % 0x0008,
@@ -158,14 +342,7 @@ impl CpuBus {
                 self.wram[addr as usize] = data;
                 Ok(())
             }
-            0x2000 => self.ppu.borrow_mut().write_ctrl(data),
-            0x2001 => self.ppu.borrow_mut().write_mask(data),
-            0x2002 => self.ppu.borrow_mut().write_status(data),
-            0x2003 => self.ppu.borrow_mut().write_oam_addr(data),
-            0x2004 => self.ppu.borrow_mut().write_oam_data(data),
-            0x2005 => self.ppu.borrow_mut().write_scroll(data),
-            0x2006 => self.ppu.borrow_mut().write_vram_addr(data),
-            0x2007 => self.ppu.borrow_mut().write_vram_data(data),
+            0x2000..=0x2007 => self.ppu.borrow_mut().write_register(addr, data),
             0x4000 => self.apu.borrow_mut().write_square_ch1_control1(data),
             0x4001 => self.apu.borrow_mut().write_square_ch1_control2(data),
             0x4002 => self.apu.borrow_mut().write_square_ch1_freq1(data),
@@ -184,69 +361,106 @@ impl CpuBus {
             0x4011 => self.apu.borrow_mut().write_dpcm_control2(data),
             0x4012 => self.apu.borrow_mut().write_dpcm_control3(data),
             0x4013 => self.apu.borrow_mut().write_dpcm_control4(data),
-            0x4014 => self.ppu.borrow_mut().write_oam_dma(data),
+            0x4014 => self.oam_dma(data),
             0x4015 => self.apu.borrow_mut().write_voice_control(data),
-            0x4016 => self.joypad1.borrow_mut().write(data),
-            0x4017 => self.joypad2.borrow_mut().write(data),
+            0x4016 => {
+                self.joypad1.borrow_mut().write(data)?;
+                self.joypad2.borrow_mut().write(data)
+            }
+            0x4017 => self.apu.borrow_mut().write_frame_counter(data),
             0x4020..=0xFFFF => self.mmc.borrow_mut().write_cpu(addr, data),
             _ => Ok(()),
         }
     }
 }
 
-pub enum PpuBusEvent {
-    Dma(Vec<u8>, u8),
+const POWER_ON_PALETTE: [u8; 0x0020] = [
+    0x09, 0x01, 0x00, 0x01, 0x00, 0x02, 0x02, 0x0D, 0x08, 0x10, 0x08, 0x24, 0x00, 0x00, 0x04, 0x2C,
+    0x09, 0x01, 0x34, 0x03, 0x00, 0x04, 0x00, 0x14, 0x08, 0x3A, 0x00, 0x02, 0x00, 0x20, 0x2C, 0x08,
+];
+
+const POWER_ON_OAM_SEED: u32 = 0x2A6D_365A;
+
+fn power_on_oam() -> [u8; 0x0100] {
+    let mut oam = [0; 0x0100];
+    let mut state = POWER_ON_OAM_SEED;
+
+    for byte in oam.iter_mut() {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+
+        *byte = (state >> 24) as u8;
+    }
+
+    oam
 }
 
 pub struct PpuBus {
     mmc: Rc<RefCell<Box<dyn Mmc>>>,
-    event: Receiver<PpuBusEvent>,
-    cpu_bus_sender: Sender<CpuBusEvent>,
-    pub vram: [u8; 0x0800],
+    mirroring: Option<Mirroring>,
+    // 4画面ミラーリング用に、カートリッジ側の2KBも含めて持っておく
+    pub vram: [u8; 0x1000],
     pub palette: [u8; 0x0020],
     pub oam: [u8; 0x0100],
 }
 
 impl PpuBus {
-    pub fn new(
-        mmc: Rc<RefCell<Box<dyn Mmc>>>,
-        event: Receiver<PpuBusEvent>,
-        cpu_bus_sender: Sender<CpuBusEvent>,
-    ) -> Self {
+    pub fn new(mmc: Rc<RefCell<Box<dyn Mmc>>>) -> Self {
         Self {
             mmc,
-            event,
-            cpu_bus_sender,
-            vram: [0xFF; 0x0800],
-            palette: [0; 0x0020],
-            oam: [0; 0x0100],
+            mirroring: None,
+            vram: [0xFF; 0x1000],
+            palette: POWER_ON_PALETTE,
+            oam: power_on_oam(),
         }
     }
 
-    pub fn tick(&mut self) -> Result<()> {
-        match self.event.try_recv() {
-            Ok(event) => match event {
-                PpuBusEvent::Dma(data, oam_addr) => {
-                    debug!("RECEIVED DMA: {:#04X}", oam_addr);
-
-                    for i in 0..data.len() {
-                        let addr = i + oam_addr as usize;
-                        self.oam[addr] = data[i];
-                    }
-                }
-            },
-            _ => {}
-        }
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+            .unwrap_or_else(|| self.mmc.borrow().mirroring())
+    }
 
-        Ok(())
+    pub fn mirroring_override(&self) -> Option<Mirroring> {
+        self.mirroring
+    }
+
+    pub fn set_mirroring(&mut self, mirroring: Option<Mirroring>) {
+        self.mirroring = mirroring;
     }
 
-    pub fn request_dma(&mut self, cpu_addr: u16, oam_addr: u8) -> Result<()> {
-        debug!("SEND REQUEST DMA: {:#04X}", oam_addr);
+    fn name_table_index(&self, addr: u16) -> usize {
+        let addr = (addr - 0x2000) as usize & 0x0FFF;
+        let table = addr / 0x0400;
 
-        self.cpu_bus_sender
-            .send(CpuBusEvent::RequestDma(cpu_addr, oam_addr))
-            .context("failed to send cpu event")
+        let page = match self.mirroring() {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 1,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+
+        page * 0x0400 + (addr & 0x03FF)
+    }
+
+    pub fn describe(addr: u16) -> &'static str {
+        match addr & 0x3FFF {
+            0x0000..=0x0FFF => "pattern table 0",
+            0x1000..=0x1FFF => "pattern table 1",
+            0x2000..=0x23BF => "name table 0",
+            0x23C0..=0x23FF => "attribute table 0",
+            0x2400..=0x27BF => "name table 1",
+            0x27C0..=0x27FF => "attribute table 1",
+            0x2800..=0x2BBF => "name table 2",
+            0x2BC0..=0x2BFF => "attribute table 2",
+            0x2C00..=0x2FBF => "name table 3",
+            0x2FC0..=0x2FFF => "attribute table 3",
+            0x3000..=0x3EFF => "name table mirror",
+            0x3F00..=0x3F0F => "background palette",
+            0x3F10..=0x3F1F => "sprite palette",
+            _ => "palette mirror",
+        }
     }
 
     pub fn read_word(&self, addr: u16) -> Result<u16> {
@@ -258,7 +472,6 @@ impl PpuBus {
 
     pub fn read(&self, addr: u16) -> Result<u8> {
         let addr = match addr {
-            0x2800..=0x3EFF => 0x2000 + (addr - 0x2800) % 0x0800,
             0x3F10..=0x3F1F if addr % 4 == 0 => addr - 0x0010,
             0x3F20..=0x3FFF => 0x3F00 + addr - 0x3F20,
             0x4000..=0xFFFF => addr - 0x4000,
@@ -267,7 +480,7 @@ impl PpuBus {
 
         match addr {
             0x0000..=0x1FFF => self.mmc.borrow().read_ppu(addr),
-            0x2000..=0x27FF => Ok(self.vram[(addr - 0x2000) as usize]),
+            0x2000..=0x3EFF => Ok(self.vram[self.name_table_index(addr)]),
             0x3F00..=0x3F1F => Ok(self.palette[(addr - 0x3F00) as usize]),
             _ => Ok(0),
         }
@@ -285,7 +498,6 @@ impl PpuBus {
 
     pub fn write(&mut self, addr: u16, data: u8) -> Result<()> {
         let addr = match addr {
-            0x2800..=0x3EFF => 0x2000 + (addr - 0x2800) % 0x0800,
             0x3F10..=0x3F1F if addr % 4 == 0 => addr - 0x0010,
             0x3F20..=0x3FFF => 0x3F00 + addr - 0x3F20,
             0x4000..=0xFFFF => addr - 0x4000,
@@ -294,8 +506,9 @@ impl PpuBus {
 
         match addr {
             0x0000..=0x1FFF => self.mmc.borrow_mut().write_ppu(addr, data),
-            0x2000..=0x27FF => {
-                self.vram[(addr - 0x2000) as usize] = data;
+            0x2000..=0x3EFF => {
+                let index = self.name_table_index(addr);
+                self.vram[index] = data;
                 Ok(())
             }
             0x3F00..=0x3F1F => {
@@ -306,3 +519,331 @@ impl PpuBus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mmc::new_mmc, rom::Rom};
+
+    fn mmc(mirroring: Mirroring) -> Rc<RefCell<Box<dyn Mmc>>> {
+        let rom = Rom::from_parts(vec![0; 0x4000], vec![0; 0x2000], 0, mirroring).unwrap();
+
+        Rc::new(RefCell::new(new_mmc(rom).unwrap()))
+    }
+
+    fn ppu_bus(mirroring: Mirroring) -> PpuBus {
+        PpuBus::new(mmc(mirroring))
+    }
+
+    fn cpu_bus() -> CpuBus {
+        let mmc = mmc(Mirroring::Horizontal);
+        let ppu = Ppu::new(PpuBus::new(Rc::clone(&mmc)), Accuracy::Cycle);
+
+        CpuBus::new(
+            mmc,
+            Rc::new(RefCell::new(ppu)),
+            Rc::new(RefCell::new(Apu::new())),
+            Rc::new(RefCell::new(Joypad::new())),
+            Rc::new(RefCell::new(Joypad::new())),
+            Accuracy::Cycle,
+        )
+    }
+
+    #[test]
+    fn mirroring_override_changes_the_name_table_mapping() {
+        let mut bus = ppu_bus(Mirroring::Horizontal);
+
+        // 水平ミラーリングでは$2000と$2400、$2800と$2C00が同じ
+        assert_eq!(bus.name_table_index(0x2400), bus.name_table_index(0x2000));
+        assert_eq!(bus.name_table_index(0x2C00), bus.name_table_index(0x2800));
+        assert_ne!(bus.name_table_index(0x2800), bus.name_table_index(0x2000));
+
+        bus.set_mirroring(Some(Mirroring::Vertical));
+
+        // 垂直ミラーリングでは$2000と$2800、$2400と$2C00が同じ
+        assert_eq!(bus.mirroring(), Mirroring::Vertical);
+        assert_eq!(bus.name_table_index(0x2800), bus.name_table_index(0x2000));
+        assert_eq!(bus.name_table_index(0x2C00), bus.name_table_index(0x2400));
+        assert_ne!(bus.name_table_index(0x2400), bus.name_table_index(0x2000));
+
+        bus.set_mirroring(Some(Mirroring::FourScreen));
+
+        // 4画面ではカートリッジのVRAMも使って、4枚がそれぞれ別になる
+        for &(addr, index) in [
+            (0x2000, 0x0000),
+            (0x2400, 0x0400),
+            (0x2800, 0x0800),
+            (0x2C05, 0x0C05),
+            (0x3C05, 0x0C05),
+        ]
+        .iter()
+        {
+            assert_eq!(bus.name_table_index(addr), index, "{:#06X}", addr);
+        }
+
+        bus.write(0x2C10, 0x5A).unwrap();
+
+        assert_eq!(bus.read(0x2C10).unwrap(), 0x5A);
+        assert_ne!(bus.read(0x2410).unwrap(), 0x5A);
+        assert_ne!(bus.read(0x2810).unwrap(), 0x5A);
+
+        bus.set_mirroring(None);
+
+        assert_eq!(bus.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn describe_labels_cpu_and_ppu_addresses() {
+        let cpu = [
+            (0x0000, "WRAM"),
+            (0x1800, "WRAM mirror"),
+            (0x2000, "PPU PPUCTRL"),
+            (0x3FFA, "PPU PPUSTATUS"),
+            (0x4000, "APU pulse1 ctrl"),
+            (0x4014, "OAMDMA"),
+            (0x4017, "JOY2 / APU frame counter"),
+            (0x4018, "unused"),
+            (0x6000, "PRG-RAM"),
+            (0x8000, "PRG-ROM bank"),
+            (0xFFFA, "NMI vector"),
+        ];
+
+        for &(addr, label) in cpu.iter() {
+            assert_eq!(CpuBus::describe(addr), label, "{:#06X}", addr);
+        }
+
+        let ppu = [
+            (0x0000, "pattern table 0"),
+            (0x1000, "pattern table 1"),
+            (0x2000, "name table 0"),
+            (0x23C0, "attribute table 0"),
+            (0x2C00, "name table 3"),
+            (0x3000, "name table mirror"),
+            (0x3F00, "background palette"),
+            (0x3F10, "sprite palette"),
+            (0x3F20, "palette mirror"),
+            (0x6000, "name table 0"),
+        ];
+
+        for &(addr, label) in ppu.iter() {
+            assert_eq!(PpuBus::describe(addr), label, "{:#06X}", addr);
+        }
+    }
+
+    #[test]
+    fn joypad_upper_bits_come_from_the_open_bus() {
+        let mut bus = cpu_bus();
+
+        for &value in [0xA5, 0x40, 0x1F, 0xFF].iter() {
+            for &addr in [0x4016, 0x4017].iter() {
+                // 直前にバスに乗った値を作る
+                bus.write(0x0000, value).unwrap();
+
+                let data = bus.read(addr).unwrap();
+
+                assert_eq!(
+                    data & JOYPAD_OPEN_BUS_MASK,
+                    value & JOYPAD_OPEN_BUS_MASK,
+                    "{:#06X} after {:#04X}",
+                    addr,
+                    value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn strobe_reaches_both_pads_and_4017_writes_go_to_the_apu() {
+        let mut bus = cpu_bus();
+
+        // 1PはAだけ、2PはSelectだけ押されている
+        bus.joypad1.borrow_mut().set_state(0x01);
+        bus.joypad2.borrow_mut().set_state(0x04);
+
+        for _ in 0..3 {
+            bus.read(0x4016).unwrap();
+            bus.read(0x4017).unwrap();
+        }
+
+        // $4016へのストローブで両方のパッドがAに戻る
+        bus.write(0x4016, 0x01).unwrap();
+        bus.write(0x4016, 0x00).unwrap();
+
+        let pad1 = (0..3)
+            .map(|_| bus.read(0x4016).unwrap() & 0x01)
+            .collect::<Vec<_>>();
+        let pad2 = (0..2)
+            .map(|_| bus.read(0x4017).unwrap() & 0x01)
+            .collect::<Vec<_>>();
+
+        assert_eq!(pad1, [1, 0, 0]);
+        assert_eq!(pad2, [0, 0]);
+
+        // $4017への書き込みはAPUのフレームカウンタに行き、2Pのシフトレジスタは戻らない
+        bus.write(0x4017, 0x41).unwrap();
+
+        assert_eq!(bus.read(0x4017).unwrap() & 0x01, 1);
+
+        for _ in 0..30000 {
+            bus.apu.borrow_mut().tick().unwrap();
+        }
+
+        assert!(!bus.apu.borrow().irq());
+
+        bus.write(0x4017, 0x00).unwrap();
+
+        for _ in 0..30000 {
+            bus.apu.borrow_mut().tick().unwrap();
+        }
+
+        assert!(bus.apu.borrow().irq());
+    }
+
+    #[test]
+    fn power_on_palette_and_oam_are_seeded() {
+        let bus = ppu_bus(Mirroring::Horizontal);
+
+        let palette = (0x3F00..0x3F20)
+            .map(|addr| bus.read(addr).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            palette,
+            [
+                0x09, 0x01, 0x00, 0x01, 0x00, 0x02, 0x02, 0x0D, 0x08, 0x10, 0x08, 0x24, 0x00, 0x00,
+                0x04, 0x2C, 0x09, 0x01, 0x34, 0x03, 0x00, 0x04, 0x00, 0x14, 0x08, 0x3A, 0x00, 0x02,
+                0x00, 0x20, 0x2C, 0x08,
+            ]
+        );
+
+        // OAMのゴミは起動ごとに同じ値になる
+        assert_eq!(bus.oam, ppu_bus(Mirroring::Horizontal).oam);
+        assert!(bus.oam.iter().any(|&byte| byte != bus.oam[0]));
+    }
+
+    #[test]
+    fn open_bus_bits_decay_after_the_decay_frames() {
+        let mut bus = OpenBus::default();
+
+        bus.refresh(0xFF, 0xFF, 0);
+        bus.refresh(0x01, 0x01, 20);
+
+        bus.decay(OPEN_BUS_DECAY_FRAMES - 1);
+        assert_eq!(bus.value(), 0xFF);
+
+        // ビットごとに最後に駆動されたフレームから数える
+        bus.decay(OPEN_BUS_DECAY_FRAMES);
+        assert_eq!(bus.value(), 0x01);
+
+        bus.decay(20 + OPEN_BUS_DECAY_FRAMES);
+        assert_eq!(bus.value(), 0x00);
+    }
+
+    #[test]
+    fn write_only_ppu_registers_read_the_open_bus() {
+        let mut bus = cpu_bus();
+
+        bus.write(0x2000, 0x80).unwrap();
+        bus.write(0x2003, 0x11).unwrap();
+
+        // 書き込んだ$80ではなく、最後にバスに乗った$11が見える
+        for &addr in [0x2000, 0x2001, 0x2003, 0x2005, 0x2006].iter() {
+            assert_eq!(bus.read(addr).unwrap(), 0x11, "{:#06X}", addr);
+        }
+    }
+
+    #[test]
+    fn dma_alternates_get_and_put_cycles() {
+        for &(cycles, alignment) in [(1, 0), (2, 1)].iter() {
+            let mut bus = cpu_bus();
+
+            for i in 0..0x0100 {
+                bus.wram[0x0200 + i] = 0x80 | i as u8;
+            }
+
+            bus.ppu.borrow_mut().oam_mut().fill(0);
+
+            bus.write(0x4014, 0x02).unwrap();
+
+            // 書き込みの次のサイクルで始まるので、書き込みが奇数サイクルなら1サイクル待つ
+            bus.cycles = cycles;
+            bus.start_dma().unwrap();
+
+            assert_eq!(bus.stalls, alignment, "cycles {}", cycles);
+
+            for i in 0..0x0100 {
+                // get: 読むだけでOAMはまだ変わらない
+                bus.step_dma().unwrap();
+                assert_eq!(bus.ppu.borrow().oam()[i], 0, "get {:#04X}", i);
+
+                // put: 読んだ値をOAMに書く
+                bus.step_dma().unwrap();
+                assert_eq!(bus.ppu.borrow().oam()[i], 0x80 | i as u8, "put {:#04X}", i);
+            }
+
+            assert!(!bus.dma_active());
+        }
+    }
+
+    #[test]
+    fn peek_leaves_the_latch_vblank_and_shift_registers_alone() {
+        for &peek in [true, false].iter() {
+            let mut bus = cpu_bus();
+
+            // ウォームアップ中は$2006への書き込みが無視されるので、2フレーム目まで進める
+            while bus.ppu.borrow().frame_count() == 0 {
+                bus.ppu.borrow_mut().tick().unwrap();
+            }
+
+            bus.write(0x2006, 0x3F).unwrap();
+            bus.write(0x2006, 0x05).unwrap();
+            bus.write(0x2007, 0x15).unwrap();
+
+            while bus.peek(0x2002).unwrap() & 0x80 == 0 {
+                bus.ppu.borrow_mut().tick().unwrap();
+            }
+
+            // $2006の1回目と2回目の間で$2002を読む
+            bus.write(0x2006, 0x3F).unwrap();
+
+            let status = if peek {
+                bus.peek(0x2002).unwrap()
+            } else {
+                bus.read(0x2002).unwrap()
+            };
+
+            assert_eq!(status & 0x80, 0x80, "peek {}", peek);
+
+            bus.write(0x2006, 0x05).unwrap();
+
+            // peekならwが残っていて$3F05を指す。読み込みならwが戻り、上位バイトとして扱われる
+            assert_eq!(bus.peek(0x2007).unwrap() == 0x15, peek, "peek {}", peek);
+            assert_eq!(bus.peek(0x2002).unwrap() & 0x80 != 0, peek, "peek {}", peek);
+        }
+
+        // $4015と$4016も、peekではフレームIRQもシフトレジスタも変わらない
+        let mut bus = cpu_bus();
+
+        bus.apu.borrow_mut().write_frame_counter(0x00).unwrap();
+
+        while !bus.apu.borrow().irq() {
+            bus.apu.borrow_mut().tick().unwrap();
+        }
+
+        // Bだけ押されている
+        bus.joypad1.borrow_mut().set_state(0x02);
+        bus.write(0x4016, 0x01).unwrap();
+        bus.write(0x4016, 0x00).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(bus.peek(0x4015).unwrap() & 0x40, 0x40);
+            assert_eq!(bus.peek(0x4016).unwrap() & 0x01, 0x00);
+        }
+
+        assert_eq!(bus.read(0x4015).unwrap() & 0x40, 0x40);
+        assert_eq!(bus.peek(0x4015).unwrap() & 0x40, 0x00);
+
+        assert_eq!(bus.read(0x4016).unwrap() & 0x01, 0x00);
+        assert_eq!(bus.peek(0x4016).unwrap() & 0x01, 0x01);
+    }
+}
@@ -1,16 +1,20 @@
-use std::fmt::{self, Debug, Display, Formatter, UpperHex};
+use std::{
+    collections::VecDeque,
+    fmt::{self, Debug, Display, Formatter, UpperHex},
+    io::Write,
+};
 
 use anyhow::Result;
 use bitfield::bitfield;
 use bitmatch::bitmatch;
 use log::{debug, error, trace};
 
-use crate::bus::CpuBus;
+use crate::{asm, bus::CpuBus};
 
 const STACK_BASE: u16 = 0x0100;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
-enum AddrMode {
+pub(crate) enum AddrMode {
     ZeroPageIndexedX,
     ZeroPageIndexedY,
     AbsoluteIndexedX,
@@ -120,8 +124,68 @@ pub struct Cpu {
 
     irq: bool,
     halt: bool,
+    page_crossed: bool,
+    extra_cycles: u16,
+    instructions: u64,
+    total_cycles: u64,
 
     bus: CpuBus,
+
+    trace: Option<Box<dyn Write>>,
+    history: VecDeque<String>,
+    history_len: usize,
+
+    watchdog: Option<u64>,
+    idle_instructions: u64,
+    stalled: bool,
+}
+
+// 命令ごとの基本サイクル数 (ページ跨ぎや分岐で増える分は含まない)
+#[rustfmt::skip]
+const CYCLES: [u8; 0x100] = [
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+];
+
+const INTERRUPT_CYCLES: u16 = 7;
+
+// XAAでAとORされる値。個体差があるが、よく使われる$EEにしておく
+const XAA_MAGIC: u8 = 0xEE;
+
+// インデックスでページを跨ぐと1サイクル増える読み込み命令
+// (書き込みやRMWは常にその分のサイクルがかかるので、表に含めてある)
+fn has_page_penalty(opecode: u8) -> bool {
+    match opecode {
+        0x91 | 0x99 | 0x9D => false,
+        _ if opecode & 0x1F == 0x11 || opecode & 0x1F == 0x19 || opecode & 0x1F == 0x1D => true,
+        0xB3 | 0xBB | 0xBC | 0xBE | 0xBF => true,
+        0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => true,
+        _ => false,
+    }
+}
+
+fn instruction_len(opecode: u8) -> u16 {
+    match opecode & 0x1F {
+        0x00 if opecode == 0x20 => 3,
+        0x00 | 0x02 if opecode & 0x80 > 0 => 2,
+        0x00 | 0x02 | 0x08 | 0x0A | 0x12 | 0x18 | 0x1A => 1,
+        0x0C..=0x0F | 0x19 | 0x1B..=0x1F => 3,
+        _ => 2,
+    }
 }
 
 impl Debug for Cpu {
@@ -145,11 +209,117 @@ impl Cpu {
             pc: 0,
             irq: false,
             halt: false,
+            page_crossed: false,
+            extra_cycles: 0,
+            instructions: 0,
+            total_cycles: 0,
             bus,
+            trace: None,
+            history: VecDeque::new(),
+            history_len: 0,
+            watchdog: None,
+            idle_instructions: 0,
+            stalled: false,
         }
     }
 
-    pub fn reset(&mut self) -> Result<()> {
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Write>>) {
+        self.trace = trace;
+    }
+
+    pub fn take_trace(&mut self) -> Option<Box<dyn Write>> {
+        self.trace.take()
+    }
+
+    pub fn flush_trace(&mut self) -> Result<()> {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.flush()?;
+        }
+
+        Ok(())
+    }
+
+    // クラッシュレポート用に直近の命令のトレースを残しておく (0で無効)
+    pub fn set_history_len(&mut self, len: usize) {
+        self.history_len = len;
+
+        while self.history.len() > len {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history_len
+    }
+
+    pub fn history(&self) -> Vec<String> {
+        self.history.iter().cloned().collect()
+    }
+
+    pub fn peek(&self, addr: u16) -> Result<u8> {
+        self.bus.peek(addr)
+    }
+
+    pub fn wram(&self) -> &[u8; 0x0800] {
+        &self.bus.wram
+    }
+
+    // nestest.logと同じ形式で、命令を実行する前の状態を書き出す
+    fn write_trace(&mut self, cycles: u64) -> Result<()> {
+        let len = instruction_len(self.bus.peek(self.pc)?);
+
+        let mut bytes = String::new();
+        let mut code = Vec::new();
+
+        for i in 0..3 {
+            if i < len {
+                let byte = self.bus.peek(self.pc.wrapping_add(i))?;
+                bytes.push_str(&format!("{:02X} ", byte));
+                code.push(byte);
+            } else {
+                bytes.push_str("   ");
+            }
+        }
+
+        let disassembly = asm::disassemble_range(&code, self.pc)
+            .into_iter()
+            .next()
+            .map(|(_, text)| text)
+            .unwrap_or_default();
+
+        let (line, dot) = self.bus.ppu_position();
+
+        let line = format!(
+            "{:04X}  {} {:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}\n",
+            self.pc,
+            bytes,
+            disassembly,
+            self.a,
+            self.x,
+            self.y,
+            self.p.0,
+            self.s,
+            line,
+            dot,
+            cycles
+        );
+
+        if let Some(trace) = self.trace.as_mut() {
+            trace.write_all(line.as_bytes())?;
+        }
+
+        if self.history_len > 0 {
+            if self.history.len() == self.history_len {
+                self.history.pop_front();
+            }
+
+            self.history.push_back(line.trim_end().to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn power_on(&mut self) -> Result<()> {
         self.a = 0;
         self.x = 0;
         self.y = 0;
@@ -157,12 +327,88 @@ impl Cpu {
         self.p = P(0x24);
         self.pc = self.bus.read_word(0xFFFC)?;
         self.bus.stalls = 0;
+        self.halt = false;
+
+        // nestest.logに合わせて、リセットシーケンスの7サイクルから数える
+        self.total_cycles = INTERRUPT_CYCLES as u64;
+
+        Ok(())
+    }
+
+    // ソフトリセットはIを立ててSを3つ進めるだけで、他のレジスタはそのまま
+    pub fn reset(&mut self) -> Result<()> {
+        self.s = self.s.wrapping_sub(3);
+        self.p.set_i(true);
+        self.pc = self.bus.read_word(0xFFFC)?;
+        self.bus.stalls = 0;
+        self.halt = false;
 
         Ok(())
     }
 
+    pub fn set_watchdog(&mut self, limit: Option<u64>) {
+        self.watchdog = limit;
+        self.idle_instructions = 0;
+    }
+
+    pub fn watchdog(&self) -> Option<u64> {
+        self.watchdog
+    }
+
+    pub fn take_stalled(&mut self) -> bool {
+        let stalled = self.stalled;
+
+        self.stalled = false;
+
+        stalled
+    }
+
+    // $2002の読み込みもNMIもないまま命令を実行し続けたら止まっているとみなす
+    fn watch(&mut self, pc: u16) {
+        let limit = match self.watchdog {
+            Some(limit) => limit,
+            None => return,
+        };
+
+        if self.bus.take_status_read() {
+            self.idle_instructions = 0;
+            return;
+        }
+
+        self.idle_instructions += 1;
+
+        if self.idle_instructions >= limit {
+            error!("CPU STALLED: ${:04X}", pc);
+
+            self.stalled = true;
+            self.idle_instructions = 0;
+        }
+    }
+
+    pub fn set_pc(&mut self, addr: u16) {
+        self.pc = addr;
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.s
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halt
+    }
+
+    // 実行した命令の数 (DMAやストールのサイクルは数えない)
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
     pub fn tick(&mut self) -> Result<()> {
         self.bus.cycles = self.bus.cycles.wrapping_add(1);
+        self.total_cycles += 1;
 
         self.bus.tick()?;
 
@@ -172,39 +418,51 @@ impl Cpu {
             return Ok(());
         }
 
-        self.interrupt()?;
+        if self.bus.dma_requested() {
+            return self.bus.start_dma();
+        }
+
+        if self.bus.dma_active() {
+            return self.bus.step_dma();
+        }
 
+        // 止まったCPUは割り込みも受け付けない
         if self.halt {
             return Ok(());
         }
 
-        let opecode = self.bus.read(self.pc)?;
+        let interrupt_cycles = self.interrupt()?;
+
+        if self.trace.is_some() || self.history_len > 0 {
+            // 割り込みと命令は同じティックでまとめて実行するので、割り込みの分だけ後ろにずらす
+            self.write_trace(self.total_cycles - 1 + interrupt_cycles as u64)?;
+        }
+
+        let pc = self.pc;
+        let opecode = self.bus.read(pc)?;
 
         self.pc = self.pc.wrapping_add(1);
 
-        self.do_mnemonic(opecode)?;
+        self.extra_cycles = 0;
+        // オペランドを読まない命令でも、前の命令のページ跨ぎを残さない
+        self.page_crossed = false;
 
-        Ok(())
-    }
+        self.do_mnemonic(opecode)?;
 
-    fn is_overflow_positive(&mut self, left: u8, right: u8) -> bool {
-        let result = left.wrapping_add(right);
+        self.instructions += 1;
 
-        let s1 = (left >> 7) > 0;
-        let s2 = (right >> 7) > 0;
-        let s3 = (result >> 7) > 0;
+        if self.page_crossed && has_page_penalty(opecode) {
+            self.extra_cycles += 1;
+        }
 
-        (s1 && s2 && !s3) || (!s1 && !s2 && s3)
-    }
+        // 命令は1回でまとめて実行するので、残りのサイクルは何もせずに待つ
+        let cycles = interrupt_cycles + CYCLES[opecode as usize] as u16 + self.extra_cycles;
 
-    fn is_overflow_negative(&mut self, left: u8, right: u8) -> bool {
-        let result = left.wrapping_sub(right);
+        self.bus.stalls += cycles - 1;
 
-        let s1 = (left >> 7) > 0;
-        let s2 = (right >> 7) > 0;
-        let s3 = (result >> 7) > 0;
+        self.watch(pc);
 
-        (!s1 && s2 && s3) || (s1 && !s2 && !s3)
+        Ok(())
     }
 
     fn read_operand_addr_zero_page(&mut self, index: u8) -> Result<u16> {
@@ -222,9 +480,20 @@ impl Cpu {
 
         let addr = offset.wrapping_add(index as u16);
 
+        self.page_crossed = Self::crosses_page(offset, addr);
+
         Ok(addr)
     }
 
+    fn crosses_page(base: u16, addr: u16) -> bool {
+        base & 0xFF00 != addr & 0xFF00
+    }
+
+    // 直前のオペランドのインデックスがページを跨いだか (読み込み命令は1サイクル増える)
+    pub fn page_crossed(&self) -> bool {
+        self.page_crossed
+    }
+
     // TODO BUSに移動する
     fn read_operand_addr_indirect_page(&self, hop_addr: u16) -> Result<u16> {
         let low = self.bus.read(hop_addr)?;
@@ -243,6 +512,8 @@ impl Cpu {
     }
 
     fn read_operand_addr(&mut self, mode: AddrMode) -> Result<u16> {
+        self.page_crossed = false;
+
         match mode {
             // INST #i
             AddrMode::Immediate => {
@@ -287,13 +558,33 @@ impl Cpu {
             // INST (d),y
             AddrMode::IndirectIndexedY => {
                 let hop_addr = self.read_operand_addr_zero_page(0)?;
-                let addr = self.read_operand_addr_indirect_zero_page(hop_addr)?;
+                let base = self.read_operand_addr_indirect_zero_page(hop_addr)?;
+                let addr = base.wrapping_add(self.y as u16);
 
-                Ok(addr.wrapping_add(self.y as u16))
+                self.page_crossed = Self::crosses_page(base, addr);
+
+                Ok(addr)
             }
         }
     }
 
+    // インデックス付きの書き込みは、ページの繰り上がりを直す前のアドレスを一度読んでしまう
+    fn read_operand_addr_for_write(&mut self, mode: AddrMode) -> Result<u16> {
+        let addr = self.read_operand_addr(mode)?;
+
+        let index = match mode {
+            AddrMode::AbsoluteIndexedX => self.x,
+            AddrMode::AbsoluteIndexedY | AddrMode::IndirectIndexedY => self.y,
+            _ => return Ok(addr),
+        };
+
+        let base = addr.wrapping_sub(index as u16);
+
+        self.bus.read((base & 0xFF00) | (addr & 0x00FF))?;
+
+        Ok(addr)
+    }
+
     fn set_z_by(&mut self, val: u8) {
         self.p.set_z(val == 0);
     }
@@ -307,32 +598,41 @@ impl Cpu {
         self.set_n_by(val);
     }
 
-    fn interrupt(&mut self) -> Result<()> {
+    // BRK/PHPはB=1、NMI/IRQはB=0で積む (bit5は常に1)
+    fn push_status(&mut self, brk: bool) -> Result<()> {
+        let mut p = self.p;
+
+        p.set_b(if brk { 0b11 } else { 0b10 });
+
+        self.push_8(p.0)
+    }
+
+    fn interrupt(&mut self) -> Result<u16> {
+        let mut cycles = 0;
+
         if self.bus.nmi() {
+            self.idle_instructions = 0;
+
             self.push_16(self.pc)?;
             self.pc = self.bus.read_word(0xFFFA)?;
 
-            let mut p = self.p.clone();
-
-            p.set_b(0b10);
-
-            self.push_8(p.0)?;
+            self.push_status(false)?;
             self.p.set_i(true);
+
+            cycles += INTERRUPT_CYCLES;
         }
 
-        if !self.p.i() && self.irq {
+        if !self.p.i() && (self.irq || self.bus.irq()) {
             self.push_16(self.pc)?;
             self.pc = self.bus.read_word(0xFFFE)?;
 
-            let mut p = self.p.clone();
-
-            p.set_b(0b10);
-
-            self.push_8(p.0)?;
+            self.push_status(false)?;
             self.p.set_i(true);
+
+            cycles += INTERRUPT_CYCLES;
         }
 
-        Ok(())
+        Ok(cycles)
     }
 
     #[bitmatch]
@@ -360,7 +660,9 @@ impl Cpu {
 
             // +04
             // NOP d
-            "hhh00100" if h == 0b000 || h == 0b010 || h == 0b011 => self.nop(1),
+            "hhh00100" if h == 0b000 || h == 0b010 || h == 0b011 => {
+                self.nop_read(AddrMode::ZeroPage)
+            }
             // BIT d, BIT a
             "0010m100" => self.bit(self.addr_mode_from_ctrl_mode(m)),
 
@@ -394,7 +696,7 @@ impl Cpu {
 
             // +0C
             // NOP a
-            "00001100" => self.nop(2),
+            "00001100" => self.nop_read(AddrMode::Absolute),
             // JMP a
             "01001100" => self.jmp(AddrMode::Absolute),
             // JMP (a)
@@ -420,7 +722,7 @@ impl Cpu {
 
             // +14
             // NOP d,x
-            "hhh10100" if h != 0b100 && h != 0b101 => self.nop(1),
+            "hhh10100" if h != 0b100 && h != 0b101 => self.nop_read(AddrMode::ZeroPageIndexedX),
 
             // +18
             // CLC
@@ -442,7 +744,7 @@ impl Cpu {
 
             // +1C
             // NOP a,x
-            "hhh11100" if h != 0b100 && h != 0b101 => self.nop(2),
+            "hhh11100" if h != 0b100 && h != 0b101 => self.nop_read(AddrMode::AbsoluteIndexedX),
             // SHY a,x
             "10011100" => self.shy(AddrMode::AbsoluteIndexedX),
 
@@ -531,6 +833,24 @@ impl Cpu {
             "10011110" => self.shx(AddrMode::AbsoluteIndexedY),
 
             // unoficial
+            // AHX (d),y
+            "10010011" => self.ahx(AddrMode::IndirectIndexedY),
+            // AHX a,y
+            "10011111" => self.ahx(AddrMode::AbsoluteIndexedY),
+            // TAS a,y
+            "10011011" => self.tas(AddrMode::AbsoluteIndexedY),
+            // LAS a,y
+            "10111011" => self.las(AddrMode::AbsoluteIndexedY),
+
+            // ANC #i
+            "00?01011" => self.anc(AddrMode::Immediate),
+
+            // ALR #i
+            "01001011" => self.alr(AddrMode::Immediate),
+
+            // XAA #i
+            "10001011" => self.xaa(AddrMode::Immediate),
+
             // LAX
             "101mmm11" => self.lax(self.addr_mode_from_ax_mode(m)),
 
@@ -543,6 +863,9 @@ impl Cpu {
             // ISC
             "111mmm11" if m != 0b010 => self.isc(self.addr_mode_from_alu_mode(m)),
 
+            // ARR #i
+            "01101011" => self.arr(AddrMode::Immediate),
+
             // AXS #i
             "11001011" => self.axs(AddrMode::Immediate),
 
@@ -640,20 +963,17 @@ impl Cpu {
         Ok(result)
     }
 
+    // 1バイトずつ積まないと、Sが0x00をまたぐときに$0200へはみ出してしまう
     fn push_16(&mut self, data: u16) -> Result<()> {
-        self.s = self.s.wrapping_sub(1);
-        self.bus.write_word(STACK_BASE + self.s as u16, data)?;
-        self.s = self.s.wrapping_sub(1);
-
-        Ok(())
+        self.push_8((data >> 8) as u8)?;
+        self.push_8(data as u8)
     }
 
     fn pop_16(&mut self) -> Result<u16> {
-        self.s = self.s.wrapping_add(1);
-        let result = self.bus.read_word(STACK_BASE + self.s as u16)?;
-        self.s = self.s.wrapping_add(1);
+        let low = self.pop_8()?;
+        let high = self.pop_8()?;
 
-        Ok(result)
+        Ok(((high as u16) << 8) | (low as u16))
     }
 
     fn nop(&mut self, padding: u16) -> Result<()> {
@@ -664,13 +984,24 @@ impl Cpu {
         Ok(())
     }
 
+    // メモリを指すNOPも読み込みはするので、レジスタの副作用やページ跨ぎのサイクルが起きる
+    fn nop_read(&mut self, mode: AddrMode) -> Result<()> {
+        let addr = self.read_operand_addr(mode)?;
+
+        self.bus.read(addr)?;
+
+        trace!("{:?}: NOP {}", self, ActualAddr(mode, addr));
+
+        Ok(())
+    }
+
     fn brk(&mut self) -> Result<()> {
         let addr = self.bus.read_word(0xFFFE)?;
 
         trace!("{:?}: BRK", self);
 
         self.push_16(self.pc + 1)?;
-        self.push_8(self.p.0 | 0b00110000)?;
+        self.push_status(true)?;
 
         self.p.set_i(true);
 
@@ -773,7 +1104,7 @@ impl Cpu {
 
     fn sty(&mut self, mode: AddrMode) -> Result<()> {
         let data = self.y;
-        let addr = self.read_operand_addr(mode)?;
+        let addr = self.read_operand_addr_for_write(mode)?;
 
         self.bus.write(addr, data)?;
 
@@ -783,7 +1114,7 @@ impl Cpu {
     }
 
     fn php(&mut self) -> Result<()> {
-        self.push_8(self.p.0 | 0b00110000)?;
+        self.push_status(true)?;
 
         trace!("{:?}: PHP", self);
 
@@ -865,6 +1196,13 @@ impl Cpu {
         Ok(())
     }
 
+    // 分岐すると1サイクル、さらにページを跨ぐともう1サイクルかかる
+    fn _branch(&mut self, addr: u16) -> Result<()> {
+        self.extra_cycles += 1 + Self::crosses_page(self.pc, addr) as u16;
+
+        self._jmp(addr)
+    }
+
     fn jmp(&mut self, mode: AddrMode) -> Result<()> {
         let addr = self.read_operand_addr(mode)?;
 
@@ -877,7 +1215,7 @@ impl Cpu {
         let addr = self.read_operand_addr(mode)?;
 
         if !self.p.n() {
-            self._jmp(addr)?;
+            self._branch(addr)?;
         }
 
         trace!("{:?}: BPL {}", self, ActualAddr(mode, addr));
@@ -889,7 +1227,7 @@ impl Cpu {
         let addr = self.read_operand_addr(mode)?;
 
         if self.p.n() {
-            self._jmp(addr)?;
+            self._branch(addr)?;
         }
 
         trace!("{:?}: BMI {}", self, ActualAddr(mode, addr));
@@ -901,7 +1239,7 @@ impl Cpu {
         let addr = self.read_operand_addr(mode)?;
 
         if !self.p.v() {
-            self._jmp(addr)?;
+            self._branch(addr)?;
         }
 
         trace!("{:?}: BVC {}", self, ActualAddr(mode, addr));
@@ -913,7 +1251,7 @@ impl Cpu {
         let addr = self.read_operand_addr(mode)?;
 
         if self.p.v() {
-            self._jmp(addr)?;
+            self._branch(addr)?;
         }
 
         trace!("{:?}: BVS {}", self, ActualAddr(mode, addr));
@@ -925,7 +1263,7 @@ impl Cpu {
         let addr = self.read_operand_addr(mode)?;
 
         if !self.p.c() {
-            self._jmp(addr)?;
+            self._branch(addr)?;
         }
 
         trace!("{:?}: BCC {}", self, ActualAddr(mode, addr));
@@ -937,7 +1275,7 @@ impl Cpu {
         let addr = self.read_operand_addr(mode)?;
 
         if self.p.c() {
-            self._jmp(addr)?;
+            self._branch(addr)?;
         }
 
         trace!("{:?}: BCS {}", self, ActualAddr(mode, addr));
@@ -949,7 +1287,7 @@ impl Cpu {
         let addr = self.read_operand_addr(mode)?;
 
         if !self.p.z() {
-            self._jmp(addr)?;
+            self._branch(addr)?;
         }
 
         trace!("{:?}: BNE {}", self, ActualAddr(mode, addr));
@@ -961,7 +1299,7 @@ impl Cpu {
         let addr = self.read_operand_addr(mode)?;
 
         if self.p.z() {
-            self._jmp(addr)?;
+            self._branch(addr)?;
         }
 
         trace!("{:?}: BEQ {}", self, ActualAddr(mode, addr));
@@ -1035,8 +1373,10 @@ impl Cpu {
         Ok(())
     }
 
-    fn shy(&mut self, _mode: AddrMode) -> Result<()> {
-        debug!("SHY");
+    fn shy(&mut self, mode: AddrMode) -> Result<()> {
+        let addr = self._sh(mode, self.y)?;
+
+        trace!("{:?}: SHY {}", self, ActualAddr(mode, addr));
 
         Ok(())
     }
@@ -1080,22 +1420,24 @@ impl Cpu {
         Ok(())
     }
 
-    fn adc(&mut self, mode: AddrMode) -> Result<()> {
+    // 途中の値ではなく、キャリーまで足した最終結果からフラグを決める
+    fn _adc(&mut self, right: u8) {
         let left = self.a;
-        let addr = self.read_operand_addr(mode)?;
-        let right = self.bus.read(addr)?;
-        let c = self.p.c() as u8;
+        let sum = left as u16 + right as u16 + self.p.c() as u16;
+        let result = sum as u8;
 
-        let (result1, c1) = left.overflowing_add(right);
-        let (result2, c2) = result1.overflowing_add(c);
-        let v1 = self.is_overflow_positive(left, right);
-        let v2 = self.is_overflow_positive(result1, c);
+        self.a = result;
 
-        self.a = result2;
+        self.set_zn_by(result);
+        self.p.set_v((left ^ result) & (right ^ result) & 0x80 != 0);
+        self.p.set_c(sum > 0xFF);
+    }
+
+    fn adc(&mut self, mode: AddrMode) -> Result<()> {
+        let addr = self.read_operand_addr(mode)?;
+        let right = self.bus.read(addr)?;
 
-        self.set_zn_by(result2);
-        self.p.set_v(v1 | v2);
-        self.p.set_c(c1 | c2);
+        self._adc(right);
 
         trace!("{:?}: ADC {}", self, ActualAddr(mode, addr));
 
@@ -1104,7 +1446,7 @@ impl Cpu {
 
     fn sta(&mut self, mode: AddrMode) -> Result<()> {
         let data = self.a;
-        let addr = self.read_operand_addr(mode)?;
+        let addr = self.read_operand_addr_for_write(mode)?;
 
         self.bus.write(addr, data)?;
 
@@ -1140,6 +1482,63 @@ impl Cpu {
         Ok(())
     }
 
+    fn las(&mut self, mode: AddrMode) -> Result<()> {
+        let addr = self.read_operand_addr(mode)?;
+        let data = self.bus.read(addr)? & self.s;
+
+        self.a = data;
+        self.x = data;
+        self.s = data;
+
+        self.set_zn_by(data);
+
+        trace!("{:?}: LAS {}", self, ActualAddr(mode, addr));
+
+        Ok(())
+    }
+
+    fn ahx(&mut self, mode: AddrMode) -> Result<()> {
+        let addr = self._sh(mode, self.a & self.x)?;
+
+        trace!("{:?}: AHX {}", self, ActualAddr(mode, addr));
+
+        Ok(())
+    }
+
+    fn tas(&mut self, mode: AddrMode) -> Result<()> {
+        self.s = self.a & self.x;
+
+        let addr = self._sh(mode, self.s)?;
+
+        trace!("{:?}: TAS {}", self, ActualAddr(mode, addr));
+
+        Ok(())
+    }
+
+    // SHX/SHY/AHX/TASはベースアドレスの上位バイト+1とANDした値を書き込む
+    // ページを跨ぐと、書き込み先の上位バイトもその値に化ける
+    fn _sh(&mut self, mode: AddrMode, data: u8) -> Result<u16> {
+        let addr = self.read_operand_addr_for_write(mode)?;
+
+        let index = match mode {
+            AddrMode::AbsoluteIndexedX => self.x,
+            _ => self.y,
+        };
+
+        let base = addr.wrapping_sub(index as u16);
+        let data = data & ((base >> 8) as u8).wrapping_add(1);
+
+        let addr = if self.page_crossed {
+            ((data as u16) << 8) | (addr & 0x00FF)
+        } else {
+            addr
+        };
+
+        self.bus.write(addr, data)?;
+
+        Ok(addr)
+    }
+
     fn sax(&mut self, mode: AddrMode) -> Result<()> {
         let addr = self.read_operand_addr(mode)?;
         let data = self.a & self.x;
@@ -1161,30 +1560,23 @@ impl Cpu {
     }
 
     fn sbc(&mut self, mode: AddrMode) -> Result<()> {
-        let left = self.a;
         let addr = self.read_operand_addr(mode)?;
         let right = self.bus.read(addr)?;
 
-        let c = !self.p.c() as u8;
-
-        let (result1, c1) = left.overflowing_sub(right);
-        let (result2, c2) = result1.overflowing_sub(c);
-        let v1 = self.is_overflow_negative(left, right);
-        let v2 = self.is_overflow_negative(result1, c);
-
-        self.a = result2;
-
-        self.set_zn_by(result2);
-        self.p.set_v(v1 | v2);
-        self.p.set_c(!(c1 | c2));
+        // A - M - (1 - C) == A + !M + C
+        self._adc(!right);
 
         trace!("{:?}: SBC {}", self, ActualAddr(mode, addr));
 
         Ok(())
     }
 
+    // STP(KIL)はリセットされるまでCPUを止める。PCはオペコードを指したまま
     fn stp(&mut self) -> Result<()> {
-        debug!("STP");
+        self.pc = self.pc.wrapping_sub(1);
+        self.halt = true;
+
+        debug!("STP: ${:04X}", self.pc);
 
         Ok(())
     }
@@ -1214,6 +1606,7 @@ impl Cpu {
         if mode == AddrMode::Accumulator {
             self.a = result;
         } else {
+            self.bus.write(addr, data)?;
             self.bus.write(addr, result)?;
         };
 
@@ -1261,7 +1654,7 @@ impl Cpu {
 
     fn stx(&mut self, mode: AddrMode) -> Result<()> {
         let data = self.x;
-        let addr = self.read_operand_addr(mode)?;
+        let addr = self.read_operand_addr_for_write(mode)?;
 
         self.bus.write(addr, data)?;
 
@@ -1288,6 +1681,7 @@ impl Cpu {
 
         let data = left.wrapping_sub(1);
 
+        self.bus.write(addr, left)?;
         self.bus.write(addr, data)?;
 
         self.set_zn_by(data);
@@ -1303,6 +1697,7 @@ impl Cpu {
 
         let data = left.wrapping_add(1);
 
+        self.bus.write(addr, left)?;
         self.bus.write(addr, data)?;
 
         self.set_zn_by(data);
@@ -1392,6 +1787,69 @@ impl Cpu {
         Ok(())
     }
 
+    fn anc(&mut self, mode: AddrMode) -> Result<()> {
+        let addr = self.read_operand_addr(mode)?;
+        let data = self.bus.read(addr)? & self.a;
+
+        self.a = data;
+
+        self.set_zn_by(data);
+        self.p.set_c(self.p.n());
+
+        trace!("{:?}: ANC {}", self, ActualAddr(mode, addr));
+
+        Ok(())
+    }
+
+    fn alr(&mut self, mode: AddrMode) -> Result<()> {
+        let addr = self.read_operand_addr(mode)?;
+        let data = self.bus.read(addr)? & self.a;
+
+        let result = data >> 1;
+
+        self.a = result;
+
+        self.set_zn_by(result);
+        self.p.set_c(Self::carry_shr(data));
+
+        trace!("{:?}: ALR {}", self, ActualAddr(mode, addr));
+
+        Ok(())
+    }
+
+    fn xaa(&mut self, mode: AddrMode) -> Result<()> {
+        let addr = self.read_operand_addr(mode)?;
+        let data = self.bus.read(addr)?;
+
+        let result = (self.a | XAA_MAGIC) & self.x & data;
+
+        self.a = result;
+
+        self.set_zn_by(result);
+
+        trace!("{:?}: XAA {}", self, ActualAddr(mode, addr));
+
+        Ok(())
+    }
+
+    // NESの6502はデシマルモードがないので、Dフラグに関係なくこの結果になる
+    fn arr(&mut self, mode: AddrMode) -> Result<()> {
+        let addr = self.read_operand_addr(mode)?;
+        let data = self.bus.read(addr)? & self.a;
+
+        let result = (data >> 1) | ((self.p.c() as u8) << 7);
+
+        self.a = result;
+
+        self.set_zn_by(result);
+        self.p.set_c((result >> 6) & 1 == 1);
+        self.p.set_v(((result >> 6) ^ (result >> 5)) & 1 == 1);
+
+        trace!("{:?}: ARR {}", self, ActualAddr(mode, addr));
+
+        Ok(())
+    }
+
     fn axs(&mut self, mode: AddrMode) -> Result<()> {
         let addr = self.read_operand_addr(mode)?;
         let right = self.bus.read(addr)?;
@@ -1474,9 +1932,777 @@ impl Cpu {
         Ok(())
     }
 
-    fn shx(&mut self, _mode: AddrMode) -> Result<()> {
-        debug!("SHX");
+    fn shx(&mut self, mode: AddrMode) -> Result<()> {
+        let addr = self._sh(mode, self.x)?;
+
+        trace!("{:?}: SHX {}", self, ActualAddr(mode, addr));
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        apu::Apu,
+        bus::PpuBus,
+        joypad::Joypad,
+        mmc::new_mmc,
+        nes::Accuracy,
+        ppu::Ppu,
+        rom::{Mirroring, Rom},
+    };
+
+    // $8000からプログラムを置いて電源を入れたCPU (残りはNOPで埋める)
+    fn cpu_with(program: &[u8]) -> Cpu {
+        cpu_and_ppu_with(program).0
+    }
+
+    // NMIを起こせるように、PPUも一緒に返す
+    fn cpu_and_ppu_with(program: &[u8]) -> (Cpu, Rc<RefCell<Ppu>>) {
+        let mut prg = vec![0xEA; 0x8000];
+
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x7FFC] = 0x00;
+        prg[0x7FFD] = 0x80;
+
+        let rom = Rom::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Horizontal).unwrap();
+        let mmc = Rc::new(RefCell::new(new_mmc(rom).unwrap()));
+        let ppu = Rc::new(RefCell::new(Ppu::new(
+            PpuBus::new(Rc::clone(&mmc)),
+            Accuracy::Cycle,
+        )));
+
+        let bus = CpuBus::new(
+            mmc,
+            Rc::clone(&ppu),
+            Rc::new(RefCell::new(Apu::new())),
+            Rc::new(RefCell::new(Joypad::new())),
+            Rc::new(RefCell::new(Joypad::new())),
+            Accuracy::Cycle,
+        );
+
+        let mut cpu = Cpu::new(bus);
+
+        cpu.power_on().unwrap();
+
+        (cpu, ppu)
+    }
+
+    // VBlankフラグが立ってから少し進めて、読み込みによる抑制の窓を外す
+    fn enter_vblank(ppu: &Rc<RefCell<Ppu>>) {
+        let mut ppu = ppu.borrow_mut();
+
+        while ppu.peek_register(0x2002).unwrap() & 0x80 == 0 {
+            ppu.tick().unwrap();
+        }
+
+        for _ in 0..10 {
+            ppu.tick().unwrap();
+        }
+    }
+
+    // 1命令実行して、次の命令を読むまでにかかったサイクル数を返す
+    fn step(cpu: &mut Cpu) -> u64 {
+        cpu.tick().unwrap();
+
+        let mut cycles = 1;
+
+        while cpu.bus.stalls > 0 || cpu.bus.dma_active() {
+            cpu.tick().unwrap();
+            cycles += 1;
+        }
+
+        cycles
+    }
+
+    #[test]
+    fn compare_sets_carry_zero_and_negative_from_the_difference() {
+        // (レジスタ, オペランド, C, Z, N)
+        let cases = [
+            (0x00, 0x00, true, true, false),
+            (0x01, 0x00, true, false, false),
+            (0x00, 0x01, false, false, true),
+            (0x80, 0x00, true, false, true),
+            (0x00, 0x80, false, false, true),
+            (0x7F, 0x80, false, false, true),
+            (0x80, 0x7F, true, false, false),
+            (0xFF, 0x00, true, false, true),
+            (0xFF, 0xFF, true, true, false),
+        ];
+
+        // CMP #i, CPX #i, CPY #i
+        for &opecode in [0xC9, 0xE0, 0xC0].iter() {
+            for &(left, right, c, z, n) in cases.iter() {
+                let mut cpu = cpu_with(&[opecode, right]);
+
+                match opecode {
+                    0xC9 => cpu.a = left,
+                    0xE0 => cpu.x = left,
+                    _ => cpu.y = left,
+                }
+
+                step(&mut cpu);
+
+                let name = format!("{:02X}: {:02X} - {:02X}", opecode, left, right);
+
+                assert_eq!(cpu.p.c(), c, "C {}", name);
+                assert_eq!(cpu.p.z(), z, "Z {}", name);
+                assert_eq!(cpu.p.n(), n, "N {}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn ldy_and_sty_zero_page_indexed_x() {
+        // LDY $10,X
+        let mut cpu = cpu_with(&[0xB4, 0x10]);
+
+        cpu.x = 0x05;
+        cpu.bus.wram[0x15] = 0x80;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.y, 0x80);
+        assert!(cpu.p.n());
+        assert!(!cpu.p.z());
+
+        // STY $10,X
+        let mut cpu = cpu_with(&[0x94, 0x10]);
+
+        cpu.x = 0x05;
+        cpu.y = 0x42;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.bus.wram[0x15], 0x42);
+        assert_eq!(cpu.bus.wram[0x10], 0xFF);
+
+        // LDY a,X
+        let mut cpu = cpu_with(&[0xBC, 0x00, 0x02]);
+
+        cpu.x = 0x03;
+        cpu.bus.wram[0x0203] = 0x00;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.y, 0x00);
+        assert!(cpu.p.z());
+    }
+
+    #[test]
+    fn bit_decodes_only_zero_page_and_absolute() {
+        // $34はBIT d,xではなくNOP d,x
+        let mut cpu = cpu_with(&[0x34, 0x10]);
+
+        cpu.bus.wram[0x10] = 0xC0;
+
+        step(&mut cpu);
+
+        assert!(!cpu.p.n());
+        assert!(!cpu.p.v());
+        assert_eq!(cpu.pc, 0x8002);
+
+        // BIT d
+        let mut cpu = cpu_with(&[0x24, 0x10]);
+
+        cpu.bus.wram[0x10] = 0xC0;
+
+        step(&mut cpu);
+
+        assert!(cpu.p.n());
+        assert!(cpu.p.v());
+    }
+
+    #[test]
+    fn indirect_pointer_wraps_in_zero_page() {
+        // ポインタが$FFにあると上位バイトは$0100ではなく$00から読む
+        let setup = |cpu: &mut Cpu| {
+            cpu.bus.wram[0x00FF] = 0x34;
+            cpu.bus.wram[0x0000] = 0x02;
+            cpu.bus.wram[0x0100] = 0x03;
+            cpu.bus.wram[0x0234] = 0x77;
+            cpu.bus.wram[0x0236] = 0x78;
+            cpu.bus.wram[0x0334] = 0x11;
+            cpu.bus.wram[0x0336] = 0x12;
+        };
+
+        // LDA ($FF,X)
+        let mut cpu = cpu_with(&[0xA1, 0xFF]);
+
+        setup(&mut cpu);
+        cpu.x = 0x00;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.a, 0x77);
+
+        // LDA ($FE,X) は$FE+1=$FFを指す
+        let mut cpu = cpu_with(&[0xA1, 0xFE]);
+
+        setup(&mut cpu);
+        cpu.x = 0x01;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.a, 0x77);
+
+        // LDA ($FF),Y
+        let mut cpu = cpu_with(&[0xB1, 0xFF]);
+
+        setup(&mut cpu);
+        cpu.y = 0x02;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.a, 0x78);
+    }
+
+    #[test]
+    fn arr_sets_carry_and_overflow_from_bits_6_and_5() {
+        // (A, オペランド, 前のC, 結果, C, V)
+        let cases = [
+            (0xFF, 0xFF, false, 0x7F, true, false),
+            (0xFF, 0xFF, true, 0xFF, true, false),
+            (0xFF, 0x40, false, 0x20, false, true),
+            (0xFF, 0x80, false, 0x40, true, true),
+            (0xFF, 0x01, false, 0x00, false, false),
+            (0x0F, 0xF0, true, 0x80, false, false),
+            (0xC0, 0xFF, false, 0x60, true, false),
+        ];
+
+        for &(a, operand, carry, result, c, v) in cases.iter() {
+            // ARR #i
+            let mut cpu = cpu_with(&[0x6B, operand]);
+
+            cpu.a = a;
+            cpu.p.set_c(carry);
+
+            step(&mut cpu);
+
+            let name = format!("{:02X} & {:02X} (C={})", a, operand, carry);
+
+            assert_eq!(cpu.a, result, "A {}", name);
+            assert_eq!(cpu.p.c(), c, "C {}", name);
+            assert_eq!(cpu.p.v(), v, "V {}", name);
+            assert_eq!(cpu.p.z(), result == 0, "Z {}", name);
+            assert_eq!(cpu.p.n(), result & 0x80 != 0, "N {}", name);
+        }
+    }
+
+    #[test]
+    fn zero_page_indexed_wraps_in_zero_page() {
+        // LDA $FF,X は$0100ではなく$00を読む
+        let mut cpu = cpu_with(&[0xB5, 0xFF]);
+
+        cpu.x = 0x01;
+        cpu.bus.wram[0x0000] = 0x12;
+        cpu.bus.wram[0x0100] = 0x34;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.a, 0x12);
+
+        // LDX $FF,Y
+        let mut cpu = cpu_with(&[0xB6, 0xFF]);
+
+        cpu.y = 0x02;
+        cpu.bus.wram[0x0001] = 0x56;
+        cpu.bus.wram[0x0101] = 0x78;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.x, 0x56);
+
+        // STA $80,X も同じように折り返す
+        let mut cpu = cpu_with(&[0x95, 0x80]);
+
+        cpu.a = 0x9A;
+        cpu.x = 0x90;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.bus.wram[0x0010], 0x9A);
+        assert_eq!(cpu.bus.wram[0x0110], 0xFF);
+    }
+
+    #[test]
+    fn countdown_loop_takes_1281_cycles() {
+        // LDX #$00; DEX; BNE $8002
+        let mut cpu = cpu_with(&[0xA2, 0x00, 0xCA, 0xD0, 0xFD]);
+
+        let mut cycles = 0;
+
+        while cpu.pc != 0x8005 {
+            cycles += step(&mut cpu);
+        }
+
+        // LDX 2 + (DEX 2 + 分岐成立 3) x 255 + DEX 2 + 分岐不成立 2
+        assert_eq!(cycles, 1281);
+    }
+
+    #[test]
+    fn oam_dma_alignment_follows_the_write_cycle() {
+        // LDA $00 (3) と NOP (2) で$4014に書くサイクルの偶奇を変える
+        let programs: [&[u8]; 2] = [
+            &[0xEA, 0xA9, 0x02, 0x8D, 0x14, 0x40],
+            &[0xA5, 0x00, 0xA9, 0x02, 0x8D, 0x14, 0x40],
+        ];
+
+        let mut totals = Vec::new();
+
+        for program in programs.iter() {
+            let mut cpu = cpu_with(program);
+
+            step(&mut cpu);
+            step(&mut cpu);
+
+            // STA aは4サイクル目に書き込む
+            let write_cycle = cpu.bus.cycles.wrapping_add(4);
+            let total = step(&mut cpu);
+
+            assert_eq!(total, 4 + 513 + (write_cycle % 2) as u64);
+
+            totals.push(total);
+        }
+
+        assert_ne!(totals[0], totals[1]);
+    }
+
+    #[test]
+    fn stack_wraps_within_page_one() {
+        // PHP (S=$00)
+        let mut cpu = cpu_with(&[0x08]);
+
+        cpu.s = 0x00;
+        cpu.p = P(0xC3);
+        cpu.bus.wram[0x0000] = 0x00;
+        cpu.bus.wram[0x0200] = 0x00;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.s, 0xFF);
+        assert_eq!(cpu.bus.wram[0x0100], 0xF3);
+        assert_eq!(cpu.bus.wram[0x0000], 0x00);
+        assert_eq!(cpu.bus.wram[0x0200], 0x00);
+
+        // PLP (S=$FF)
+        let mut cpu = cpu_with(&[0x28]);
+
+        cpu.s = 0xFF;
+        cpu.bus.wram[0x0100] = 0x81;
+        cpu.bus.wram[0x0200] = 0x42;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.s, 0x00);
+        assert!(cpu.p.n());
+        assert!(cpu.p.c());
+        assert!(!cpu.p.v());
+
+        // JSR $8010 (S=$00) して RTS で戻る
+        let mut cpu = cpu_with(&[0x20, 0x10, 0x80]);
+
+        cpu.s = 0x00;
+        cpu.bus.wram[0x0010] = 0x60;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.s, 0xFE);
+        assert_eq!(cpu.bus.wram[0x0100], 0x80);
+        assert_eq!(cpu.bus.wram[0x01FF], 0x02);
+
+        cpu.pc = 0x0010;
+
+        step(&mut cpu);
+
+        assert_eq!(cpu.s, 0x00);
+        assert_eq!(cpu.pc, 0x8003);
+    }
+
+    #[test]
+    fn indexed_reads_pay_for_page_crossing() {
+        // LDA $00FF,X
+        let cycles = |x| {
+            let mut cpu = cpu_with(&[0xBD, 0xFF, 0x00]);
+
+            cpu.x = x;
+
+            step(&mut cpu)
+        };
+
+        assert_eq!(cycles(0x00), 4);
+        assert_eq!(cycles(0x01), 5);
+
+        // STA $00FF,X は常に5サイクル
+        let mut cpu = cpu_with(&[0x9D, 0xFF, 0x00]);
+
+        cpu.x = 0x00;
+
+        assert_eq!(step(&mut cpu), 5);
+
+        // 非公式のNOP a,xも読み込み命令と同じ
+        for &opecode in [0x1C, 0x3C, 0x5C, 0x7C, 0xDC, 0xFC].iter() {
+            for &(x, cycles) in [(0x00, 4), (0x01, 5)].iter() {
+                let mut cpu = cpu_with(&[opecode, 0xFF, 0x00]);
+
+                cpu.x = x;
+
+                assert_eq!(step(&mut cpu), cycles, "{:02X} X={}", opecode, x);
+                assert_eq!(cpu.pc(), 0x8003);
+            }
+        }
+
+        // NOP a はオペランドのアドレスを実際に読む
+        let (mut cpu, ppu) = cpu_and_ppu_with(&[0x0C, 0x02, 0x20]);
+
+        enter_vblank(&ppu);
+
+        assert_eq!(step(&mut cpu), 4);
+        assert_eq!(ppu.borrow().peek_register(0x2002).unwrap() & 0x80, 0);
+    }
+
+    #[test]
+    fn absolute_indexed_reports_the_page_cross() {
+        let cases = [(0x00, false, 4), (0x01, true, 5), (0xFF, true, 5)];
+
+        for &(x, crossed, cycles) in cases.iter() {
+            // LDA $12FF,X
+            let mut cpu = cpu_with(&[0xBD, 0xFF, 0x12]);
+
+            cpu.x = x;
+
+            assert_eq!(step(&mut cpu), cycles, "X={:#04X}", x);
+            assert_eq!(cpu.page_crossed(), crossed, "X={:#04X}", x);
+        }
+
+        // 次の命令で読み直される
+        let mut cpu = cpu_with(&[0xBD, 0xFF, 0x12, 0xEA]);
+
+        cpu.x = 0x01;
+
+        step(&mut cpu);
+        step(&mut cpu);
+
+        assert!(!cpu.page_crossed());
+    }
+
+    #[test]
+    fn indexed_store_dummy_reads_the_unfixed_address() {
+        // STA $20FF,X (X=3) は$2102に書く前に$2002を読んでしまう
+        let (mut cpu, ppu) = cpu_and_ppu_with(&[0x9D, 0xFF, 0x20]);
+
+        cpu.x = 0x03;
+        cpu.a = 0x00;
+        enter_vblank(&ppu);
+
+        step(&mut cpu);
+
+        assert_eq!(ppu.borrow().peek_register(0x2002).unwrap() & 0x80, 0);
+
+        // ページを跨がなければダミーリードは書き込み先と同じ$2102 ($2002のミラー)
+        let (mut cpu, ppu) = cpu_and_ppu_with(&[0x9D, 0x00, 0x21]);
+
+        cpu.x = 0x02;
+        enter_vblank(&ppu);
+
+        step(&mut cpu);
+
+        assert_eq!(ppu.borrow().peek_register(0x2002).unwrap() & 0x80, 0);
+
+        // 無関係なRAMへのストアはVBlankを落とさない
+        let (mut cpu, ppu) = cpu_and_ppu_with(&[0x9D, 0xFF, 0x02]);
+
+        cpu.x = 0x03;
+        enter_vblank(&ppu);
+
+        step(&mut cpu);
+
+        assert_eq!(ppu.borrow().peek_register(0x2002).unwrap() & 0x80, 0x80);
+        assert_eq!(cpu.bus.wram[0x0302], 0x00);
+    }
+
+    #[test]
+    fn decimal_flag_is_stored_but_ignored_by_arithmetic() {
+        let mut cpu = cpu_with(&[
+            0xF8, // SED
+            0x08, // PHP
+            0x18, // CLC
+            0xA9, 0x09, // LDA #$09
+            0x69, 0x01, // ADC #$01
+            0x85, 0x00, // STA $00
+            0x38, // SEC
+            0xA9, 0x10, // LDA #$10
+            0xE9, 0x01, // SBC #$01
+        ]);
+
+        let s = cpu.s;
+
+        for _ in 0..9 {
+            step(&mut cpu);
+        }
+
+        assert!(cpu.p.d());
+        assert_eq!(cpu.bus.wram[0x0100 + s as usize] & 0x08, 0x08);
+
+        // BCDなら$10と$09になる
+        assert_eq!(cpu.bus.wram[0x0000], 0x0A);
+        assert_eq!(cpu.a, 0x0F);
+    }
+
+    #[test]
+    fn sh_stores_and_the_high_byte_plus_one() {
+        // (プログラム, A, X, Y, 書き込み先, 値) プログラムは3バイトに揃える
+        let cases = [
+            // SHX a,Y
+            ([0x9E, 0xF0, 0x02], 0x00, 0x01, 0x20, 0x0110, 0x01),
+            ([0x9E, 0x00, 0x02], 0x00, 0xFF, 0x10, 0x0210, 0x03),
+            // SHY a,X
+            ([0x9C, 0xF0, 0x02], 0x00, 0x20, 0x01, 0x0110, 0x01),
+            ([0x9C, 0x00, 0x02], 0x00, 0x10, 0xFF, 0x0210, 0x03),
+            // AHX a,Y
+            ([0x9F, 0xF0, 0x02], 0xFF, 0x05, 0x20, 0x0110, 0x01),
+            ([0x9F, 0x00, 0x02], 0x0F, 0xFF, 0x10, 0x0210, 0x03),
+            // AHX (d),Y ($10には$02F0)
+            ([0x93, 0x10, 0xEA], 0xFF, 0x01, 0x20, 0x0110, 0x01),
+            // TAS a,Y
+            ([0x9B, 0xF0, 0x02], 0xFF, 0x05, 0x20, 0x0110, 0x01),
+            ([0x9B, 0x00, 0x02], 0xFF, 0xFE, 0x10, 0x0210, 0x02),
+        ];
+
+        for &(program, a, x, y, addr, value) in cases.iter() {
+            let mut cpu = cpu_with(&program);
+
+            cpu.a = a;
+            cpu.x = x;
+            cpu.y = y;
+            cpu.bus.wram[0x0010] = 0xF0;
+            cpu.bus.wram[0x0011] = 0x02;
+
+            step(&mut cpu);
+
+            let name = format!("{:02X?} A={:02X} X={:02X} Y={:02X}", program, a, x, y);
+
+            assert_eq!(cpu.bus.wram[addr], value, "{}", name);
+
+            if program[0] == 0x9B {
+                assert_eq!(cpu.s, a & x, "S {}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn anc_alr_and_xaa_immediate() {
+        // (オペコード, A, X, オペランド, 結果, C)
+        let cases = [
+            // ANC #i
+            (0x0B, 0xF0, 0x00, 0x90, 0x90, true),
+            (0x2B, 0x0F, 0x00, 0xF0, 0x00, false),
+            // ALR #i
+            (0x4B, 0xFF, 0x00, 0x03, 0x01, true),
+            (0x4B, 0xFF, 0x00, 0x82, 0x41, false),
+            // XAA #i
+            (0x8B, 0x11, 0xFF, 0xFF, 0xFF, false),
+            (0x8B, 0x00, 0x0F, 0x3C, 0x0C, false),
+        ];
+
+        for &(opecode, a, x, operand, result, c) in cases.iter() {
+            let mut cpu = cpu_with(&[opecode, operand]);
+
+            cpu.a = a;
+            cpu.x = x;
+
+            step(&mut cpu);
+
+            let name = format!("{:02X}: A={:02X} X={:02X} #{:02X}", opecode, a, x, operand);
+
+            assert_eq!(cpu.a, result, "A {}", name);
+            assert_eq!(cpu.p.c(), c, "C {}", name);
+            assert_eq!(cpu.p.z(), result == 0, "Z {}", name);
+            assert_eq!(cpu.p.n(), result & 0x80 != 0, "N {}", name);
+            assert_eq!(cpu.pc, 0x8002, "PC {}", name);
+        }
+    }
+
+    #[test]
+    fn arr_ignores_the_decimal_flag() {
+        for &a in [0x00, 0x3C, 0x5A, 0x99, 0xC3, 0xFF].iter() {
+            for &operand in [0x01, 0x40, 0x7F, 0x80, 0xAA, 0xFF].iter() {
+                for &carry in [false, true].iter() {
+                    let run = |decimal| {
+                        // ARR #i
+                        let mut cpu = cpu_with(&[0x6B, operand]);
+
+                        cpu.a = a;
+                        cpu.p.set_c(carry);
+                        cpu.p.set_d(decimal);
+
+                        step(&mut cpu);
+
+                        (cpu.a, cpu.p.c(), cpu.p.v(), cpu.p.z(), cpu.p.n())
+                    };
+
+                    let (result, c, v, _, _) = run(false);
+                    let name = format!("{:02X} & {:02X} (C={})", a, operand, carry);
+
+                    assert_eq!(run(true), run(false), "{}", name);
+                    assert_eq!(
+                        result,
+                        ((a & operand) >> 1) | ((carry as u8) << 7),
+                        "{}",
+                        name
+                    );
+                    assert_eq!(c, result & 0x40 != 0, "C {}", name);
+                    assert_eq!(v, ((result >> 6) ^ (result >> 5)) & 1 == 1, "V {}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn adc_flags_come_from_the_final_sum() {
+        // (A, オペランド, キャリー入力, 結果, C, Z, V, N)
+        // キャリーを足す前と後で結果が変わり、途中の値で判定するとずれるもの
+        let cases = [
+            (0xFF, 0x01, false, 0x00, true, true, false, false),
+            (0xFF, 0x01, true, 0x01, true, false, false, false),
+            (0xFF, 0x00, true, 0x00, true, true, false, false),
+            (0x7F, 0x00, true, 0x80, false, false, true, true),
+            (0x80, 0xFF, true, 0x80, true, false, false, true),
+            (0x00, 0xFF, true, 0x00, true, true, false, false),
+        ];
+
+        for &(a, operand, carry, result, c, z, v, n) in cases.iter() {
+            // ADC #i
+            let mut cpu = cpu_with(&[0x69, operand]);
+
+            cpu.a = a;
+            cpu.p.set_c(carry);
+
+            step(&mut cpu);
+
+            let name = format!("{:02X} + {:02X} (C={})", a, operand, carry);
+
+            assert_eq!(cpu.a, result, "A {}", name);
+            assert_eq!(cpu.p.c(), c, "C {}", name);
+            assert_eq!(cpu.p.z(), z, "Z {}", name);
+            assert_eq!(cpu.p.v(), v, "V {}", name);
+            assert_eq!(cpu.p.n(), n, "N {}", name);
+        }
+    }
+
+    #[test]
+    fn sbc_matches_adc_of_the_inverted_operand() {
+        let run = |opecode, a, operand, carry| {
+            let mut cpu = cpu_with(&[opecode, operand]);
+
+            cpu.a = a;
+            cpu.p.set_c(carry);
+
+            step(&mut cpu);
+
+            (cpu.a, cpu.p.c(), cpu.p.z(), cpu.p.v(), cpu.p.n())
+        };
+
+        for &a in [0x00, 0x01, 0x50, 0x7F, 0x80, 0xD0, 0xFF].iter() {
+            for &operand in [0x00, 0x01, 0x30, 0x7F, 0x80, 0xB0, 0xFF].iter() {
+                for &carry in [false, true].iter() {
+                    let name = format!("{:02X} - {:02X} (C={})", a, operand, carry);
+
+                    // SBC #i, 非公式のSBC #i, ADC #!i
+                    let sbc = run(0xE9, a, operand, carry);
+
+                    assert_eq!(run(0xEB, a, operand, carry), sbc, "$EB {}", name);
+                    assert_eq!(run(0x69, a, !operand, carry), sbc, "ADC {}", name);
+
+                    let difference = a as i16 - operand as i16 - !carry as i16;
+
+                    assert_eq!(sbc.0, difference as u8, "A {}", name);
+                    assert_eq!(sbc.1, difference >= 0, "C {}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pushed_status_has_b_only_for_brk_and_php() {
+        // (命令, IRQ, NMI, 積まれるB/Uビット, Iが立つか)
+        let cases = [
+            (0x00, false, false, 0x30, true),
+            (0x08, false, false, 0x30, false),
+            (0xEA, true, false, 0x20, true),
+            (0xEA, false, true, 0x20, true),
+        ];
+
+        for &(opecode, irq, nmi, bits, i) in cases.iter() {
+            let (mut cpu, ppu) = cpu_and_ppu_with(&[opecode]);
+
+            cpu.p = P(0x00);
+            cpu.irq = irq;
+            ppu.borrow_mut().nmi = nmi;
+
+            step(&mut cpu);
+
+            // どの場合も最後に積まれるのはステータス
+            let pushed = cpu.bus.wram[0x0100 + cpu.s as usize + 1];
+
+            assert_eq!(pushed, bits, "{:#04X} irq {} nmi {}", opecode, irq, nmi);
+            assert_eq!(cpu.p.i(), i, "{:#04X} irq {} nmi {}", opecode, irq, nmi);
+        }
+    }
+
+    #[test]
+    fn las_loads_memory_and_stack_pointer_into_a_x_and_s() {
+        // (S, メモリ, 結果, Z, N)
+        let cases = [
+            (0xFD, 0x8F, 0x8D, false, true),
+            (0xFD, 0x02, 0x00, true, false),
+            (0x3C, 0xFF, 0x3C, false, false),
+        ];
+
+        for &(s, memory, result, z, n) in cases.iter() {
+            // LAS $02FF,Y
+            let mut cpu = cpu_with(&[0xBB, 0xFF, 0x02]);
+
+            cpu.s = s;
+            cpu.y = 0x01;
+            cpu.bus.wram[0x0300] = memory;
+
+            let cycles = step(&mut cpu);
+
+            assert_eq!(
+                (cpu.a, cpu.x, cpu.s),
+                (result, result, result),
+                "{:#04X}",
+                s
+            );
+            assert_eq!((cpu.p.z(), cpu.p.n()), (z, n), "{:#04X}", s);
+            // ページを跨ぐので1サイクル増える
+            assert_eq!(cycles, 5);
+        }
+    }
+
+    #[test]
+    fn soft_reset_sets_i_and_keeps_the_other_flags() {
+        let mut cpu = cpu_with(&[]);
+
+        // N, V, D, Z, C
+        cpu.p = P(0xCB);
+        cpu.a = 0x11;
+        cpu.x = 0x22;
+        cpu.y = 0x33;
+        cpu.s = 0xF0;
+        cpu.pc = 0x1234;
+
+        cpu.reset().unwrap();
+
+        assert_eq!(cpu.p.0, 0xCF);
+        assert!(cpu.p.d());
+        assert_eq!((cpu.a, cpu.x, cpu.y), (0x11, 0x22, 0x33));
+        assert_eq!(cpu.s, 0xED);
+        assert_eq!(cpu.pc, 0x8000);
+    }
+}
@@ -1,10 +1,8 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use log::debug;
-use num_derive::FromPrimitive;
-use num_traits::FromPrimitive;
-use std::collections::HashMap;
+use std::{collections::HashMap, str::FromStr};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, FromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum JoypadKey {
     A = 0,
     B,
@@ -16,12 +14,53 @@ pub enum JoypadKey {
     Right,
 }
 
+const KEYS: [JoypadKey; 8] = [
+    JoypadKey::A,
+    JoypadKey::B,
+    JoypadKey::Select,
+    JoypadKey::Start,
+    JoypadKey::Up,
+    JoypadKey::Down,
+    JoypadKey::Left,
+    JoypadKey::Right,
+];
+
 impl JoypadKey {
+    pub fn all() -> impl Iterator<Item = JoypadKey> {
+        KEYS.iter().copied()
+    }
+
     fn next(&self) -> Self {
-        FromPrimitive::from_u8(((*self as u8) + 1) % (JoypadKey::Right as u8 + 1)).unwrap()
+        let index = KEYS.iter().position(|key| key == self).unwrap_or(0);
+
+        KEYS[(index + 1) % KEYS.len()]
+    }
+}
+
+impl FromStr for JoypadKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "a" => Ok(JoypadKey::A),
+            "b" => Ok(JoypadKey::B),
+            "select" => Ok(JoypadKey::Select),
+            "start" => Ok(JoypadKey::Start),
+            "up" => Ok(JoypadKey::Up),
+            "down" => Ok(JoypadKey::Down),
+            "left" => Ok(JoypadKey::Left),
+            "right" => Ok(JoypadKey::Right),
+            _ => bail!("unknown joypad key {}", s),
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Player {
+    One,
+    Two,
+}
+
 pub struct Joypad {
     strobe: bool,
 
@@ -39,6 +78,11 @@ impl Joypad {
         }
     }
 
+    // シフトレジスタを進めずに次に読まれる値を返す
+    pub fn peek(&self) -> u8 {
+        *self.state.get(&self.cur_key).unwrap_or(&false) as u8
+    }
+
     pub fn read(&mut self) -> Result<u8> {
         let pressed = self.state.get(&self.cur_key).unwrap_or(&false);
 
@@ -52,7 +96,7 @@ impl Joypad {
     }
 
     pub fn write(&mut self, data: u8) -> Result<()> {
-        self.strobe = data >> 7 == 1;
+        self.strobe = data & 0x01 == 1;
 
         debug!("WRITE JOYPAD: {:#02X}", data);
 
@@ -74,4 +118,71 @@ impl Joypad {
 
         self.state.insert(key, false);
     }
+
+    pub fn set_pressed(&mut self, key: JoypadKey, pressed: bool) {
+        if pressed {
+            self.keydown(key);
+        } else {
+            self.keyup(key);
+        }
+    }
+
+    pub fn pressed(&self, key: JoypadKey) -> bool {
+        *self.state.get(&key).unwrap_or(&false)
+    }
+
+    pub fn set_state(&mut self, buttons: u8) {
+        debug!("SET STATE JOYPAD: {:#010b}", buttons);
+
+        for (i, key) in JoypadKey::all().enumerate() {
+            self.state.insert(key, (buttons >> i) & 1 == 1);
+        }
+    }
+
+    pub fn state_byte(&self) -> u8 {
+        JoypadKey::all()
+            .enumerate()
+            .filter(|(_, key)| *self.state.get(key).unwrap_or(&false))
+            .fold(0, |buttons, (i, _)| buttons | (1 << i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_parse_and_next_visits_each_once() {
+        assert_eq!("Start".parse::<JoypadKey>().unwrap(), JoypadKey::Start);
+        assert_eq!("left".parse::<JoypadKey>().unwrap(), JoypadKey::Left);
+        assert!("Turbo".parse::<JoypadKey>().is_err());
+
+        let mut key = JoypadKey::A;
+        let mut visited = Vec::new();
+
+        for _ in 0..8 {
+            visited.push(key);
+            key = key.next();
+        }
+
+        assert_eq!(key, JoypadKey::A);
+        assert_eq!(visited, JoypadKey::all().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn state_byte_reads_back_through_the_shift_register() {
+        let mut joypad = Joypad::new();
+
+        // A, Start, Left
+        joypad.set_state(0b0100_1001);
+
+        assert_eq!(joypad.state_byte(), 0b0100_1001);
+
+        joypad.write(0x80).unwrap();
+        joypad.write(0x00).unwrap();
+
+        let read = (0..8).fold(0, |buttons, i| buttons | (joypad.read().unwrap() << i));
+
+        assert_eq!(read, 0b0100_1001);
+    }
 }
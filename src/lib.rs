@@ -1,8 +1,16 @@
 pub mod apu;
+pub mod asm;
 pub mod bus;
 pub mod cpu;
+pub mod hash;
 pub mod joypad;
 pub mod mmc;
 pub mod nes;
 pub mod ppu;
+pub mod region;
 pub mod rom;
+pub mod scheduler;
+pub mod sink;
+pub mod tools;
+#[cfg(feature = "zip")]
+pub mod zip;
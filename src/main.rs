@@ -1,11 +1,21 @@
+use anyhow::{bail, Context, Result};
 use env_logger::{Builder, Target};
-use pixels::{Pixels, SurfaceTexture};
-use rnes::{joypad::JoypadKey, nes::Nes, rom::Rom};
+use pixels::{wgpu, PixelsBuilder, SurfaceTexture};
+use rnes::{
+    joypad::JoypadKey,
+    nes::{Accuracy, Battery, Nes},
+    rom::Rom,
+    sink::ChannelSink,
+    tools,
+};
 use std::{
+    collections::VecDeque,
     env,
-    fs::File,
-    io::BufReader,
-    sync::mpsc,
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc::{self, Receiver, SyncSender},
     thread,
     time::{Duration, Instant},
 };
@@ -20,10 +30,151 @@ use winit_input_helper::WinitInputHelper;
 enum NesThreadEvent {
     Player1Keydown(JoypadKey),
     Player1Keyup(JoypadKey),
+    Turbo(bool),
+    LoadRom(Rom, PathBuf),
+    Quit,
 }
 
 enum UiThreadEvent {
     Render(Vec<u8>),
+    Error(anyhow::Error),
+}
+
+impl From<Vec<u8>> for UiThreadEvent {
+    fn from(buffer: Vec<u8>) -> Self {
+        UiThreadEvent::Render(buffer)
+    }
+}
+
+struct Options {
+    rom_path: String,
+    frame_blend: bool,
+    dump_chr: Option<String>,
+    trace: Option<String>,
+    watchdog: Option<u64>,
+    crash_history: usize,
+    input_delay: usize,
+    turbo: usize,
+    accuracy: Accuracy,
+    battery: Battery,
+    lenient: bool,
+    scanlines: bool,
+    smooth: bool,
+}
+
+const TRACE_MAX_LINES: usize = 1_000_000;
+
+const DEFAULT_TURBO: usize = 3;
+
+fn parse_args() -> Result<Options> {
+    let args = env::args().collect::<Vec<String>>();
+
+    let mut rom_path = None;
+    let mut frame_blend = false;
+    let mut dump_chr = None;
+    let mut trace = None;
+    let mut watchdog = None;
+    let mut crash_history = 0;
+    let mut input_delay = 0;
+    let mut turbo = DEFAULT_TURBO;
+    let mut accuracy = Accuracy::default();
+    let mut battery = Battery::default();
+    let mut lenient = false;
+    let mut scanlines = false;
+    let mut smooth = false;
+
+    let mut iter = args.iter().skip(1);
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--blend" => frame_blend = true,
+            "--lenient" => lenient = true,
+            "--scanlines" => scanlines = true,
+            "--smooth" => smooth = true,
+            "--dump-chr" => {
+                dump_chr = Some(iter.next().context("--dump-chr requires a path")?.clone());
+            }
+            "--trace" => {
+                trace = Some(iter.next().context("--trace requires a path")?.clone());
+            }
+            "--watchdog" => {
+                let limit = iter.next().context("--watchdog requires a count")?;
+
+                watchdog = Some(
+                    limit
+                        .parse()
+                        .with_context(|| format!("invalid instruction count {}", limit))?,
+                );
+            }
+            "--crash-history" => {
+                let len = iter.next().context("--crash-history requires a count")?;
+
+                crash_history = len
+                    .parse()
+                    .with_context(|| format!("invalid instruction count {}", len))?;
+            }
+            "--input-delay" => {
+                let frames = iter
+                    .next()
+                    .context("--input-delay requires a frame count")?;
+
+                input_delay = frames
+                    .parse()
+                    .with_context(|| format!("invalid frame count {}", frames))?;
+            }
+            "--turbo" => {
+                let speed = iter.next().context("--turbo requires a speed")?;
+
+                turbo = speed
+                    .parse()
+                    .with_context(|| format!("invalid turbo speed {}", speed))?;
+
+                if turbo == 0 {
+                    bail!("--turbo must be at least 1");
+                }
+            }
+            "--accuracy" => {
+                accuracy = match iter.next().map(String::as_str) {
+                    Some("fast") => Accuracy::Fast,
+                    Some("balanced") => Accuracy::Balanced,
+                    Some("cycle") => Accuracy::Cycle,
+                    _ => bail!("--accuracy requires fast, balanced or cycle"),
+                };
+            }
+            "--battery" => {
+                battery = match iter.next().map(String::as_str) {
+                    Some("auto") => Battery::Auto,
+                    Some("on") => Battery::On,
+                    Some("off") => Battery::Off,
+                    _ => bail!("--battery requires auto, on or off"),
+                };
+            }
+            _ if arg.starts_with("--") => bail!("unknown option {}", arg),
+            _ => rom_path = Some(arg.clone()),
+        }
+    }
+
+    match rom_path {
+        Some(rom_path) => Ok(Options {
+            rom_path,
+            frame_blend,
+            dump_chr,
+            trace,
+            watchdog,
+            crash_history,
+            input_delay,
+            turbo,
+            accuracy,
+            battery,
+            lenient,
+            scanlines,
+            smooth,
+        }),
+        None => bail!(
+            "usage: {} [--blend] [--lenient] [--scanlines] [--smooth] [--dump-chr <png>] [--trace <file>] [--watchdog <n>] [--crash-history <n>] [--input-delay <frames>] [--turbo <n>] [--accuracy <level>] [--battery <mode>] <rom>",
+            args[0]
+        ),
+    }
 }
 
 fn main() {
@@ -32,6 +183,98 @@ fn main() {
 
     builder.init();
 
+    if let Err(e) = run() {
+        eprintln!("error: {:#}", e);
+        process::exit(1);
+    }
+}
+
+fn load_rom<P: AsRef<Path>>(path: P, lenient: bool) -> Result<Rom> {
+    let path = path.as_ref();
+
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+
+    let mut data = Vec::new();
+    reader
+        .read_to_end(&mut data)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    #[cfg(feature = "zip")]
+    let data = if rnes::zip::is_zip(&data) {
+        rnes::zip::read_nes(&data).with_context(|| format!("failed to unzip {}", path.display()))?
+    } else {
+        data
+    };
+
+    let rom = if lenient {
+        Rom::from_bytes_lenient(data)
+    } else {
+        Rom::from_bytes(data)
+    };
+
+    rom.with_context(|| format!("failed to load {}", path.display()))
+}
+
+// CRCで名前を付けるので、ROMの名前を変えてもセーブが見つかる
+fn save_path<P: AsRef<Path>>(rom_path: P, rom: &Rom) -> PathBuf {
+    let rom_path = rom_path.as_ref();
+
+    let path = rom_path.with_file_name(format!("{:08X}.sav", rom.crc32()));
+    let legacy = rom_path.with_extension("sav");
+
+    if !path.exists() && legacy.exists() {
+        legacy
+    } else {
+        path
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RendererBackend {
+    // Vulkan, Metal, DX12
+    Primary,
+    // OpenGL, DX11
+    Secondary,
+    // 全バックエンドから省電力のアダプタ (ソフトウェア実装しかない環境向け)
+    LowPower,
+}
+
+const RENDERER_BACKENDS: [RendererBackend; 3] = [
+    RendererBackend::Primary,
+    RendererBackend::Secondary,
+    RendererBackend::LowPower,
+];
+
+// GPUの初期化に失敗しても起動できるよう、バックエンドを順に試す
+fn select_renderer<T, F>(mut init: F) -> Result<(RendererBackend, T)>
+where
+    F: FnMut(RendererBackend) -> Result<T>,
+{
+    let mut errors = vec![];
+
+    for &backend in RENDERER_BACKENDS.iter() {
+        match init(backend) {
+            Ok(renderer) => return Ok((backend, renderer)),
+            Err(e) => errors.push(format!("{:?}: {:#}", backend, e)),
+        }
+    }
+
+    bail!(
+        "failed to initialize renderer (a Vulkan, Metal, DX12 or OpenGL capable GPU is required; {})",
+        errors.join(", ")
+    )
+}
+
+fn run() -> Result<()> {
+    let options = parse_args()?;
+
+    let rom = load_rom(&options.rom_path, options.lenient)?;
+
+    if let Some(path) = &options.dump_chr {
+        return tools::export_chr_png(&rom, path, tools::GRAYSCALE);
+    }
+
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
 
@@ -41,124 +284,304 @@ fn main() {
         .with_inner_size(size)
         .with_min_inner_size(size)
         .build(&event_loop)
-        .unwrap();
+        .context("failed to create window")?;
 
     let window_size = window.inner_size();
-    let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
-    let mut pixels = Pixels::new(256, 240, surface_texture).unwrap();
+    let (backend, mut pixels) = select_renderer(|backend| {
+        let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
+        let builder = PixelsBuilder::new(
+            tools::UPSCALED_WIDTH,
+            tools::UPSCALED_HEIGHT,
+            surface_texture,
+        );
+
+        let builder = match backend {
+            RendererBackend::Primary => builder,
+            RendererBackend::Secondary => builder.wgpu_backend(wgpu::BackendBit::SECONDARY),
+            RendererBackend::LowPower => builder
+                .wgpu_backend(wgpu::BackendBit::all())
+                .request_adapter_options(wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::LowPower,
+                    compatible_surface: None,
+                }),
+        };
+
+        Ok(builder.build()?)
+    })?;
+
+    if backend != RendererBackend::Primary {
+        eprintln!("warning: falling back to the {:?} renderer", backend);
+    }
 
     let (nes_sender, nes_receiver) = mpsc::channel::<NesThreadEvent>();
     let (ui_sender, ui_receiver) = mpsc::sync_channel::<UiThreadEvent>(1);
 
-    let args = env::args().collect::<Vec<String>>();
+    let lenient = options.lenient;
+    let mut scanlines = options.scanlines;
+    let mut smooth = options.smooth;
 
-    let mut reader = BufReader::new(File::open(args[1].clone()).unwrap());
-    let rom = Rom::new(&mut reader).unwrap();
+    thread::spawn(move || {
+        if let Err(e) = run_nes(rom, &options, nes_receiver, &ui_sender) {
+            let _ = ui_sender.send(UiThreadEvent::Error(e));
+        }
+    });
 
-    {
-        thread::spawn(move || {
-            let mut nes = Nes::new(rom).unwrap();
+    let mut time = Instant::now();
+    let mut failed = false;
 
-            nes.reset().unwrap();
+    event_loop.run(move |event, _, control_flow| {
+        match event {
+            Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } => {
+                *control_flow = ControlFlow::Exit;
+            }
+            Event::WindowEvent {
+                event: WindowEvent::DroppedFile(ref path),
+                ..
+            } => match load_rom(path, lenient) {
+                Ok(rom) => {
+                    let save_path = save_path(path, &rom);
+                    let _ = nes_sender.send(NesThreadEvent::LoadRom(rom, save_path));
+                }
+                Err(e) => eprintln!("error: {:#}", e),
+            },
+            Event::RedrawRequested(_) => {
+                if let Err(e) = pixels.render() {
+                    eprintln!("error: failed to render: {}", e);
+                    failed = true;
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+            Event::MainEventsCleared => match ui_receiver.recv() {
+                Ok(UiThreadEvent::Render(buffer)) => {
+                    tools::upscale_frame(&buffer, pixels.get_frame(), scanlines, smooth);
+                }
+                Ok(UiThreadEvent::Error(e)) => {
+                    eprintln!("error: {:#}", e);
+                    failed = true;
+                    *control_flow = ControlFlow::Exit;
+                }
+                Err(_) => {
+                    *control_flow = ControlFlow::Exit;
+                }
+            },
+            Event::LoopDestroyed => {
+                let _ = nes_sender.send(NesThreadEvent::Quit);
 
-            loop {
-                let time = Instant::now();
+                // セーブを書き出させるためにNESスレッドの終了を待つ
+                while let Ok(event) = ui_receiver.recv() {
+                    if let UiThreadEvent::Error(e) = event {
+                        eprintln!("error: {:#}", e);
+                        failed = true;
+                    }
+                }
 
-                for _ in 0..89342 {
-                    nes.tick().unwrap();
+                if failed {
+                    process::exit(1);
                 }
+            }
+            _ => {}
+        }
 
-                match nes_receiver.try_recv() {
-                    Ok(event) => match event {
-                        NesThreadEvent::Player1Keydown(key) => nes.player1_keydown(key),
-                        NesThreadEvent::Player1Keyup(key) => nes.player1_keyup(key),
-                    },
-                    _ => {}
-                };
+        match *control_flow {
+            ControlFlow::Exit => {}
+            _ => {
+                if time.elapsed() >= Duration::from_millis(1000 / 60) {
+                    time = Instant::now();
 
-                let buffer = nes.render().unwrap();
+                    window.request_redraw();
+                }
 
-                let _ = ui_sender.try_send(UiThreadEvent::Render(buffer));
+                if input.update(&event) {
+                    if input.key_pressed(VirtualKeyCode::Escape) || input.quit() {
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
 
-                let elapsed = time.elapsed().as_millis();
+                    if input.key_pressed(VirtualKeyCode::F1) {
+                        scanlines = !scanlines;
+                    }
+                    if input.key_pressed(VirtualKeyCode::F2) {
+                        smooth = !smooth;
+                    }
+
+                    // Tabを押している間だけ早送りする
+                    if input.key_pressed(VirtualKeyCode::Tab) {
+                        let _ = nes_sender.send(NesThreadEvent::Turbo(true));
+                    }
+                    if input.key_released(VirtualKeyCode::Tab) {
+                        let _ = nes_sender.send(NesThreadEvent::Turbo(false));
+                    }
 
-                let (wait, c) = ((1000 / 60) as u128).overflowing_sub(elapsed);
+                    for (input_key, joypad_key) in [
+                        (VirtualKeyCode::Z, JoypadKey::A),
+                        (VirtualKeyCode::X, JoypadKey::B),
+                        (VirtualKeyCode::C, JoypadKey::Select),
+                        (VirtualKeyCode::V, JoypadKey::Start),
+                        (VirtualKeyCode::Up, JoypadKey::Up),
+                        (VirtualKeyCode::Down, JoypadKey::Down),
+                        (VirtualKeyCode::Left, JoypadKey::Left),
+                        (VirtualKeyCode::Right, JoypadKey::Right),
+                    ]
+                    .iter()
+                    {
+                        if input.key_pressed(*input_key) {
+                            let _ = nes_sender.send(NesThreadEvent::Player1Keydown(*joypad_key));
+                        }
+                        if input.key_released(*input_key) {
+                            let _ = nes_sender.send(NesThreadEvent::Player1Keyup(*joypad_key));
+                        }
+                    }
 
-                if !c {
-                    thread::sleep(Duration::from_millis(wait as u64));
+                    if let Some(size) = input.window_resized() {
+                        pixels.resize(size.width, size.height);
+                    }
                 }
+
+                *control_flow = ControlFlow::Poll;
             }
-        });
+        }
+    });
+}
+
+// セーブファイルの隣にクラッシュレポートを書き出す
+fn write_crash_report(nes: &Nes, error: anyhow::Error, save_path: &Path) -> anyhow::Error {
+    let path = save_path.with_extension("crash.txt");
+    let report = nes.crash_report(&error);
+
+    match fs::write(&path, report.to_string()) {
+        Ok(()) => error.context(format!("crash report written to {}", path.display())),
+        Err(e) => error.context(format!(
+            "failed to write crash report {}: {}",
+            path.display(),
+            e
+        )),
     }
+}
+
+fn run_nes(
+    rom: Rom,
+    options: &Options,
+    nes_receiver: Receiver<NesThreadEvent>,
+    ui_sender: &SyncSender<UiThreadEvent>,
+) -> Result<()> {
+    let mut save_path = save_path(&options.rom_path, &rom);
+
+    let mut nes = Nes::new_with_options(rom, options.accuracy)?;
+
+    nes.set_frame_blend(options.frame_blend);
+    nes.set_battery(options.battery);
+    nes.load_battery(&save_path)?;
 
-    {
-        let mut time = Instant::now();
+    nes.set_watchdog(options.watchdog);
+    nes.set_crash_history(options.crash_history);
+    nes.set_frame_sink(Some(Box::new(ChannelSink::new(ui_sender.clone()))));
 
-        event_loop.run(move |event, _, control_flow| {
+    if let Some(path) = &options.trace {
+        nes.set_trace(Some(Box::new(tools::TraceWriter::new(
+            path,
+            TRACE_MAX_LINES,
+        )?)));
+    }
+
+    nes.power_on()?;
+
+    let mut frame = Duration::from_secs_f64(1.0 / nes.region().frame_rate());
+
+    let mut buttons = 0;
+    let mut inputs = VecDeque::new();
+    let mut turbo = false;
+
+    loop {
+        let time = Instant::now();
+
+        // 入力はフレームの頭でまとめて反映するので、届いたタイミングで遅れが変わらない
+        while let Ok(event) = nes_receiver.try_recv() {
             match event {
-                Event::WindowEvent {
-                    event: WindowEvent::CloseRequested,
-                    ..
-                } => {
-                    *control_flow = ControlFlow::Exit;
-                }
-                Event::RedrawRequested(_) => {
-                    pixels.render().unwrap();
+                NesThreadEvent::Player1Keydown(key) => buttons |= 1 << key as u8,
+                NesThreadEvent::Player1Keyup(key) => buttons &= !(1 << key as u8),
+                NesThreadEvent::Turbo(held) => turbo = held,
+                NesThreadEvent::LoadRom(rom, path) => {
+                    nes.load_rom(rom)?;
+                    nes.load_battery(&path)?;
+
+                    save_path = path;
+
+                    frame = Duration::from_secs_f64(1.0 / nes.region().frame_rate());
                 }
-                Event::MainEventsCleared => match ui_receiver.recv() {
-                    Ok(event) => match event {
-                        UiThreadEvent::Render(buffer) => {
-                            pixels.get_frame().copy_from_slice(buffer.as_slice());
-                        }
-                    },
-                    _ => {}
-                },
-                _ => {}
+                NesThreadEvent::Quit => return nes.shutdown(),
             }
+        }
 
-            match *control_flow {
-                ControlFlow::Exit => {}
-                _ => {
-                    if time.elapsed() >= Duration::from_millis(1000 / 60) {
-                        time = Instant::now();
+        // --input-delayのフレーム数だけ前の入力を使う
+        inputs.push_back(buttons);
 
-                        window.request_redraw();
-                    }
+        while inputs.len() > options.input_delay {
+            if let Some(buttons) = inputs.pop_front() {
+                nes.set_player1_state(buttons);
+            }
+        }
 
-                    if input.update(&event) {
-                        if input.key_pressed(VirtualKeyCode::Escape) || input.quit() {
-                            *control_flow = ControlFlow::Exit;
-                            return;
-                        }
+        // 早送り中は表示1フレームの間に--turboのフレーム数だけ進める
+        let frames = if turbo { options.turbo } else { 1 };
 
-                        for (input_key, joypad_key) in [
-                            (VirtualKeyCode::Z, JoypadKey::A),
-                            (VirtualKeyCode::X, JoypadKey::B),
-                            (VirtualKeyCode::C, JoypadKey::Select),
-                            (VirtualKeyCode::V, JoypadKey::Start),
-                            (VirtualKeyCode::Up, JoypadKey::Up),
-                            (VirtualKeyCode::Down, JoypadKey::Down),
-                            (VirtualKeyCode::Left, JoypadKey::Left),
-                            (VirtualKeyCode::Right, JoypadKey::Right),
-                        ]
-                        .iter()
-                        {
-                            if input.key_pressed(*input_key) {
-                                nes_sender.send(NesThreadEvent::Player1Keydown(*joypad_key));
-                            }
-                            if input.key_released(*input_key) {
-                                nes_sender.send(NesThreadEvent::Player1Keyup(*joypad_key));
-                            }
-                        }
+        for _ in 0..frames {
+            if let Err(e) = nes.run_frame() {
+                return Err(write_crash_report(&nes, e, &save_path));
+            }
+        }
 
-                        if let Some(size) = input.window_resized() {
-                            pixels.resize(size.width, size.height);
-                        }
-                    }
+        if nes.take_stalled() {
+            eprintln!("warning: no $2002 read or NMI for a while, the game may be stuck");
+        }
 
-                    *control_flow = ControlFlow::Poll;
-                }
+        if !nes.flush_frame_sink() {
+            return Ok(());
+        }
+
+        if let Some(wait) = frame.checked_sub(time.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renderer_falls_back_past_failing_backends() {
+        let mut tried = vec![];
+
+        let (backend, renderer) = select_renderer(|backend| {
+            tried.push(backend);
+
+            match backend {
+                RendererBackend::Primary => bail!("no adapter"),
+                _ => Ok("renderer"),
             }
-        });
+        })
+        .unwrap();
+
+        assert_eq!(backend, RendererBackend::Secondary);
+        assert_eq!(renderer, "renderer");
+        assert_eq!(
+            tried,
+            [RendererBackend::Primary, RendererBackend::Secondary]
+        );
+
+        // 最初に成功したらそれ以降は試さない
+        let (backend, _) = select_renderer(|_| Ok(())).unwrap();
+
+        assert_eq!(backend, RendererBackend::Primary);
+
+        // 全部失敗したら、それぞれの理由を並べて返す
+        let e = select_renderer::<(), _>(|backend| bail!("{:?} failed", backend)).unwrap_err();
+        let message = e.to_string();
+
+        assert!(message.starts_with("failed to initialize renderer"));
+        assert!(message.contains("LowPower: LowPower failed"), "{}", message);
     }
 }
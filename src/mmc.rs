@@ -3,35 +3,71 @@ use bitfield::bitfield;
 use bitmatch::bitmatch;
 use log::debug;
 
-use crate::rom::{MapperType, Rom};
+use crate::rom::{MapperType, Mirroring, Rom, SubmapperType};
 
 pub trait Mmc {
     fn read_cpu(&self, addr: u16) -> Result<u8>;
     fn write_cpu(&mut self, addr: u16, data: u8) -> Result<()>;
     fn read_ppu(&self, addr: u16) -> Result<u8>;
     fn write_ppu(&mut self, addr: u16, data: u8) -> Result<()>;
+    fn mirroring(&self) -> Mirroring;
+
+    fn tick(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // MMC3などはIRQを出し、CPUが応答のレジスタに書き込むまで出し続ける
+    fn irq(&self) -> bool {
+        false
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
 }
 
 pub fn new_mmc(rom: Rom) -> Result<Box<dyn Mmc>> {
     match rom.mapper {
         MapperType::Mmc0 => Ok(Box::new(Mmc0::new(rom))),
         MapperType::Mmc1 => Ok(Box::new(Mmc1::new(rom))),
-        _ => bail!("unknown mapper {:?}", rom.mapper),
+        MapperType::Uxrom => Ok(Box::new(Uxrom::new(rom))),
+        MapperType::Cnrom => Ok(Box::new(Cnrom::new(rom))),
+        MapperType::Gxrom => Ok(Box::new(Gxrom::new(rom))),
+        _ => bail!("unsupported mapper {:?}", rom.mapper),
     }
 }
 
+fn bank_offset(bank: usize, bank_size: usize, total_size: usize) -> usize {
+    let banks = (total_size / bank_size).max(1);
+
+    (bank % banks) * bank_size
+}
+
 pub struct Mmc0 {
     rom: Rom,
 
-    prg_ram: [u8; 0x2000],
+    prg_ram: Vec<u8>,
 }
 
 impl Mmc0 {
     pub fn new(rom: Rom) -> Self {
-        Self {
-            rom,
-            prg_ram: [0; 0x2000],
-        }
+        let prg_ram = vec![0; rom.total_prg_ram_size()];
+
+        Self { rom, prg_ram }
+    }
+}
+
+fn read_prg_ram(prg_ram: &[u8], addr: u16) -> u8 {
+    prg_ram.get((addr - 0x6000) as usize).copied().unwrap_or(0)
+}
+
+fn write_prg_ram(prg_ram: &mut [u8], addr: u16, data: u8) {
+    if let Some(byte) = prg_ram.get_mut((addr - 0x6000) as usize) {
+        *byte = data;
     }
 }
 
@@ -44,7 +80,7 @@ impl Mmc for Mmc0 {
         };
 
         match addr {
-            0x6000..=0x7FFF => Ok(self.prg_ram[(addr - 0x6000) as usize]),
+            0x6000..=0x7FFF => Ok(read_prg_ram(&self.prg_ram, addr)),
             0x8000..=0xFFFF => Ok(self.rom.prg()[(addr - 0x8000) as usize]),
             _ => Ok(0),
         }
@@ -53,7 +89,7 @@ impl Mmc for Mmc0 {
     fn write_cpu(&mut self, addr: u16, data: u8) -> Result<()> {
         match addr {
             0x6000..=0x7FFF => {
-                self.prg_ram[(addr - 0x6000) as usize] = data;
+                write_prg_ram(&mut self.prg_ram, addr, data);
 
                 Ok(())
             }
@@ -71,6 +107,26 @@ impl Mmc for Mmc0 {
     fn write_ppu(&mut self, addr: u16, data: u8) -> Result<()> {
         Ok(())
     }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.mirroring()
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        if self.prg_ram.is_empty() {
+            None
+        } else {
+            Some(&self.prg_ram)
+        }
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.prg_ram.is_empty() {
+            None
+        } else {
+            Some(&mut self.prg_ram)
+        }
+    }
 }
 
 bitfield! {
@@ -89,11 +145,15 @@ bitfield! {
 pub struct Mmc1 {
     rom: Rom,
 
-    prg_ram: [u8; 0x2000],
+    prg_ram: Vec<u8>,
+    fixed_prg: bool,
 
     latch: u8,
     counter: usize,
 
+    cycles: u64,
+    last_write_cycles: Option<u64>,
+
     control: Mmc1Control,
     chr_bank_0: u8,
     chr_bank_1: u8,
@@ -102,15 +162,22 @@ pub struct Mmc1 {
 
 impl Mmc1 {
     pub fn new(rom: Rom) -> Self {
+        let prg_ram = vec![0; rom.total_prg_ram_size()];
+        let fixed_prg = rom.submapper == SubmapperType::Mmc1FixedPrg;
+
         Self {
             rom,
 
-            prg_ram: [0; 0x2000],
+            prg_ram,
+            fixed_prg,
 
             latch: 0,
             counter: 0,
 
-            control: Mmc1Control(0),
+            cycles: 0,
+            last_write_cycles: None,
+
+            control: Mmc1Control(0x0C),
             chr_bank_0: 0,
             chr_bank_1: 0,
             prg_bank: Mmc1PrgBank(0),
@@ -142,11 +209,24 @@ impl Mmc1 {
 
     #[bitmatch]
     fn write_load(&mut self, addr: u16, data: u8) {
+        // RMW命令のダミー書き込みと本書き込みは同じサイクルで届くので、後者は無視する
+        if self.last_write_cycles == Some(self.cycles) {
+            debug!(
+                "IGNORE MMC1 CONSECUTIVE WRITE: {:#04X} = {:#02X}",
+                addr, data
+            );
+
+            return;
+        }
+
+        self.last_write_cycles = Some(self.cycles);
+
         #[bitmatch]
         let "r??????d" = data;
 
         if r > 0 {
             self.reset_load();
+            self.control = Mmc1Control(self.control.0 | 0x0C);
 
             return;
         }
@@ -160,7 +240,7 @@ impl Mmc1 {
         self.counter += 1;
 
         if self.counter == 5 {
-            self.copy_register(addr & 0b01100000);
+            self.copy_register(addr & 0x6000);
             self.reset_load();
         }
     }
@@ -194,8 +274,8 @@ impl Mmc1 {
                 self.rom.prg()[(bank * 0x4000 + offset) as usize]
             }
             0xC000..=0xFFFF => {
-                let neg_offset = 0xFFFF - addr;
-                self.rom.prg()[self.rom.prg_size - neg_offset as usize]
+                let offset = (addr - 0xC000) as usize;
+                self.rom.prg()[self.rom.prg_size - 0x4000 + offset]
             }
             _ => {
                 debug!("index out of range");
@@ -205,6 +285,11 @@ impl Mmc1 {
     }
 
     fn read_prg_bank(&self, addr: u16) -> u8 {
+        if self.fixed_prg {
+            let offset = (addr - 0x8000) as usize;
+            return self.rom.prg()[offset % self.rom.prg_size.max(1)];
+        }
+
         match self.control.prg_rom_bank() {
             0 | 1 => self.read_prg_bank_32kb(addr),
             2 => self.read_prg_bank_first_fixed(addr),
@@ -217,22 +302,22 @@ impl Mmc1 {
     }
 
     fn read_chr_bank_8kb(&self, addr: u16) -> u8 {
-        let bank = (self.chr_bank_0 & 0b1110) as u16 >> 1;
-        let offset = addr;
-        self.rom.chr()[(bank * 0x2000 + offset) as usize]
+        let bank = (self.chr_bank_0 & 0b1110) as usize >> 1;
+        let offset = addr as usize;
+        self.rom.chr()[bank_offset(bank, 0x2000, self.rom.chr_size) + offset]
     }
 
     fn read_chr_bank_4kb(&self, addr: u16) -> u8 {
         match addr {
             0x0000..=0x0FFF => {
-                let bank = self.chr_bank_0 as u16;
-                let offset = addr;
-                self.rom.chr()[(bank * 0x1000 + offset) as usize]
+                let bank = self.chr_bank_0 as usize;
+                let offset = addr as usize;
+                self.rom.chr()[bank_offset(bank, 0x1000, self.rom.chr_size) + offset]
             }
             0x1000..=0x1FFF => {
-                let bank = self.chr_bank_1 as u16;
-                let offset = addr - 0x1000;
-                self.rom.chr()[(bank * 0x1000 + offset) as usize]
+                let bank = self.chr_bank_1 as usize;
+                let offset = (addr - 0x1000) as usize;
+                self.rom.chr()[bank_offset(bank, 0x1000, self.rom.chr_size) + offset]
             }
             _ => {
                 debug!("index out of range");
@@ -252,7 +337,7 @@ impl Mmc1 {
 impl Mmc for Mmc1 {
     fn read_cpu(&self, addr: u16) -> Result<u8> {
         match addr {
-            0x6000..=0x7FFF => Ok(self.prg_ram[(addr - 0x6000) as usize]),
+            0x6000..=0x7FFF => Ok(read_prg_ram(&self.prg_ram, addr)),
             0x8000..=0xFFFF => Ok(self.read_prg_bank(addr)),
             _ => Ok(0),
         }
@@ -261,7 +346,7 @@ impl Mmc for Mmc1 {
     fn write_cpu(&mut self, addr: u16, data: u8) -> Result<()> {
         match addr {
             0x6000..=0x7FFF => {
-                self.prg_ram[(addr - 0x6000) as usize] = data;
+                write_prg_ram(&mut self.prg_ram, addr, data);
 
                 Ok(())
             }
@@ -281,4 +366,409 @@ impl Mmc for Mmc1 {
     fn write_ppu(&mut self, addr: u16, data: u8) -> Result<()> {
         Ok(())
     }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control.mirror() {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        self.cycles = self.cycles.wrapping_add(1);
+
+        Ok(())
+    }
+
+    fn prg_ram(&self) -> Option<&[u8]> {
+        if self.prg_ram.is_empty() {
+            None
+        } else {
+            Some(&self.prg_ram)
+        }
+    }
+
+    fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if self.prg_ram.is_empty() {
+            None
+        } else {
+            Some(&mut self.prg_ram)
+        }
+    }
+}
+
+pub struct Uxrom {
+    rom: Rom,
+
+    chr_ram: [u8; 0x2000],
+    bus_conflicts: bool,
+
+    prg_bank: u8,
+}
+
+impl Uxrom {
+    pub fn new(rom: Rom) -> Self {
+        let bus_conflicts = rom.submapper == SubmapperType::UxromBusConflicts;
+
+        Self {
+            rom,
+
+            chr_ram: [0; 0x2000],
+            bus_conflicts,
+
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Mmc for Uxrom {
+    fn read_cpu(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x8000..=0xBFFF => {
+                let offset = bank_offset(self.prg_bank as usize, 0x4000, self.rom.prg_size);
+                Ok(self.rom.prg()[offset + (addr - 0x8000) as usize])
+            }
+            0xC000..=0xFFFF => {
+                let offset = self.rom.prg_size.saturating_sub(0x4000);
+                Ok(self.rom.prg()[offset + (addr - 0xC000) as usize])
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn write_cpu(&mut self, addr: u16, data: u8) -> Result<()> {
+        if let 0x8000..=0xFFFF = addr {
+            // バスコンフリクトがあると、書き込んだ値とROMの値のANDになる
+            self.prg_bank = if self.bus_conflicts {
+                data & self.read_cpu(addr)?
+            } else {
+                data
+            };
+
+            debug!("UXROM PRG BANK: {:#02X}", self.prg_bank);
+        }
+
+        Ok(())
+    }
+
+    fn read_ppu(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF if self.rom.chr_size == 0 => Ok(self.chr_ram[addr as usize]),
+            0x0000..=0x1FFF => Ok(self.rom.chr()[addr as usize]),
+            _ => Ok(0),
+        }
+    }
+
+    fn write_ppu(&mut self, addr: u16, data: u8) -> Result<()> {
+        if let 0x0000..=0x1FFF = addr {
+            if self.rom.chr_size == 0 {
+                self.chr_ram[addr as usize] = data;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.mirroring()
+    }
+}
+
+pub struct Cnrom {
+    rom: Rom,
+
+    chr_bank: u8,
+}
+
+impl Cnrom {
+    pub fn new(rom: Rom) -> Self {
+        Self { rom, chr_bank: 0 }
+    }
+}
+
+impl Mmc for Cnrom {
+    fn read_cpu(&self, addr: u16) -> Result<u8> {
+        let addr = if self.rom.prg_size <= 0x4000 && addr >= 0xC000 {
+            addr - 0x4000
+        } else {
+            addr
+        };
+
+        match addr {
+            0x8000..=0xFFFF => Ok(self.rom.prg()[(addr - 0x8000) as usize]),
+            _ => Ok(0),
+        }
+    }
+
+    fn write_cpu(&mut self, addr: u16, data: u8) -> Result<()> {
+        if let 0x8000..=0xFFFF = addr {
+            self.chr_bank = data;
+
+            debug!("CNROM CHR BANK: {:#02X}", data);
+        }
+
+        Ok(())
+    }
+
+    fn read_ppu(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let offset = bank_offset(self.chr_bank as usize, 0x2000, self.rom.chr_size);
+                Ok(self.rom.chr()[offset + addr as usize])
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn write_ppu(&mut self, _addr: u16, _data: u8) -> Result<()> {
+        Ok(())
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.mirroring()
+    }
+}
+
+bitfield! {
+    struct GxromBank(u8);
+    prg_bank, _: 5, 4;
+    chr_bank, _: 1, 0;
+}
+
+pub struct Gxrom {
+    rom: Rom,
+
+    bank: GxromBank,
+}
+
+impl Gxrom {
+    pub fn new(rom: Rom) -> Self {
+        Self {
+            rom,
+            bank: GxromBank(0),
+        }
+    }
+}
+
+impl Mmc for Gxrom {
+    fn read_cpu(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x8000..=0xFFFF => {
+                let offset = bank_offset(self.bank.prg_bank() as usize, 0x8000, self.rom.prg_size);
+                Ok(self.rom.prg()[(offset + (addr - 0x8000) as usize) % self.rom.prg_size])
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn write_cpu(&mut self, addr: u16, data: u8) -> Result<()> {
+        if let 0x8000..=0xFFFF = addr {
+            // バスコンフリクトがあるので、ROMの値とのANDになる
+            self.bank = GxromBank(data & self.read_cpu(addr)?);
+
+            debug!("GXROM BANK: {:#02X}", self.bank.0);
+        }
+
+        Ok(())
+    }
+
+    fn read_ppu(&self, addr: u16) -> Result<u8> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let offset = bank_offset(self.bank.chr_bank() as usize, 0x2000, self.rom.chr_size);
+                Ok(self.rom.chr()[offset + addr as usize])
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn write_ppu(&mut self, _addr: u16, _data: u8) -> Result<()> {
+        Ok(())
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.rom.mirroring()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nes::Nes;
+
+    // バンクiの中身が全部iになるROMとCHR
+    fn banked(size: usize, bank_size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i / bank_size) as u8).collect()
+    }
+
+    fn mmc_with(mapper: u8, prg: Vec<u8>, chr: Vec<u8>) -> Box<dyn Mmc> {
+        new_mmc(Rom::from_parts(prg, chr, mapper, Mirroring::Horizontal).unwrap()).unwrap()
+    }
+
+    // INC $9FF0を5回。ROMの$9FF0は$03なので、ダミー書き込みは$03、本書き込みは$04になる
+    fn inc_control_register() -> Nes {
+        let mut prg = vec![0xEA; 0x8000];
+
+        for i in 0..5 {
+            prg[i * 3..(i + 1) * 3].copy_from_slice(&[0xEE, 0xF0, 0x9F]);
+        }
+
+        prg[0x1FF0] = 0x03;
+        prg[0x7FFC] = 0x00;
+        prg[0x7FFD] = 0x80;
+
+        Nes::from_parts(prg, vec![0; 0x2000], 1, Mirroring::Horizontal).unwrap()
+    }
+
+    #[test]
+    fn rmw_registers_only_the_dummy_write() {
+        let mut nes = inc_control_register();
+
+        assert_eq!(nes.mirroring(), Mirroring::SingleScreenLower);
+
+        for _ in 0..4 {
+            nes.step_instruction().unwrap();
+        }
+
+        // 4回分のビットがまだシフトレジスタに残っている
+        assert_eq!(nes.mirroring(), Mirroring::SingleScreenLower);
+
+        nes.step_instruction().unwrap();
+
+        // ダミー書き込みの1だけが5回入ってCONTROLが$1Fになる
+        assert_eq!(nes.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn cnrom_chr_bank_wraps_to_the_present_banks() {
+        let mut mmc = mmc_with(3, vec![0; 0x8000], banked(0x4000, 0x2000));
+
+        mmc.write_cpu(0x8000, 1).unwrap();
+        assert_eq!(mmc.read_ppu(0x1FFF).unwrap(), 1);
+
+        // 2バンクしかないので、バンク5はバンク1になる
+        mmc.write_cpu(0x8000, 5).unwrap();
+        assert_eq!(mmc.read_ppu(0x0000).unwrap(), 1);
+        assert_eq!(mmc.read_ppu(0x1FFF).unwrap(), 1);
+
+        mmc.write_cpu(0x8000, 0xFE).unwrap();
+        assert_eq!(mmc.read_ppu(0x1FFF).unwrap(), 0);
+    }
+
+    #[test]
+    fn fresh_mmc1_reads_the_reset_vector_from_the_last_bank() {
+        let mut prg = banked(0x10000, 0x4000);
+
+        prg[0xFFFC] = 0x00;
+        prg[0xFFFD] = 0xC0;
+
+        let mmc = mmc_with(1, prg, vec![0; 0x2000]);
+
+        // CONTROLは$0Cで始まるので、$C000〜は最後のバンクに固定されている
+        assert_eq!(mmc.read_cpu(0xFFFC).unwrap(), 0x00);
+        assert_eq!(mmc.read_cpu(0xFFFD).unwrap(), 0xC0);
+        assert_eq!(mmc.read_cpu(0xC000).unwrap(), 3);
+        assert_eq!(mmc.read_cpu(0x8000).unwrap(), 0);
+    }
+
+    #[test]
+    fn uxrom_submapper_selects_bus_conflicts() {
+        // (サブマッパー, $8000に1を書いた後のバンク)
+        let cases = [
+            (SubmapperType::UxromNoBusConflicts, 1),
+            (SubmapperType::UxromBusConflicts, 0),
+        ];
+
+        for &(submapper, bank) in cases.iter() {
+            let mut rom =
+                Rom::from_parts(banked(0x8000, 0x4000), vec![], 2, Mirroring::Vertical).unwrap();
+
+            rom.submapper = submapper;
+
+            let mut uxrom = Uxrom::new(rom);
+
+            // バンク0の$8000は$00なので、バスコンフリクトがあると1 AND 0になる
+            uxrom.write_cpu(0x8000, 1).unwrap();
+
+            assert_eq!(uxrom.read_cpu(0x8000).unwrap(), bank, "{:?}", submapper);
+        }
+    }
+
+    #[test]
+    fn prg_ram_is_sized_from_the_header() {
+        // NES 2.0で、PRG-RAMは64 << 5 = 2KB
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0x00, 0x08, 0, 0, 0x05];
+
+        data.resize(0x10 + 0x4000 + 0x2000, 0);
+
+        let rom = Rom::from_bytes(data).unwrap();
+
+        assert_eq!(rom.total_prg_ram_size(), 0x0800);
+
+        let mut mmc = new_mmc(rom).unwrap();
+
+        mmc.write_cpu(0x67FF, 0x12).unwrap();
+        mmc.write_cpu(0x6800, 0x34).unwrap();
+
+        assert_eq!(mmc.read_cpu(0x67FF).unwrap(), 0x12);
+        assert_eq!(mmc.read_cpu(0x6800).unwrap(), 0x00);
+        assert_eq!(mmc.prg_ram().map(|ram| ram.len()), Some(0x0800));
+    }
+
+    #[test]
+    fn gxrom_bank_byte_switches_prg_and_chr() {
+        let mut prg = banked(0x20000, 0x8000);
+
+        // バスコンフリクトで消えないように、書き込み先は$FFにしておく
+        for bank in prg.chunks_mut(0x8000) {
+            bank[0x7FFF] = 0xFF;
+        }
+
+        let mut mmc = mmc_with(66, prg, banked(0x8000, 0x2000));
+
+        mmc.write_cpu(0xFFFF, 0x21).unwrap();
+
+        assert_eq!(mmc.read_cpu(0x8000).unwrap(), 2);
+        assert_eq!(mmc.read_ppu(0x0000).unwrap(), 1);
+
+        mmc.write_cpu(0xFFFF, 0x13).unwrap();
+
+        assert_eq!(mmc.read_cpu(0x8000).unwrap(), 1);
+        assert_eq!(mmc.read_ppu(0x1FFF).unwrap(), 3);
+    }
+
+    #[test]
+    fn nes2_submapper_selects_the_mmc1_variant() {
+        // MMC1、PRG 64KB。バイト8の上位4ビットがサブマッパー5
+        let header = |flags7: u8| {
+            let mut data = vec![b'N', b'E', b'S', 0x1A, 4, 1, 0x10, flags7, 0x50];
+
+            data.resize(0x10, 0);
+            data.extend(banked(0x10000, 0x4000));
+            data.extend(vec![0; 0x2000]);
+
+            Rom::from_bytes(data).unwrap()
+        };
+
+        // (フラグ7, サブマッパー, $C000に見えるバンク)
+        // 固定の32KBなら2番目のバンク、普通のMMC1なら最後のバンクになる
+        let cases = [
+            (0x08, SubmapperType::Mmc1FixedPrg, 1),
+            (0x00, SubmapperType::Default, 3),
+        ];
+
+        for &(flags7, submapper, bank) in cases.iter() {
+            let rom = header(flags7);
+
+            assert!(matches!(rom.mapper, MapperType::Mmc1), "{:#04X}", flags7);
+            assert_eq!(rom.submapper, submapper, "{:#04X}", flags7);
+
+            let mmc = new_mmc(rom).unwrap();
+
+            assert_eq!(mmc.read_cpu(0x8000).unwrap(), 0, "{:#04X}", flags7);
+            assert_eq!(mmc.read_cpu(0xC000).unwrap(), bank, "{:#04X}", flags7);
+        }
+    }
 }
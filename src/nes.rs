@@ -1,35 +1,169 @@
-use std::{cell::RefCell, rc::Rc, sync::mpsc::channel};
+use std::{
+    cell::RefCell,
+    fmt::{self, Display, Formatter},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use log::error;
 
 use crate::{
-    apu::Apu,
-    bus::{CpuBus, CpuBusEvent, PpuBus, PpuBusEvent},
+    apu::{Apu, ApuState},
+    bus::{CpuBus, PpuBus},
     cpu::Cpu,
-    joypad::{Joypad, JoypadKey},
-    mmc::new_mmc,
-    ppu::Ppu,
-    rom::Rom,
+    joypad::{Joypad, JoypadKey, Player},
+    mmc::{new_mmc, Mmc},
+    ppu::{ForcedBlank, OamEntry, Ppu, VISIBLE_HEIGHT, VISIBLE_WIDTH},
+    region::Region,
+    rom::{Mirroring, Rom, RomInfo},
+    scheduler::{Clock, Scheduler},
+    sink::FrameSink,
 };
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Accuracy {
+    /// OAM DMAは一度に256バイトを転送し、スプライト評価はドット65でまとめて行う
+    Fast,
+    /// OAM DMAはget/putサイクルごとに進め、スプライト評価はまとめて行う
+    Balanced,
+    /// OAM DMAもスプライト評価もドット単位で進める
+    #[default]
+    Cycle,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Battery {
+    /// ヘッダーのバッテリーフラグに従う
+    #[default]
+    Auto,
+    /// ヘッダーに関係なくPRG-RAMを保存する
+    On,
+    /// ヘッダーに関係なくPRG-RAMを保存しない
+    Off,
+}
+
+// 内部エラーで止まったときの調査用の情報
+#[derive(Debug, Clone)]
+pub struct CrashReport {
+    pub error: String,
+    pub rom_crc32: u32,
+    pub rom_info: RomInfo,
+    pub cpu: String,
+    pub position: (u16, u16),
+    pub frame: u64,
+    pub trace: Vec<String>,
+    pub wram: Vec<u8>,
+    pub prg_ram: Option<Vec<u8>>,
+    pub oam: Vec<u8>,
+    pub palette_ram: Vec<u8>,
+}
+
+fn write_hex_dump(f: &mut Formatter<'_>, name: &str, data: &[u8]) -> fmt::Result {
+    writeln!(f, "[{}]", name)?;
+
+    for (i, row) in data.chunks(16).enumerate() {
+        write!(f, "{:04X}:", i * 16)?;
+
+        for byte in row {
+            write!(f, " {:02X}", byte)?;
+        }
+
+        writeln!(f)?;
+    }
+
+    writeln!(f)
+}
+
+impl Display for CrashReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "error: {}", self.error)?;
+        writeln!(f, "rom: {:08X} ({})", self.rom_crc32, self.rom_info)?;
+        writeln!(f, "cpu: {}", self.cpu)?;
+        writeln!(
+            f,
+            "ppu: frame {} line {} dot {}",
+            self.frame, self.position.0, self.position.1
+        )?;
+        writeln!(f)?;
+
+        writeln!(f, "[trace]")?;
+
+        for line in self.trace.iter() {
+            writeln!(f, "{}", line)?;
+        }
+
+        writeln!(f)?;
+
+        write_hex_dump(f, "wram", &self.wram)?;
+
+        if let Some(prg_ram) = &self.prg_ram {
+            write_hex_dump(f, "prg-ram", prg_ram)?;
+        }
+
+        write_hex_dump(f, "oam", &self.oam)?;
+        write_hex_dump(f, "palette", &self.palette_ram)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestResult {
+    Passed,
+    /// $6000の結果コード (画面から判定した場合はNone) とメッセージ
+    Failed {
+        code: Option<u8>,
+        message: String,
+    },
+    Timeout,
+}
+
+// blarggのテストROMは$6001〜$6003にこの値を書いてから結果を$6000に出す
+const TEST_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEST_RUNNING: u8 = 0x80;
+const TEST_NEEDS_RESET: u8 = 0x81;
+// リセットは100ms以上待ってから押す
+const TEST_RESET_DELAY_FRAMES: u64 = 6;
+
+// ステップオーバーでサブルーチンから戻ってこないときに諦めるまでの命令数
+const STEP_OVER_LIMIT: u64 = 1_000_000;
+
 pub struct Nes {
     cpu: Rc<RefCell<Cpu>>,
     ppu: Rc<RefCell<Ppu>>,
     apu: Rc<RefCell<Apu>>,
     joypad1: Rc<RefCell<Joypad>>,
     joypad2: Rc<RefCell<Joypad>>,
+    mmc: Rc<RefCell<Box<dyn Mmc>>>,
+    scheduler: Scheduler,
+    frame_sink: Option<Box<dyn FrameSink>>,
+
+    rom_info: RomInfo,
+    rom_crc32: u32,
+    region: Region,
+    accuracy: Accuracy,
+    battery: bool,
+    battery_override: Battery,
+    save_path: Option<PathBuf>,
 }
 
 impl Nes {
     pub fn new(rom: Rom) -> Result<Self> {
+        Self::new_with_options(rom, Accuracy::default())
+    }
+
+    pub fn new_with_options(rom: Rom, accuracy: Accuracy) -> Result<Self> {
+        let rom_info = rom.info();
+        let rom_crc32 = rom.crc32();
+        let region = Region::from(&rom.timing_mode);
+        let battery = rom.flag1.has_battery();
+
         let mmc = Rc::new(RefCell::new(new_mmc(rom)?));
         let apu = Rc::new(RefCell::new(Apu::new()));
 
-        let (ppu_bus_sender, ppu_bus_event) = channel::<PpuBusEvent>();
-        let (cpu_bus_sender, cpu_bus_event) = channel::<CpuBusEvent>();
-
-        let ppu_bus = PpuBus::new(Rc::clone(&mmc), ppu_bus_event, cpu_bus_sender);
-        let ppu = Rc::new(RefCell::new(Ppu::new(ppu_bus)));
+        let ppu_bus = PpuBus::new(Rc::clone(&mmc));
+        let ppu = Rc::new(RefCell::new(Ppu::new(ppu_bus, accuracy)));
 
         let joypad1 = Rc::new(RefCell::new(Joypad::new()));
         let joypad2 = Rc::new(RefCell::new(Joypad::new()));
@@ -40,8 +174,7 @@ impl Nes {
             Rc::clone(&apu),
             Rc::clone(&joypad1),
             Rc::clone(&joypad2),
-            cpu_bus_event,
-            ppu_bus_sender,
+            accuracy,
         );
         let cpu = Rc::new(RefCell::new(Cpu::new(cpu_bus)));
 
@@ -51,15 +184,347 @@ impl Nes {
             apu,
             joypad1,
             joypad2,
+            mmc,
+            scheduler: Scheduler::new(region),
+            frame_sink: None,
+
+            rom_info,
+            rom_crc32,
+            region,
+            accuracy,
+            battery,
+            battery_override: Battery::default(),
+            save_path: None,
         })
     }
 
+    // ROMファイルを用意せずにPRGとCHRから直接組み立てる
+    pub fn from_parts(
+        prg: Vec<u8>,
+        chr: Vec<u8>,
+        mapper: u8,
+        mirroring: Mirroring,
+    ) -> Result<Self> {
+        let mut nes = Self::new(Rom::from_parts(prg, chr, mapper, mirroring)?)?;
+
+        if let Mirroring::SingleScreenLower | Mirroring::SingleScreenUpper = mirroring {
+            nes.set_mirroring(Some(mirroring));
+        }
+
+        nes.power_on()?;
+
+        Ok(nes)
+    }
+
+    // テストROMを結果が出るかタイムアウトするまで動かす
+    pub fn run_test_rom(rom: Rom, timeout_frames: u64) -> Result<TestResult> {
+        let mut nes = Self::new(rom)?;
+        let mut reset_at = None;
+
+        nes.power_on()?;
+
+        for frame in 0..timeout_frames {
+            nes.run_frame()?;
+
+            if reset_at == Some(frame) {
+                nes.reset()?;
+                reset_at = None;
+            }
+
+            if let Some(result) = nes.test_result()? {
+                return Ok(result);
+            }
+
+            if reset_at.is_none() && nes.test_status()? == Some(TEST_NEEDS_RESET) {
+                reset_at = Some(frame + TEST_RESET_DELAY_FRAMES);
+            }
+        }
+
+        Ok(TestResult::Timeout)
+    }
+
+    fn test_status(&self) -> Result<Option<u8>> {
+        for (i, byte) in TEST_SIGNATURE.iter().enumerate() {
+            if self.peek(0x6001 + i as u16)? != *byte {
+                return Ok(None);
+            }
+        }
+
+        self.peek(0x6000).map(Some)
+    }
+
+    fn test_result(&self) -> Result<Option<TestResult>> {
+        match self.test_status()? {
+            Some(TEST_RUNNING) | Some(TEST_NEEDS_RESET) => Ok(None),
+            Some(0) => Ok(Some(TestResult::Passed)),
+            Some(code) => Ok(Some(TestResult::Failed {
+                code: Some(code),
+                message: self.test_message()?,
+            })),
+            // $6000を使わない古いテストは画面に結果を書く
+            None => {
+                let text = self.screen_text()?;
+                let lower = text.to_ascii_lowercase();
+
+                if lower.contains("failed") {
+                    Ok(Some(TestResult::Failed {
+                        code: None,
+                        message: text,
+                    }))
+                } else if lower.contains("passed") {
+                    Ok(Some(TestResult::Passed))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    fn test_message(&self) -> Result<String> {
+        let mut message = String::new();
+
+        for addr in 0x6004..0x8000 {
+            match self.peek(addr)? {
+                0 => break,
+                byte => message.push(byte as char),
+            }
+        }
+
+        Ok(message.trim().to_string())
+    }
+
+    // ネームテーブル0をASCIIとして読む
+    pub fn screen_text(&self) -> Result<String> {
+        let ppu = self.ppu.borrow();
+        let mut lines = Vec::new();
+
+        for row in 0..30 {
+            let mut line = String::new();
+
+            for col in 0..32 {
+                let tile = ppu.peek_vram(0x2000 + row * 32 + col)?;
+
+                line.push(if (0x20..0x7F).contains(&tile) {
+                    tile as char
+                } else {
+                    ' '
+                });
+            }
+
+            let line = line.trim_end();
+
+            if !line.is_empty() {
+                lines.push(line.to_string());
+            }
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    pub fn load_rom(&mut self, rom: Rom) -> Result<()> {
+        let frame_blend = self.ppu.borrow().frame_blend();
+        let forced_blank = self.ppu.borrow().forced_blank();
+        let sprite_limit = self.ppu.borrow().sprite_limit();
+        let debug_sprite_boxes = self.ppu.borrow().debug_sprite_boxes();
+        let mirroring = self.ppu.borrow().mirroring_override();
+        let trace = self.cpu.borrow_mut().take_trace();
+        let watchdog = self.cpu.borrow().watchdog();
+        let history_len = self.cpu.borrow().history_len();
+        let battery_override = self.battery_override;
+        let frame_sink = self.frame_sink.take();
+
+        *self = Self::new_with_options(rom, self.accuracy)?;
+
+        self.set_frame_blend(frame_blend);
+        self.set_forced_blank(forced_blank);
+        self.set_sprite_limit(sprite_limit);
+        self.set_debug_sprite_boxes(debug_sprite_boxes);
+        self.set_mirroring(mirroring);
+        self.set_trace(trace);
+        self.set_watchdog(watchdog);
+        self.set_crash_history(history_len);
+        self.set_battery(battery_override);
+        self.set_frame_sink(frame_sink);
+        self.power_on()
+    }
+
+    pub fn rom_info(&self) -> &RomInfo {
+        &self.rom_info
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn accuracy(&self) -> Accuracy {
+        self.accuracy
+    }
+
+    // 吸い出しによってはヘッダーのバッテリーフラグが間違っているので上書きできるようにする
+    pub fn set_battery(&mut self, battery: Battery) {
+        self.battery_override = battery;
+    }
+
+    pub fn has_battery(&self) -> bool {
+        match self.battery_override {
+            Battery::Auto => self.battery,
+            Battery::On => true,
+            Battery::Off => false,
+        }
+    }
+
+    pub fn load_battery<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+
+        self.save_path = Some(path.to_path_buf());
+
+        if !self.has_battery() || !path.exists() {
+            return Ok(());
+        }
+
+        let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+
+        if let Some(prg_ram) = self.mmc.borrow_mut().prg_ram_mut() {
+            let len = prg_ram.len().min(data.len());
+
+            prg_ram[..len].copy_from_slice(&data[..len]);
+        }
+
+        Ok(())
+    }
+
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.cpu.borrow_mut().flush_trace()?;
+
+        let path = match self.save_path.take() {
+            Some(path) if self.has_battery() => path,
+            _ => return Ok(()),
+        };
+
+        if let Some(prg_ram) = self.mmc.borrow().prg_ram() {
+            fs::write(&path, prg_ram)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn power_on(&mut self) -> Result<()> {
+        self.cpu.borrow_mut().power_on()?;
+
+        Ok(())
+    }
+
     pub fn reset(&mut self) -> Result<()> {
+        self.apu.borrow_mut().reset()?;
+        self.ppu.borrow_mut().reset();
         self.cpu.borrow_mut().reset()?;
 
         Ok(())
     }
 
+    // 次のフレームの先頭 (ライン0のドット0) まで進める
+    pub fn run_frame(&mut self) -> Result<()> {
+        let frame = self.frame_count();
+
+        while self.frame_count() == frame {
+            self.tick()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn step_instruction(&mut self) -> Result<()> {
+        if self.cpu.borrow().halted() {
+            bail!("CPU is halted at ${:04X}", self.cpu.borrow().pc());
+        }
+
+        let instructions = self.cpu.borrow().instructions();
+
+        while self.cpu.borrow().instructions() == instructions {
+            self.tick()?;
+        }
+
+        Ok(())
+    }
+
+    // JSRならスタックポインタが戻った状態で次の命令に来るまで進める
+    // 再帰や割り込みの途中で同じアドレスに来ても、スタックの深さが違うので止まらない
+    pub fn step_over(&mut self) -> Result<()> {
+        let (pc, sp) = {
+            let cpu = self.cpu.borrow();
+
+            (cpu.pc(), cpu.sp())
+        };
+
+        if self.peek(pc)? != 0x20 {
+            return self.step_instruction();
+        }
+
+        let return_addr = pc.wrapping_add(3);
+
+        for _ in 0..STEP_OVER_LIMIT {
+            self.step_instruction()?;
+
+            let cpu = self.cpu.borrow();
+
+            if cpu.pc() == return_addr && cpu.sp() == sp {
+                return Ok(());
+            }
+        }
+
+        bail!("JSR at ${:04X} did not return", pc)
+    }
+
+    pub fn jump_to(&mut self, addr: u16) {
+        self.cpu.borrow_mut().set_pc(addr);
+    }
+
+    // 副作用なしにCPUから見たメモリを読む
+    pub fn peek(&self, addr: u16) -> Result<u8> {
+        self.cpu.borrow().peek(addr)
+    }
+
+    pub fn set_crash_history(&mut self, len: usize) {
+        self.cpu.borrow_mut().set_history_len(len);
+    }
+
+    pub fn crash_report(&self, error: &anyhow::Error) -> CrashReport {
+        let cpu = self.cpu.borrow();
+        let ppu = self.ppu.borrow();
+
+        CrashReport {
+            error: format!("{:#}", error),
+            rom_crc32: self.rom_crc32,
+            rom_info: self.rom_info.clone(),
+            cpu: format!("{:?}", cpu),
+            position: ppu.position(),
+            frame: ppu.frame_count(),
+            trace: cpu.history(),
+            wram: cpu.wram().to_vec(),
+            prg_ram: self.mmc.borrow().prg_ram().map(|prg_ram| prg_ram.to_vec()),
+            oam: ppu.oam().to_vec(),
+            palette_ram: ppu.palette_ram().to_vec(),
+        }
+    }
+
+    pub fn set_watchdog(&mut self, limit: Option<u64>) {
+        self.cpu.borrow_mut().set_watchdog(limit);
+    }
+
+    pub fn take_stalled(&mut self) -> bool {
+        self.cpu.borrow_mut().take_stalled()
+    }
+
+    pub fn set_button(&mut self, player: Player, key: JoypadKey, pressed: bool) {
+        let joypad = match player {
+            Player::One => &self.joypad1,
+            Player::Two => &self.joypad2,
+        };
+
+        joypad.borrow_mut().set_pressed(key, pressed);
+    }
+
     pub fn player1_keydown(&mut self, key: JoypadKey) {
         self.joypad1.borrow_mut().keydown(key);
     }
@@ -68,6 +533,10 @@ impl Nes {
         self.joypad1.borrow_mut().keyup(key);
     }
 
+    pub fn set_player1_state(&mut self, buttons: u8) {
+        self.joypad1.borrow_mut().set_state(buttons);
+    }
+
     pub fn player2_keydown(&mut self, key: JoypadKey) {
         self.joypad2.borrow_mut().keydown(key);
     }
@@ -76,14 +545,894 @@ impl Nes {
         self.joypad2.borrow_mut().keyup(key);
     }
 
+    // PPUの1ドット分進める (その間に来るCPUのサイクルも実行する)
+    // 結果はROMとリセット、tickの間に与えた入力だけで決まり、コアは実時間を見ない
     pub fn tick(&mut self) -> Result<()> {
-        self.cpu.borrow_mut().tick()?;
+        while let Clock::Cpu = self.scheduler.step() {
+            self.cpu.borrow_mut().tick()?;
+        }
+
+        let frame = self.frame_count();
+
         self.ppu.borrow_mut().tick()?;
 
+        if self.frame_sink.is_some() && self.frame_count() != frame {
+            self.push_frame()?;
+        }
+
         Ok(())
     }
 
+    fn push_frame(&mut self) -> Result<()> {
+        let frame = self.render()?;
+
+        if let Some(sink) = self.frame_sink.as_mut() {
+            sink.push_frame(&frame, VISIBLE_WIDTH, VISIBLE_HEIGHT);
+        }
+
+        Ok(())
+    }
+
+    // フレームが出来上がるたびに渡す先 (render()をポーリングしなくてよくなる)
+    pub fn set_frame_sink(&mut self, sink: Option<Box<dyn FrameSink>>) {
+        self.frame_sink = sink;
+    }
+
+    // 受け取り側がいなくなっていたらfalseを返す
+    pub fn flush_frame_sink(&mut self) -> bool {
+        match self.frame_sink.as_mut() {
+            Some(sink) => sink.flush(),
+            None => true,
+        }
+    }
+
+    pub fn cpu_cycles(&self) -> u64 {
+        self.scheduler.cpu_cycles()
+    }
+
+    pub fn ppu_dots(&self) -> u64 {
+        self.scheduler.ppu_dots()
+    }
+
     pub fn render(&mut self) -> Result<Vec<u8>> {
         self.ppu.borrow_mut().render()
     }
+
+    pub fn oam(&self) -> [u8; 0x0100] {
+        *self.ppu.borrow().oam()
+    }
+
+    pub fn set_oam(&mut self, oam: &[u8; 0x0100]) {
+        self.ppu.borrow_mut().oam_mut().copy_from_slice(oam);
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.ppu.borrow().mirroring()
+    }
+
+    // デバッグ用: カートリッジやマッパーの指定に関係なくミラーリングを固定する
+    // Noneでマッパーの指定に戻す
+    pub fn set_mirroring(&mut self, mirroring: Option<Mirroring>) {
+        self.ppu.borrow_mut().set_mirroring(mirroring);
+    }
+
+    pub fn palette_ram(&self) -> [u8; 0x0020] {
+        *self.ppu.borrow().palette_ram()
+    }
+
+    pub fn resolved_palette(&self) -> [[u8; 3]; 0x0020] {
+        self.ppu.borrow().resolved_palette()
+    }
+
+    pub fn sprite(&self, i: usize) -> OamEntry {
+        self.ppu.borrow().sprite(i)
+    }
+
+    pub fn position(&self) -> (u16, u16) {
+        self.ppu.borrow().position()
+    }
+
+    pub fn apu_state(&self) -> ApuState {
+        self.apu.borrow().debug_state()
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.ppu.borrow().frame_count()
+    }
+
+    pub fn set_frame_blend(&mut self, enabled: bool) {
+        self.ppu.borrow_mut().set_frame_blend(enabled);
+    }
+
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.ppu.borrow_mut().set_sprite_limit(enabled);
+    }
+
+    pub fn set_debug_sprite_boxes(&mut self, enabled: bool) {
+        self.ppu.borrow_mut().set_debug_sprite_boxes(enabled);
+    }
+
+    pub fn set_forced_blank(&mut self, forced_blank: ForcedBlank) {
+        self.ppu.borrow_mut().set_forced_blank(forced_blank);
+    }
+
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Write>>) {
+        self.cpu.borrow_mut().set_trace(trace);
+    }
+}
+
+impl Drop for Nes {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            error!("SHUTDOWN FAILED: {:#}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash;
+
+    // $8000からプログラムを置き、リセットベクタを$8000に向けたPRG
+    fn prg_with(program: &[u8]) -> Vec<u8> {
+        let mut prg = vec![0xEA; 0x8000];
+
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x7FFC] = 0x00;
+        prg[0x7FFD] = 0x80;
+
+        prg
+    }
+
+    fn nes_with(program: &[u8]) -> Nes {
+        Nes::from_parts(prg_with(program), vec![0; 0x2000], 0, Mirroring::Horizontal).unwrap()
+    }
+
+    fn state_hash(nes: &Nes) -> u32 {
+        let mut state = Vec::new();
+
+        for addr in 0x0000..0x0800 {
+            state.push(nes.peek(addr).unwrap());
+        }
+
+        state.extend_from_slice(&nes.oam());
+        state.extend_from_slice(&nes.palette_ram());
+        state.extend_from_slice(&nes.cpu_cycles().to_le_bytes());
+        state.extend_from_slice(&nes.ppu_dots().to_le_bytes());
+
+        hash::crc32(&state)
+    }
+
+    // コントローラーを読み続けて、ボタンごとに押されていた回数を$00〜$07に足していく
+    const READ_JOYPAD: [u8; 22] = [
+        0xA2, 0x00, // LDX #$00
+        0xAD, 0x16, 0x40, // LDA $4016
+        0x29, 0x01, // AND #$01
+        0x18, // CLC
+        0x75, 0x00, // ADC $00,X
+        0x95, 0x00, // STA $00,X
+        0xE8, // INX
+        0xE0, 0x08, // CPX #$08
+        0xD0, 0xF1, // BNE $8002
+        0xE6, 0x10, // INC $10
+        0x4C, 0x00, 0x80, // JMP $8000
+    ];
+
+    fn run_script(script: &[u8]) -> u32 {
+        let mut nes = nes_with(&READ_JOYPAD);
+
+        for buttons in script {
+            nes.set_player1_state(*buttons);
+            nes.run_frame().unwrap();
+        }
+
+        state_hash(&nes)
+    }
+
+    #[test]
+    fn same_inputs_give_the_same_state() {
+        let script = [0x00, 0x01, 0x81, 0x10, 0xFF, 0x00, 0x42, 0x03];
+
+        assert_eq!(run_script(&script), run_script(&script));
+        assert_ne!(run_script(&script), run_script(&[0x00; 8]));
+    }
+
+    #[test]
+    fn dma_stalls_the_same_in_every_accuracy() {
+        // LDA #$02; STA $4014
+        let program = [0xA9, 0x02, 0x8D, 0x14, 0x40];
+
+        let dma_cycles = |accuracy| {
+            let rom = Rom::from_parts(
+                prg_with(&program),
+                vec![0; 0x2000],
+                0,
+                Mirroring::Horizontal,
+            )
+            .unwrap();
+            let mut nes = Nes::new_with_options(rom, accuracy).unwrap();
+
+            nes.power_on().unwrap();
+
+            nes.step_instruction().unwrap();
+            nes.step_instruction().unwrap();
+
+            let start = nes.cpu_cycles();
+
+            nes.step_instruction().unwrap();
+
+            nes.cpu_cycles() - start
+        };
+
+        let cycle = dma_cycles(Accuracy::Cycle);
+
+        assert!(cycle == 4 + 513 || cycle == 4 + 514, "{}", cycle);
+        assert_eq!(dma_cycles(Accuracy::Balanced), cycle);
+        assert_eq!(dma_cycles(Accuracy::Fast), cycle);
+    }
+
+    #[test]
+    fn load_rom_keeps_the_mirroring_override() {
+        let mut nes = nes_with(&[]);
+
+        nes.set_mirroring(Some(Mirroring::Vertical));
+
+        let rom =
+            Rom::from_parts(prg_with(&[]), vec![0; 0x2000], 0, Mirroring::Horizontal).unwrap();
+
+        nes.load_rom(rom).unwrap();
+
+        assert_eq!(nes.mirroring(), Mirroring::Vertical);
+
+        nes.set_mirroring(None);
+
+        assert_eq!(nes.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn from_parts_runs_from_the_reset_vector() {
+        let mut prg = vec![0xEA; 0x8000];
+
+        // $C000: LDA #$42; STA $00
+        prg[0x4000..0x4004].copy_from_slice(&[0xA9, 0x42, 0x85, 0x00]);
+        prg[0x7FFC] = 0x00;
+        prg[0x7FFD] = 0xC0;
+
+        let mut nes = Nes::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Vertical).unwrap();
+
+        assert_eq!(nes.cpu.borrow().pc(), 0xC000);
+
+        nes.step_instruction().unwrap();
+        nes.step_instruction().unwrap();
+
+        assert_eq!(nes.cpu.borrow().pc(), 0xC004);
+        assert_eq!(nes.peek(0x0000).unwrap(), 0x42);
+        assert_eq!(nes.mirroring(), Mirroring::Vertical);
+    }
+
+    // 決まったサイクル数でIRQを出し、$E000への書き込みで取り下げるマッパー
+    struct IrqMmc {
+        prg: Vec<u8>,
+        countdown: u32,
+        irq: bool,
+    }
+
+    impl Mmc for IrqMmc {
+        fn read_cpu(&self, addr: u16) -> Result<u8> {
+            match addr {
+                0x8000..=0xFFFF => Ok(self.prg[(addr - 0x8000) as usize]),
+                _ => Ok(0),
+            }
+        }
+
+        fn write_cpu(&mut self, addr: u16, _data: u8) -> Result<()> {
+            if addr == 0xE000 {
+                self.irq = false;
+            }
+
+            Ok(())
+        }
+
+        fn read_ppu(&self, _addr: u16) -> Result<u8> {
+            Ok(0)
+        }
+
+        fn write_ppu(&mut self, _addr: u16, _data: u8) -> Result<()> {
+            Ok(())
+        }
+
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::Horizontal
+        }
+
+        fn tick(&mut self) -> Result<()> {
+            if self.countdown > 0 {
+                self.countdown -= 1;
+                self.irq = self.countdown == 0;
+            }
+
+            Ok(())
+        }
+
+        fn irq(&self) -> bool {
+            self.irq
+        }
+    }
+
+    #[test]
+    fn mapper_irq_is_taken_and_acknowledged() {
+        let mut prg = prg_with(&[
+            0xA9, 0x00, // LDA #$00
+            0x85, 0x00, // STA $00
+            0x58, // CLI
+            0x4C, 0x05, 0x80, // JMP $8005
+        ]);
+
+        // $8100: INC $00; STA $E000; RTI
+        prg[0x0100..0x0106].copy_from_slice(&[0xE6, 0x00, 0x8D, 0x00, 0xE0, 0x40]);
+        prg[0x7FFE] = 0x00;
+        prg[0x7FFF] = 0x81;
+
+        let mut nes = nes_with(&[]);
+
+        *nes.mmc.borrow_mut() = Box::new(IrqMmc {
+            prg,
+            countdown: 100,
+            irq: false,
+        });
+
+        nes.reset().unwrap();
+
+        for _ in 0..200 {
+            nes.step_instruction().unwrap();
+        }
+
+        // 応答したあとは取り下げられるので、割り込みは1回だけ
+        assert_eq!(nes.peek(0x0000).unwrap(), 0x01);
+        assert!(!nes.mmc.borrow().irq());
+        assert_eq!(nes.cpu.borrow().pc(), 0x8005);
+    }
+
+    #[test]
+    fn step_over_waits_for_the_same_stack_depth() {
+        let mut prg = prg_with(&[
+            0xA9, 0x00, // LDA #$00
+            0x85, 0x00, // STA $00
+            0x20, 0x10, 0x80, // JSR $8010
+            0xE6, 0x00, // INC $00
+            0x60, // RTS
+        ]);
+
+        // $8010: 戻り先の$8007を深いスタックで呼ぶ
+        prg[0x10..0x14].copy_from_slice(&[0x20, 0x07, 0x80, 0x60]);
+
+        let mut nes = Nes::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Horizontal).unwrap();
+
+        nes.step_instruction().unwrap();
+        nes.step_instruction().unwrap();
+
+        let sp = nes.cpu.borrow().sp();
+
+        nes.step_over().unwrap();
+
+        assert_eq!(nes.cpu.borrow().pc(), 0x8007);
+        assert_eq!(nes.cpu.borrow().sp(), sp);
+        assert_eq!(nes.peek(0x0000).unwrap(), 0x01);
+    }
+
+    #[test]
+    fn step_over_runs_through_an_nmi() {
+        let mut prg = prg_with(&[0x4C, 0x00, 0x80]); // JMP $8000
+
+        // $8020: $01を0にしてNMIを有効にし、1フレームより長いサブルーチンを呼ぶ
+        prg[0x20..0x2D].copy_from_slice(&[
+            0xA9, 0x00, // LDA #$00
+            0x85, 0x01, // STA $01
+            0xA9, 0x80, // LDA #$80
+            0x8D, 0x00, 0x20, // STA $2000
+            0x20, 0x40, 0x80, // JSR $8040
+            0xEA, // NOP
+        ]);
+
+        prg[0x40..0x4B].copy_from_slice(&[
+            0xA0, 0x20, // LDY #$20
+            0xA2, 0x00, // LDX #$00
+            0xCA, // DEX
+            0xD0, 0xFD, // BNE $8044
+            0x88, // DEY
+            0xD0, 0xF8, // BNE $8042
+            0x60, // RTS
+        ]);
+
+        // $8060: INC $01; RTI
+        prg[0x60..0x63].copy_from_slice(&[0xE6, 0x01, 0x40]);
+        prg[0x7FFA] = 0x60;
+        prg[0x7FFB] = 0x80;
+
+        let mut nes = Nes::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Horizontal).unwrap();
+
+        // $2000への書き込みが効くようになるまで待つ
+        for _ in 0..3 {
+            nes.run_frame().unwrap();
+        }
+
+        nes.jump_to(0x8020);
+
+        for _ in 0..4 {
+            nes.step_instruction().unwrap();
+        }
+
+        nes.step_over().unwrap();
+
+        assert_eq!(nes.cpu.borrow().pc(), 0x802C);
+        assert!(nes.peek(0x0001).unwrap() >= 1);
+    }
+
+    #[test]
+    fn buttons_set_before_a_frame_are_seen_by_its_first_read() {
+        let mut prg = prg_with(&[
+            0x2C, 0x02, 0x20, // BIT $2002
+            0x10, 0xFB, // BPL $8000
+            0x2C, 0x02, 0x20, // BIT $2002
+            0x10, 0xFB, // BPL $8005
+            0xA2, 0x00, // LDX #$00
+            0xA9, 0x80, // LDA #$80
+            0x8D, 0x00, 0x20, // STA $2000
+            0x4C, 0x11, 0x80, // JMP $8011
+        ]);
+
+        // $8020: NMIで1フレームに1回だけ読み、$10,Xに並べて個数を$0Fに置く
+        prg[0x20..0x35].copy_from_slice(&[
+            0xA9, 0x01, // LDA #$01
+            0x8D, 0x16, 0x40, // STA $4016
+            0xA9, 0x00, // LDA #$00
+            0x8D, 0x16, 0x40, // STA $4016
+            0xAD, 0x16, 0x40, // LDA $4016
+            0x29, 0x01, // AND #$01
+            0x95, 0x10, // STA $10,X
+            0xE8, // INX
+            0x86, 0x0F, // STX $0F
+            0x40, // RTI
+        ]);
+        prg[0x7FFA] = 0x20;
+        prg[0x7FFB] = 0x80;
+
+        let mut nes = Nes::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Horizontal).unwrap();
+
+        for _ in 0..4 {
+            nes.run_frame().unwrap();
+        }
+
+        for &buttons in [0x01, 0x00, 0x01, 0x01, 0x00].iter() {
+            let reads = nes.peek(0x000F).unwrap();
+
+            nes.set_player1_state(buttons);
+            nes.run_frame().unwrap();
+
+            assert_eq!(nes.peek(0x000F).unwrap(), reads.wrapping_add(1));
+            assert_eq!(nes.peek(0x0010 + reads as u16).unwrap(), buttons);
+        }
+    }
+
+    // $6000に実行中を書いてからシグネチャを置き、結果コードとメッセージを書いて止まる
+    fn test_rom(code: u8, message: &[u8]) -> Rom {
+        let mut program = vec![
+            0xA9, 0x80, // LDA #$80
+            0x8D, 0x00, 0x60, // STA $6000
+            0xA9, 0xDE, // LDA #$DE
+            0x8D, 0x01, 0x60, // STA $6001
+            0xA9, 0xB0, // LDA #$B0
+            0x8D, 0x02, 0x60, // STA $6002
+            0xA9, 0x61, // LDA #$61
+            0x8D, 0x03, 0x60, // STA $6003
+        ];
+
+        for (i, &byte) in message.iter().chain([0].iter()).enumerate() {
+            // LDA #byte; STA $6004+i
+            program.extend_from_slice(&[0xA9, byte, 0x8D, 0x04 + i as u8, 0x60]);
+        }
+
+        let end = 0x8000 + program.len() as u16 + 5;
+
+        program.extend_from_slice(&[0xA9, code, 0x8D, 0x00, 0x60]);
+        program.extend_from_slice(&[0x4C, end as u8, (end >> 8) as u8]);
+
+        Rom::from_parts(
+            prg_with(&program),
+            vec![0; 0x2000],
+            0,
+            Mirroring::Horizontal,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn run_test_rom_reads_the_6000_protocol() {
+        assert_eq!(
+            Nes::run_test_rom(test_rom(0x00, b""), 10).unwrap(),
+            TestResult::Passed
+        );
+
+        assert_eq!(
+            Nes::run_test_rom(test_rom(0x03, b"FAILED #3"), 10).unwrap(),
+            TestResult::Failed {
+                code: Some(0x03),
+                message: "FAILED #3".to_string(),
+            }
+        );
+
+        // 実行中のまま終わらなければタイムアウト
+        assert_eq!(
+            Nes::run_test_rom(test_rom(0x80, b""), 10).unwrap(),
+            TestResult::Timeout
+        );
+    }
+
+    #[test]
+    fn stp_halts_the_cpu_but_not_the_ppu() {
+        // STP
+        let mut nes = nes_with(&[0x02]);
+
+        nes.step_instruction().unwrap();
+
+        let instructions = nes.cpu.borrow().instructions();
+        let frames = nes.frame_count();
+
+        for _ in 0..2 {
+            nes.run_frame().unwrap();
+        }
+
+        // PCは$8000のまま止まり、PPUだけが進む
+        assert!(nes.cpu.borrow().halted());
+        assert_eq!(nes.cpu.borrow().pc(), 0x8000);
+        assert_eq!(nes.cpu.borrow().instructions(), instructions);
+        assert_eq!(nes.frame_count(), frames + 2);
+        assert!(nes.step_instruction().is_err());
+    }
+
+    #[test]
+    fn nmi_keeps_the_write_latch_and_2002_resets_it() {
+        let mut prg = prg_with(&[
+            0x2C, 0x02, 0x20, // BIT $2002
+            0x10, 0xFB, // BPL $8000
+            0x2C, 0x02, 0x20, // BIT $2002
+            0x10, 0xFB, // BPL $8005
+            0xA9, 0x21, // LDA #$21
+            0x8D, 0x06, 0x20, // STA $2006
+            0xA9, 0x80, // LDA #$80
+            0x8D, 0x00, 0x20, // STA $2000
+            0x4C, 0x14, 0x80, // JMP $8014
+        ]);
+
+        // $8020: 1回目の書き込みの続きから$2100に書き、書きかけのまま$2002を読んで$2200に書き直す
+        prg[0x20..0x47].copy_from_slice(&[
+            0xA9, 0x00, // LDA #$00
+            0x8D, 0x06, 0x20, // STA $2006
+            0xA9, 0xAA, // LDA #$AA
+            0x8D, 0x07, 0x20, // STA $2007
+            0xA9, 0x23, // LDA #$23
+            0x8D, 0x06, 0x20, // STA $2006
+            0x2C, 0x02, 0x20, // BIT $2002
+            0xA9, 0x22, // LDA #$22
+            0x8D, 0x06, 0x20, // STA $2006
+            0xA9, 0x00, // LDA #$00
+            0x8D, 0x06, 0x20, // STA $2006
+            0xA9, 0xBB, // LDA #$BB
+            0x8D, 0x07, 0x20, // STA $2007
+            0xA9, 0x00, // LDA #$00
+            0x8D, 0x00, 0x20, // STA $2000
+            0x40, // RTI
+        ]);
+        prg[0x7FFA] = 0x20;
+        prg[0x7FFB] = 0x80;
+
+        let mut nes = Nes::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Horizontal).unwrap();
+
+        for _ in 0..5 {
+            nes.run_frame().unwrap();
+        }
+
+        let ppu = nes.ppu.borrow();
+
+        assert_eq!(ppu.peek_vram(0x2100).unwrap(), 0xAA);
+        assert_eq!(ppu.peek_vram(0x2200).unwrap(), 0xBB);
+    }
+
+    struct RecordingSink {
+        frames: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl FrameSink for RecordingSink {
+        fn push_frame(&mut self, frame: &[u8], width: usize, height: usize) {
+            assert_eq!(frame.len(), width * height * 4);
+
+            self.frames.borrow_mut().push(frame.len());
+        }
+    }
+
+    #[test]
+    fn frame_sink_gets_one_frame_per_emulated_frame() {
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let mut nes = nes_with(&[]);
+
+        nes.set_frame_sink(Some(Box::new(RecordingSink {
+            frames: Rc::clone(&frames),
+        })));
+
+        for _ in 0..3 {
+            nes.run_frame().unwrap();
+        }
+
+        assert_eq!(*frames.borrow(), vec![256 * 240 * 4; 3]);
+        assert!(nes.flush_frame_sink());
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rnes-{}-{}", std::process::id(), name));
+
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    #[test]
+    fn load_rom_flushes_the_battery_and_starts_the_new_rom() {
+        let save_path = temp_path("load_rom.sav");
+
+        // LDA #$5A; STA $6000
+        let mut nes = nes_with(&[0xA9, 0x5A, 0x8D, 0x00, 0x60]);
+
+        nes.set_battery(Battery::On);
+        nes.load_battery(&save_path).unwrap();
+        nes.run_frame().unwrap();
+
+        // $C000: LDA #$77; STA $00
+        let mut prg = vec![0xEA; 0x8000];
+
+        prg[0x4000..0x4004].copy_from_slice(&[0xA9, 0x77, 0x85, 0x00]);
+        prg[0x7FFC] = 0x00;
+        prg[0x7FFD] = 0xC0;
+
+        nes.load_rom(Rom::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Horizontal).unwrap())
+            .unwrap();
+
+        let save = fs::read(&save_path).unwrap();
+
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!(save[0], 0x5A);
+
+        assert_eq!(nes.cpu.borrow().pc(), 0xC000);
+
+        nes.step_instruction().unwrap();
+        nes.step_instruction().unwrap();
+
+        assert_eq!(nes.peek(0x0000).unwrap(), 0x77);
+        assert_eq!(nes.peek(0x6000).unwrap(), 0x00);
+
+        fs::remove_file(&save_path).unwrap();
+    }
+
+    #[test]
+    fn oam_dma_starts_at_oamaddr_and_wraps() {
+        let mut nes = nes_with(&[
+            0xA2, 0x00, // LDX #$00
+            0x8A, // TXA
+            0x9D, 0x00, 0x02, // STA $0200,X
+            0xE8, // INX
+            0xD0, 0xF9, // BNE $8002
+            0xA9, 0x40, // LDA #$40
+            0x8D, 0x03, 0x20, // STA $2003
+            0xA9, 0x02, // LDA #$02
+            0x8D, 0x14, 0x40, // STA $4014
+        ]);
+
+        nes.run_frame().unwrap();
+
+        let oam = nes.oam();
+
+        // $0200はOAM[$40]に入り、$02C0で$FFから$00に回り込む
+        for i in 0..0x0100 {
+            assert_eq!(
+                oam[(0x40 + i) & 0xFF],
+                i as u8,
+                "OAM[{:#04X}]",
+                (0x40 + i) & 0xFF
+            );
+        }
+    }
+
+    #[test]
+    fn jump_to_runs_the_next_instruction_from_there() {
+        let mut prg = prg_with(&[]);
+
+        // $C000: LDA #$33; STA $00
+        prg[0x4000..0x4004].copy_from_slice(&[0xA9, 0x33, 0x85, 0x00]);
+
+        let mut nes = Nes::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Horizontal).unwrap();
+
+        nes.jump_to(0xC000);
+
+        assert_eq!(nes.cpu.borrow().pc(), 0xC000);
+
+        nes.step_instruction().unwrap();
+
+        assert_eq!(nes.cpu.borrow().pc(), 0xC002);
+
+        nes.step_instruction().unwrap();
+
+        assert_eq!(nes.peek(0x0000).unwrap(), 0x33);
+    }
+
+    #[test]
+    fn dropping_a_battery_nes_writes_the_save_file() {
+        let save_path = temp_path("drop.sav");
+
+        // バッテリー付きのiNESヘッダー。LDA #$A5; STA $6010
+        let mut data = vec![b'N', b'E', b'S', 0x1A, 2, 1, 0x02, 0x00];
+
+        data.resize(0x10, 0);
+        data.extend(prg_with(&[0xA9, 0xA5, 0x8D, 0x10, 0x60]));
+        data.extend(vec![0; 0x2000]);
+
+        let mut nes = Nes::new(Rom::from_bytes(data).unwrap()).unwrap();
+
+        assert!(nes.has_battery());
+
+        nes.load_battery(&save_path).unwrap();
+        nes.power_on().unwrap();
+        nes.run_frame().unwrap();
+
+        drop(nes);
+
+        let save = fs::read(&save_path).unwrap();
+
+        assert_eq!(save.len(), 0x2000);
+        assert_eq!(save[0x10], 0xA5);
+
+        fs::remove_file(&save_path).unwrap();
+    }
+
+    #[test]
+    fn halted_cpu_error_produces_a_populated_crash_report() {
+        // LDA #$5A; STA $10; STP
+        let program = [0xA9, 0x5A, 0x85, 0x10, 0x02];
+        let mut nes = nes_with(&program);
+
+        nes.set_crash_history(4);
+
+        let error = loop {
+            if let Err(e) = nes.step_instruction() {
+                break e;
+            }
+        };
+
+        let report = nes.crash_report(&error);
+
+        assert_eq!(report.error, "CPU is halted at $8004");
+        let mut body = prg_with(&program);
+
+        body.resize(0xA000, 0);
+
+        assert_eq!(report.rom_crc32, hash::crc32(&body));
+        assert_eq!(report.trace.len(), 3);
+        assert!(report.trace[0].starts_with("8000  A9 5A"));
+        assert!(report.trace[2].starts_with("8004  02"));
+        assert_eq!(report.wram[0x10], 0x5A);
+        assert_eq!(report.oam.len(), 0x100);
+        assert_eq!(report.palette_ram.len(), 0x20);
+
+        let text = report.to_string();
+
+        assert!(text.starts_with("error: CPU is halted at $8004\n"));
+        assert!(text.contains("[trace]\n8000  A9 5A"));
+        assert!(text.contains("[wram]\n0000:"));
+    }
+
+    #[test]
+    fn battery_override_decides_whether_a_save_file_is_written() {
+        let cases = [
+            (Battery::Auto, false),
+            (Battery::On, true),
+            (Battery::Off, false),
+        ];
+
+        for &(battery, saved) in cases.iter() {
+            let save_path = temp_path(&format!("override-{:?}.sav", battery));
+
+            // バッテリーなしのROM。LDA #$3C; STA $6020
+            let mut nes = nes_with(&[0xA9, 0x3C, 0x8D, 0x20, 0x60]);
+
+            nes.set_battery(battery);
+
+            assert_eq!(nes.has_battery(), saved, "{:?}", battery);
+
+            nes.load_battery(&save_path).unwrap();
+            nes.run_frame().unwrap();
+
+            drop(nes);
+
+            match fs::read(&save_path) {
+                Ok(save) => {
+                    assert!(saved, "{:?}", battery);
+                    assert_eq!(save.len(), 0x2000);
+                    assert_eq!(save[0x20], 0x3C);
+
+                    fs::remove_file(&save_path).unwrap();
+                }
+                Err(_) => assert!(!saved, "{:?}", battery),
+            }
+        }
+    }
+
+    #[test]
+    fn dma_written_oam_reads_back_through_the_accessors() {
+        let mut nes = nes_with(&[
+            0xA9, 0x10, 0x8D, 0x04, 0x02, // LDA #$10; STA $0204
+            0xA9, 0x22, 0x8D, 0x05, 0x02, // LDA #$22; STA $0205
+            0xA9, 0x41, 0x8D, 0x06, 0x02, // LDA #$41; STA $0206
+            0xA9, 0x80, 0x8D, 0x07, 0x02, // LDA #$80; STA $0207
+            0xA9, 0x02, 0x8D, 0x14, 0x40, // LDA #$02; STA $4014
+        ]);
+
+        nes.run_frame().unwrap();
+
+        assert_eq!(
+            nes.sprite(1),
+            OamEntry {
+                y: 0x10,
+                tile: 0x22,
+                attributes: 0x41,
+                x: 0x80,
+            }
+        );
+        assert_eq!(nes.oam()[4..8], [0x10, 0x22, 0x41, 0x80]);
+        assert_eq!(nes.ppu.borrow().oam()[..], nes.oam()[..]);
+    }
+
+    #[test]
+    fn watchdog_fires_after_the_limit_without_progress() {
+        // JMP $8000
+        let mut nes = nes_with(&[0x4C, 0x00, 0x80]);
+
+        nes.set_watchdog(Some(100));
+
+        for _ in 0..99 {
+            nes.step_instruction().unwrap();
+        }
+
+        assert!(!nes.take_stalled());
+
+        nes.step_instruction().unwrap();
+
+        assert!(nes.take_stalled());
+        assert!(!nes.take_stalled());
+
+        // 数え直すので、次もまた100命令後
+        for _ in 0..99 {
+            nes.step_instruction().unwrap();
+        }
+
+        assert!(!nes.take_stalled());
+    }
+
+    #[test]
+    fn set_button_is_idempotent() {
+        let mut nes = nes_with(&[]);
+
+        nes.set_button(Player::Two, JoypadKey::B, true);
+        nes.set_button(Player::Two, JoypadKey::B, true);
+
+        assert_eq!(nes.joypad2.borrow().state_byte(), 0x02);
+        assert_eq!(nes.joypad1.borrow().state_byte(), 0x00);
+
+        // 2回押しても1回離せば離れる
+        nes.set_button(Player::Two, JoypadKey::B, false);
+
+        assert_eq!(nes.joypad2.borrow().state_byte(), 0x00);
+
+        nes.set_button(Player::Two, JoypadKey::B, false);
+
+        assert_eq!(nes.joypad2.borrow().state_byte(), 0x00);
+    }
 }
@@ -4,12 +4,21 @@ use bitmatch::bitmatch;
 use image::{ImageBuffer, Rgba};
 use log::{debug, trace};
 
-use crate::bus::PpuBus;
-
-const VISIBLE_WIDTH: usize = 256;
-const VISIBLE_HEIGHT: usize = 240;
-const WIDTH: usize = 340;
-const HEIGHT: usize = 261;
+use crate::{
+    bus::{OpenBus, PpuBus},
+    nes::Accuracy,
+    rom::Mirroring,
+};
+
+pub const VISIBLE_WIDTH: usize = 256;
+pub const VISIBLE_HEIGHT: usize = 240;
+const WIDTH: usize = 341;
+const HEIGHT: usize = 262;
+const VBLANK_LINE: usize = VISIBLE_HEIGHT + 1;
+const PRE_RENDER_LINE: usize = HEIGHT - 1;
+
+// タイミングの調査用に、フレームに数回しか起きないイベントだけを出すターゲット
+const EVENTS: &str = "rnes::ppu::events";
 
 const COLORS: [[u8; 4]; 64] = [
     [0x80, 0x80, 0x80, 0xFF],
@@ -118,6 +127,19 @@ impl Default for OamColor {
 
 type ColorIndex = usize;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForcedBlank {
+    HardwareAccurate,
+    ForceBlack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RegisterTiming {
+    WarmUp,
+    Rendering,
+    Idle,
+}
+
 #[derive(Debug, PartialEq)]
 enum Mode {
     Idle,
@@ -175,6 +197,14 @@ impl Oam {
     }
 }
 
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct OamEntry {
+    pub y: u8,
+    pub tile: u8,
+    pub attributes: u8,
+    pub x: u8,
+}
+
 bitfield! {
     #[derive(Clone, Copy)]
     struct Ctrl(u8);
@@ -228,6 +258,14 @@ impl Attribute {
     }
 }
 
+pub fn blend_frames(current: &[u8], previous: &[u8]) -> Vec<u8> {
+    current
+        .iter()
+        .zip(previous.iter())
+        .map(|(&c, &p)| ((c as u16 + p as u16) / 2) as u8)
+        .collect()
+}
+
 pub struct Ppu {
     bus: PpuBus,
 
@@ -235,31 +273,51 @@ pub struct Ppu {
     mask: Mask,
     status: Status,
 
-    dma_addr: u16,
     oam_addr: u8,
-    buffer: Vec<u8>,
+    data_buffer: u8,
     mode: Mode,
 
     x: u8,
     y: u8,
-    scroll_x: u8,
-    scroll_y: u8,
+
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    w: bool,
 
     cycles: usize,
     lines: usize,
+    frames: u64,
+    suppress_vblank: bool,
+    warm_up: bool,
+
+    io_bus: OpenBus,
 
     cur_bg: [Color; 8],
 
     bg_line: [Color; WIDTH],
     oam_line: [OamColor; WIDTH],
 
+    secondary_oam: [u8; 0x0100],
+    sprite_count: usize,
+    sprite_zero_next: bool,
+    eval_n: usize,
+    eval_m: usize,
+
     pixels: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    prev_pixels: ImageBuffer<Rgba<u8>, Vec<u8>>,
+
+    frame_blend: bool,
+    forced_blank: ForcedBlank,
+    accuracy: Accuracy,
+    sprite_limit: bool,
+    debug_sprite_boxes: bool,
 
     pub nmi: bool,
 }
 
 impl Ppu {
-    pub fn new(bus: PpuBus) -> Self {
+    pub fn new(bus: PpuBus, accuracy: Accuracy) -> Self {
         Self {
             bus,
 
@@ -268,53 +326,96 @@ impl Ppu {
             status: Status(0),
 
             oam_addr: 0,
-            dma_addr: 0,
-            buffer: Vec::with_capacity(2),
+            data_buffer: 0,
             mode: Mode::Idle,
 
             x: 0,
             y: 0,
-            scroll_x: 0,
-            scroll_y: 0,
+
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
 
             cycles: 0,
             lines: 0,
+            frames: 0,
+            suppress_vblank: false,
+            warm_up: true,
+
+            io_bus: Default::default(),
 
             cur_bg: [Default::default(); 8],
             bg_line: [Default::default(); WIDTH],
             oam_line: [Default::default(); WIDTH],
 
+            secondary_oam: [0xFF; 0x0100],
+            sprite_count: 0,
+            sprite_zero_next: false,
+            eval_n: 0,
+            eval_m: 0,
+
             pixels: ImageBuffer::new(VISIBLE_WIDTH as u32, VISIBLE_HEIGHT as u32),
+            prev_pixels: ImageBuffer::new(VISIBLE_WIDTH as u32, VISIBLE_HEIGHT as u32),
+
+            frame_blend: false,
+            forced_blank: ForcedBlank::HardwareAccurate,
+            accuracy,
+            sprite_limit: true,
+            debug_sprite_boxes: false,
 
             nmi: false,
         }
     }
 
+    // リセットでクリアされるのはCTRL, MASK, スクロールとラッチ, 読み込みバッファだけ
+    pub fn reset(&mut self) {
+        self.ctrl = Ctrl(0);
+        self.mask = Mask(0);
+
+        self.t = 0;
+        self.fine_x = 0;
+        self.w = false;
+
+        self.data_buffer = 0;
+
+        self.warm_up = true;
+    }
+
     pub fn tick(&mut self) -> Result<()> {
-        self.cycles += 1;
+        // 描き終わったフレームは出力に使うので、次のフレームを描き始めるときに取っておく
+        if self.frame_blend && self.lines == 0 && self.cycles == 0 {
+            self.prev_pixels.copy_from_slice(&self.pixels);
+        }
+
+        if self.lines == PRE_RENDER_LINE && self.cycles == 1 {
+            trace!(target: EVENTS, "VBLANK CLEAR: LINE {} DOT {}", self.lines, self.cycles);
 
-        self.bus.tick()?;
+            self.warm_up = false;
 
-        if self.cycles == WIDTH {
-            self.cycles = 0;
-            self.lines += 1;
+            self.status.set_irq_vblank(false);
+            self.status.set_oam_0_hit(false);
+            self.status.set_oam_overflow(false);
+            self.nmi = false;
         }
 
-        if self.cycles == 0 {
-            if self.lines == HEIGHT {
-                self.lines = 0;
-                self.status.set_irq_vblank(false);
-                self.nmi = false;
-            }
+        if self.lines == VBLANK_LINE && self.cycles == 1 {
+            self.y = 0;
+            self.mode = Mode::VBlank;
 
-            if self.lines == VISIBLE_HEIGHT {
-                self.y = 0;
-                self.mode = Mode::VBlank;
+            if !self.suppress_vblank {
                 self.status.set_irq_vblank(true);
 
-                if self.ctrl.ie_nmi() {
-                    self.nmi = true;
-                }
+                trace!(target: EVENTS, "VBLANK SET: LINE {} DOT {}", self.lines, self.cycles);
+            }
+
+            self.suppress_vblank = false;
+
+            // NMIはフラグから作られるので、フラグが立たなければ起きない
+            if self.ctrl.ie_nmi() && self.status.irq_vblank() {
+                self.nmi = true;
+
+                trace!(target: EVENTS, "NMI: LINE {} DOT {}", self.lines, self.cycles);
             }
         }
 
@@ -343,56 +444,258 @@ impl Ppu {
         match self.mode {
             Mode::Drawing => {
                 self.draw_bg()?;
+                self.evaluate_sprites();
 
                 self.put_pixels()?;
             }
             Mode::OamScan => {
-                self.draw_sprites(self.cycles % 64)?;
+                self.fetch_sprites()?;
             }
             _ => {}
         }
 
+        if self.rendering() && (self.lines < VISIBLE_HEIGHT || self.lines == PRE_RENDER_LINE) {
+            match self.cycles {
+                1 if self.lines == PRE_RENDER_LINE => self.corrupt_oam(),
+                257..=320 => self.oam_addr = 0,
+                _ => {}
+            }
+
+            match self.cycles {
+                256 => self.increment_y(),
+                257 => self.copy_x(),
+                280..=304 if self.lines == PRE_RENDER_LINE => self.copy_y(),
+                _ => {}
+            }
+        }
+
+        self.next_dot();
+
         Ok(())
     }
 
+    // ドットの処理が終わってから進めるので、ドット0はアイドル、1〜256が描画になる
+    fn next_dot(&mut self) {
+        self.cycles += 1;
+
+        if self.cycles < WIDTH && !(self.cycles == WIDTH - 1 && self.skip_dot()) {
+            return;
+        }
+
+        self.cycles = 0;
+        self.lines += 1;
+
+        if self.lines == HEIGHT {
+            self.lines = 0;
+            self.frames += 1;
+
+            self.io_bus.decay(self.frames);
+        }
+    }
+
+    // 奇数フレームで描画が有効なら、プリレンダーラインの最後のドットを飛ばす
+    // 判定はラインの終わりで行うので、途中で描画を無効にすると飛ばさない
+    fn skip_dot(&self) -> bool {
+        self.lines == PRE_RENDER_LINE && self.frames & 1 == 1 && self.rendering()
+    }
+
+    // 描画開始時にOAMADDRが8以上だと、その8バイトが先頭にコピーされてしまう
+    fn corrupt_oam(&mut self) {
+        if self.oam_addr < 8 {
+            return;
+        }
+
+        let base = (self.oam_addr & 0xF8) as usize;
+        let mut bytes = [0; 8];
+
+        bytes.copy_from_slice(&self.bus.oam[base..(base + 8)]);
+        self.bus.oam[..8].copy_from_slice(&bytes);
+    }
+
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+
+        self.v &= !0x7000;
+
+        let coarse_y = match (self.v & 0x03E0) >> 5 {
+            29 => {
+                self.v ^= 0x0800;
+                0
+            }
+            31 => 0,
+            y => y + 1,
+        };
+
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    fn copy_x(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    fn copy_y(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
     fn draw_bg(&mut self) -> Result<()> {
-        if !self.mask.bg() {
+        if !self.rendering() {
             return Ok(());
         }
 
-        let cx = self.x.wrapping_add(self.scroll_x);
-        let cy = self.y.wrapping_add(self.scroll_y);
-        let col = cx % 8;
-        let row = cy % 8;
-        let tile_x = cx / 8;
-        let tile_y = cy / 8;
+        let col = self.x.wrapping_add(self.fine_x) % 8;
+
+        if self.x == 0 || col == 0 {
+            let tile_x = (self.v & 0x001F) as u8;
+            let tile_y = ((self.v & 0x03E0) >> 5) as u8;
+            let row = ((self.v & 0x7000) >> 12) as u8;
 
-        if col == 0 {
-            let attr = self.bg_attr(tile_x, tile_y)?;
-            let tile = self.bg_tile(tile_x, tile_y)?;
+            let attr = self.bg_attr()?;
+            let tile = self.bg_tile()?;
             let indexes = self.to_indexes(tile, row, self.bg_pattern_table_addr())?;
             let palettes = self.bg_palettes(tile_x, tile_y, attr)?;
 
             self.cur_bg = self.to_colors(indexes, palettes);
         }
 
-        self.bg_line[self.x as usize] = self.cur_bg[col as usize];
+        if self.mask.bg() {
+            self.bg_line[self.x as usize] = self.cur_bg[col as usize];
+        }
+
+        if col == 7 {
+            self.increment_x();
+        }
 
         Ok(())
     }
 
-    fn draw_sprites(&mut self, i: usize) -> Result<()> {
-        if !self.mask.oam() {
+    fn rendering(&self) -> bool {
+        self.mask.bg() || self.mask.oam()
+    }
+
+    fn sprite_in_range(&self, y: u8) -> bool {
+        let size = if self.ctrl.large_sprite() { 16 } else { 8 };
+        let cur_y = self.lines as u16;
+        let target_y = y as u16;
+
+        target_y <= cur_y && cur_y < target_y + size
+    }
+
+    fn evaluate_sprites(&mut self) {
+        if self.cycles == 65 {
+            self.sprite_count = 0;
+            self.sprite_zero_next = false;
+            self.eval_n = 0;
+            self.eval_m = 0;
+        }
+
+        if !self.rendering() {
+            return;
+        }
+
+        if self.accuracy != Accuracy::Cycle {
+            // ドット単位ではなく、ドット64と65でまとめて処理する
+            match self.cycles {
+                64 => self.secondary_oam[..32].fill(0xFF),
+                65 => {
+                    while self.eval_n < 64 {
+                        self.evaluate_sprite();
+                    }
+                }
+                _ => {}
+            }
+
+            return;
+        }
+
+        match self.cycles {
+            1..=64 if self.cycles & 1 == 0 => {
+                self.secondary_oam[self.cycles / 2 - 1] = 0xFF;
+            }
+            65..=256 if self.cycles & 1 == 1 => {
+                self.evaluate_sprite();
+            }
+            _ => {}
+        }
+    }
+
+    fn evaluate_sprite(&mut self) {
+        if self.eval_n >= 64 {
+            return;
+        }
+
+        let n = self.eval_n;
+        let limit = if self.sprite_limit { 8 } else { 64 };
+
+        if self.sprite_count < limit {
+            let y = self.bus.oam[n * 4];
+
+            self.secondary_oam[self.sprite_count * 4] = y;
+
+            if self.sprite_in_range(y) {
+                let slot = self.sprite_count * 4;
+
+                self.secondary_oam[slot..(slot + 4)]
+                    .copy_from_slice(&self.bus.oam[(n * 4)..((n + 1) * 4)]);
+                self.sprite_count += 1;
+
+                if self.sprite_count > 8 {
+                    self.status.set_oam_overflow(true);
+                }
+
+                if n == 0 {
+                    self.sprite_zero_next = true;
+                }
+            }
+
+            self.eval_n += 1;
+
+            return;
+        }
+
+        // 9個目以降はnとmが両方進むハードウェアのバグを再現する
+        let y = self.bus.oam[n * 4 + self.eval_m];
+
+        if self.sprite_in_range(y) {
+            self.status.set_oam_overflow(true);
+            self.eval_n = 64;
+        } else {
+            self.eval_n += 1;
+            self.eval_m = (self.eval_m + 1) % 4;
+        }
+    }
+
+    fn fetch_sprites(&mut self) -> Result<()> {
+        if !self.rendering() || (self.cycles - 257) & 7 != 0 {
             return Ok(());
         }
 
-        let size = if self.ctrl.large_sprite() { 16 } else { 8 };
+        let slot = (self.cycles - 257) / 8;
 
-        let oam = Oam::new(&self.bus.oam[(i * 4)..((i + 1) * 4)], i == 0);
-        let cur_y = self.lines as u16;
-        let target_y = oam.y as u16;
+        // 制限を外したときは、9個目以降を最後のスロットでまとめて描く
+        let last = if slot == 7 {
+            self.sprite_count
+        } else {
+            slot + 1
+        };
+
+        for slot in slot..last.min(self.sprite_count) {
+            let oam = Oam::new(
+                &self.secondary_oam[(slot * 4)..((slot + 1) * 4)],
+                slot == 0 && self.sprite_zero_next,
+            );
 
-        if cur_y < target_y + size && target_y <= cur_y {
             self.draw_sprite(oam)?;
         }
 
@@ -424,33 +727,24 @@ impl Ppu {
 
         let cx = oam.x as usize;
 
-        let mut oam_colors = [Default::default(); 8];
-
         for (i, color) in colors.iter().enumerate() {
             let i = if oam.sprite_flag.x_flip() { 7 - i } else { i };
 
-            oam_colors[i] = OamColor {
-                color: *color,
-                behind: oam.sprite_flag.priority(),
-                zero: oam.zero,
-            };
-        }
+            // 先に取り込んだスプライトの不透明なドットが優先される
+            let dot = &mut self.oam_line[cx + i];
 
-        self.oam_line[cx..(cx + 8)].copy_from_slice(&oam_colors[..]);
+            if dot.color.transparent && !color.transparent {
+                *dot = OamColor {
+                    color: *color,
+                    behind: oam.sprite_flag.priority(),
+                    zero: oam.zero,
+                };
+            }
+        }
 
         Ok(())
     }
 
-    fn name_table_addr(&self) -> u16 {
-        match self.ctrl.name_table() {
-            0 => 0x2000,
-            1 => 0x2400,
-            2 => 0x2800,
-            3 => 0x2C00,
-            _ => 0,
-        }
-    }
-
     fn bg_pattern_table_addr(&self) -> u16 {
         match self.ctrl.bg_pattern_table() {
             false => 0x0000,
@@ -465,24 +759,16 @@ impl Ppu {
         }
     }
 
-    fn bg_attr(&self, tile_x: u8, tile_y: u8) -> Result<Attribute> {
-        let attr_x = tile_x / 4;
-        let attr_y = tile_y / 4;
-        let base_addr = self.name_table_addr() + 0x03C0;
-        let index_addr = attr_x as u16 + (attr_y as u16) * 8;
-        let addr = base_addr.wrapping_add(index_addr as u16);
+    fn bg_attr(&self) -> Result<Attribute> {
+        let addr = 0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07);
 
         let attr = Attribute(self.bus.read(addr)?);
 
         Ok(attr)
     }
 
-    fn bg_tile(&self, tile_x: u8, tile_y: u8) -> Result<u8> {
-        let base_addr = self.name_table_addr();
-        let index_addr = tile_x as u16 + (tile_y as u16) * 32;
-        let addr = base_addr.wrapping_add(index_addr as u16);
-
-        self.bus.read(addr)
+    fn bg_tile(&self) -> Result<u8> {
+        self.bus.read(0x2000 | (self.v & 0x0FFF))
     }
 
     #[bitmatch]
@@ -556,7 +842,30 @@ impl Ppu {
         colors
     }
 
+    fn forced_blank_pixel(&self) -> Result<Rgba<u8>> {
+        if self.forced_blank == ForcedBlank::ForceBlack {
+            return Ok(Rgba([0x00, 0x00, 0x00, 0xFF]));
+        }
+
+        // VRAMアドレスがパレットを指しているとその色が出る
+        let addr = self.v & 0x3FFF;
+        let addr = if addr >= 0x3F00 { addr } else { 0x3F00 };
+
+        Ok(Rgba(COLORS[self.bus.read(addr)? as usize & 0x3F]))
+    }
+
     fn put_pixels(&mut self) -> Result<()> {
+        if !self.rendering() {
+            let pixel = self.forced_blank_pixel()?;
+
+            self.pixels.put_pixel(self.x as u32, self.y as u32, pixel);
+
+            self.bg_line[self.x as usize] = Default::default();
+            self.oam_line[self.x as usize] = Default::default();
+
+            return Ok(());
+        }
+
         let backdrop = self.bus.read(0x3F00)? as usize;
         let mut pixel = Rgba(COLORS[backdrop]);
 
@@ -580,8 +889,15 @@ impl Ppu {
         }
 
         if self.mask.bg() && self.mask.oam() {
-            if sprite_color.zero && bg_color.transparent && sprite_color.color.transparent {
+            if sprite_color.zero
+                && !bg_color.transparent
+                && !sprite_color.color.transparent
+                && self.sprite_zero_hit_visible()
+                && !self.status.oam_0_hit()
+            {
                 self.status.set_oam_0_hit(true);
+
+                trace!(target: EVENTS, "SPRITE 0 HIT: LINE {} DOT {}", self.lines, self.cycles);
             }
         }
 
@@ -593,85 +909,315 @@ impl Ppu {
         Ok(())
     }
 
+    // X=255と、左端8ドットのどちらかがクリップされているところでは当たらない
+    fn sprite_zero_hit_visible(&self) -> bool {
+        if self.x == 255 {
+            return false;
+        }
+
+        self.x >= 8 || (self.mask.bg_clip() && self.mask.oam_clip())
+    }
+
     pub fn render(&mut self) -> Result<Vec<u8>> {
-        Ok(self.pixels.clone().into_raw())
+        let mut frame = if self.frame_blend {
+            blend_frames(&self.pixels, &self.prev_pixels)
+        } else {
+            self.pixels.clone().into_raw()
+        };
+
+        if self.debug_sprite_boxes {
+            self.draw_sprite_boxes(&mut frame);
+        }
+
+        Ok(frame)
+    }
+
+    // スプライトはOAMのY+1のラインから表示される
+    fn draw_sprite_boxes(&self, frame: &mut [u8]) {
+        let size = if self.ctrl.large_sprite() { 16 } else { 8 };
+
+        for i in 0..64 {
+            let sprite = self.sprite(i);
+
+            if sprite.y >= 0xEF {
+                continue;
+            }
+
+            let color = COLORS[0x11 + i % 12];
+
+            let top = sprite.y as usize + 1;
+            let left = sprite.x as usize;
+            let bottom = (top + size - 1).min(VISIBLE_HEIGHT - 1);
+            let right = (left + 7).min(VISIBLE_WIDTH - 1);
+
+            for y in top..=bottom {
+                for x in left..=right {
+                    if y != top && y != bottom && x != left && x != right {
+                        continue;
+                    }
+
+                    let offset = (y * VISIBLE_WIDTH + x) * 4;
+                    frame[offset..(offset + 4)].copy_from_slice(&color);
+                }
+            }
+        }
+    }
+
+    pub fn oam(&self) -> &[u8; 0x0100] {
+        &self.bus.oam
+    }
+
+    pub fn oam_mut(&mut self) -> &mut [u8; 0x0100] {
+        &mut self.bus.oam
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.bus.mirroring()
+    }
+
+    pub fn mirroring_override(&self) -> Option<Mirroring> {
+        self.bus.mirroring_override()
+    }
+
+    pub fn set_mirroring(&mut self, mirroring: Option<Mirroring>) {
+        self.bus.set_mirroring(mirroring);
+    }
+
+    pub fn peek_vram(&self, addr: u16) -> Result<u8> {
+        self.bus.read(addr & 0x3FFF)
+    }
+
+    pub fn palette_ram(&self) -> &[u8; 0x0020] {
+        &self.bus.palette
+    }
+
+    pub fn resolved_palette(&self) -> [[u8; 3]; 0x0020] {
+        let mut palette = [[0; 3]; 0x0020];
+
+        for (rgb, entry) in palette.iter_mut().zip(self.bus.palette.iter()) {
+            rgb.copy_from_slice(&COLORS[*entry as usize & 0x3F][..3]);
+        }
+
+        palette
+    }
+
+    pub fn sprite(&self, i: usize) -> OamEntry {
+        let data = &self.bus.oam[(i * 4)..((i + 1) * 4)];
+
+        OamEntry {
+            y: data[0],
+            tile: data[1],
+            attributes: data[2],
+            x: data[3],
+        }
     }
 
-    pub fn read_ctrl(&self) -> Result<u8> {
-        Ok(self.ctrl.0)
+    pub fn position(&self) -> (u16, u16) {
+        (self.lines as u16, self.cycles as u16)
     }
 
-    pub fn read_mask(&self) -> Result<u8> {
-        Ok(self.mask.0)
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn frame_blend(&self) -> bool {
+        self.frame_blend
+    }
+
+    pub fn set_frame_blend(&mut self, enabled: bool) {
+        if enabled && !self.frame_blend {
+            self.prev_pixels.copy_from_slice(&self.pixels);
+        }
+
+        self.frame_blend = enabled;
+    }
+
+    pub fn forced_blank(&self) -> ForcedBlank {
+        self.forced_blank
+    }
+
+    pub fn sprite_limit(&self) -> bool {
+        self.sprite_limit
+    }
+
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
+
+    pub fn debug_sprite_boxes(&self) -> bool {
+        self.debug_sprite_boxes
+    }
+
+    pub fn set_debug_sprite_boxes(&mut self, enabled: bool) {
+        self.debug_sprite_boxes = enabled;
+    }
+
+    pub fn set_forced_blank(&mut self, forced_blank: ForcedBlank) {
+        self.forced_blank = forced_blank;
+    }
+
+    pub fn read_open_bus(&self) -> Result<u8> {
+        Ok(self.io_bus.value())
+    }
+
+    fn refresh_io_bus(&mut self, data: u8, mask: u8) {
+        self.io_bus.refresh(data, mask, self.frames);
+    }
+
+    // デバッガ向けの読み込みで、wラッチやVBlankフラグ、読み込みバッファには触らない
+    pub fn peek_register(&self, addr: u16) -> Result<u8> {
+        let data = match 0x2000 + (addr & 0x0007) {
+            0x2002 => (self.status.0 & 0b11100000) | (self.io_bus.value() & 0b00011111),
+            0x2004 => self.oam_data(),
+            0x2007 if self.v & 0x3FFF >= 0x3F00 => self.bus.read(self.v & 0x3FFF)?,
+            0x2007 => self.data_buffer,
+            _ => self.io_bus.value(),
+        };
+
+        Ok(data)
     }
 
     pub fn read_status(&mut self) -> Result<u8> {
-        self.buffer.clear();
+        self.w = false;
 
-        let status = self.status.clone();
+        let status = self.status.0 & 0b11100000;
+
+        if self.status.irq_vblank() {
+            trace!(target: EVENTS, "VBLANK CLEAR ($2002): LINE {} DOT {}", self.lines, self.cycles);
+        }
 
         self.status.set_irq_vblank(false);
-        self.status.set_oam_0_hit(false);
-        self.status.set_oam_overflow(false);
 
-        Ok(status.0)
+        // 立つ1ドット前に読むとそのフレームはフラグが立たず、
+        // 立ったドットかその次に読むとフラグは読めるがNMIは起きない
+        if self.lines == VBLANK_LINE {
+            match self.cycles {
+                1 => self.suppress_vblank = true,
+                2 | 3 => self.nmi = false,
+                _ => {}
+            }
+        }
+
+        self.refresh_io_bus(status, 0b11100000);
+
+        Ok(self.io_bus.value())
     }
 
-    fn buffer_addr(&self) -> u16 {
-        if self.buffer.len() != 2 {
-            return 0;
+    fn register_timing(&self) -> RegisterTiming {
+        if self.warm_up {
+            RegisterTiming::WarmUp
+        } else if self.rendering() && (self.lines < VISIBLE_HEIGHT || self.lines == PRE_RENDER_LINE)
+        {
+            RegisterTiming::Rendering
+        } else {
+            RegisterTiming::Idle
+        }
+    }
+
+    fn increment_vram_addr(&mut self) {
+        // 描画中はvがスクロールに使われているので、XとYが同時に進んでしまう
+        if self.register_timing() == RegisterTiming::Rendering {
+            self.increment_x();
+            self.increment_y();
+
+            return;
         }
 
-        self.buffer[1] as u16 | ((self.buffer[0] as u16) << 8)
+        let inc = if self.ctrl.addr_inc_32() { 32 } else { 1 };
+
+        self.v = self.v.wrapping_add(inc) & 0x7FFF;
     }
 
-    fn set_buffer_addr(&mut self, addr: u16) {
-        self.buffer.clear();
-        self.buffer.push((addr >> 8) as u8);
-        self.buffer.push((addr & 0xFF) as u8);
+    // ドット1〜64はセカンダリOAMのクリア中で、読み込みには$FFが見える
+    fn oam_data(&self) -> u8 {
+        let clearing =
+            self.rendering() && self.lines < VISIBLE_HEIGHT && (1..=64).contains(&self.cycles);
+
+        if clearing {
+            0xFF
+        } else {
+            self.bus.oam[self.oam_addr as usize]
+        }
     }
 
-    pub fn read_oam_data(&self) -> Result<u8> {
-        // TODO OAM定義と実装
-        Ok(0)
+    pub fn read_oam_data(&mut self) -> Result<u8> {
+        let data = self.oam_data();
+
+        self.refresh_io_bus(data, 0xFF);
+
+        Ok(data)
     }
 
     pub fn read_vram_data(&mut self) -> Result<u8> {
-        let addr = self.buffer_addr();
-        let result = self.bus.read(addr)?;
+        let addr = self.v & 0x3FFF;
+
+        // パレットは即座に返るが、バッファには裏のネームテーブルが入る
+        let result = if addr >= 0x3F00 {
+            self.data_buffer = self.bus.read(addr - 0x1000)?;
+            self.bus.read(addr)?
+        } else {
+            let result = self.data_buffer;
+            self.data_buffer = self.bus.read(addr)?;
+            result
+        };
+
+        self.increment_vram_addr();
 
-        self.set_buffer_addr(addr + if self.ctrl.addr_inc_32() { 32 } else { 1 });
+        self.refresh_io_bus(result, 0xFF);
 
         Ok(result)
     }
 
-    pub fn read_oam_dma(&self) -> Result<u8> {
-        Ok(self.oam_addr)
-    }
+    pub fn write_register(&mut self, addr: u16, data: u8) -> Result<()> {
+        self.refresh_io_bus(data, 0xFF);
 
-    fn write_buffer(&mut self, data: u8) -> Result<()> {
-        if self.buffer.len() >= 2 {
-            self.buffer.clear();
-        }
+        let timing = self.register_timing();
 
-        self.buffer.push(data);
+        match (addr, timing) {
+            // 電源投入直後は最初のフレームが終わるまで書き込みが無視される
+            (0x2000 | 0x2001 | 0x2005 | 0x2006, RegisterTiming::WarmUp) => {
+                debug!(
+                    "IGNORE PPU WRITE DURING WARM-UP: {:#04X} = {:#02X}",
+                    addr, data
+                );
 
-        Ok(())
+                Ok(())
+            }
+            (0x2000, _) => self.write_ctrl(data),
+            (0x2001, _) => self.write_mask(data),
+            // 読み込み専用
+            (0x2002, _) => Ok(()),
+            (0x2003, _) => self.write_oam_addr(data),
+            (0x2004, RegisterTiming::Rendering) => {
+                // 描画中はOAMには書き込まれず、OAMADDRの上位6ビットだけが進む
+                self.oam_addr = self.oam_addr.wrapping_add(4);
+
+                Ok(())
+            }
+            (0x2004, _) => self.write_oam_data(data),
+            (0x2005, _) => self.write_scroll(data),
+            (0x2006, _) => self.write_vram_addr(data),
+            (0x2007, _) => self.write_vram_data(data),
+            _ => Ok(()),
+        }
     }
 
-    pub fn write_ctrl(&mut self, data: u8) -> Result<()> {
+    fn write_ctrl(&mut self, data: u8) -> Result<()> {
         let ctrl = Ctrl(data);
 
-        if !self.ctrl.ie_nmi() && ctrl.ie_nmi() && self.mode == Mode::VBlank {
+        if !self.ctrl.ie_nmi() && ctrl.ie_nmi() && self.status.irq_vblank() {
             self.nmi = true;
+
+            trace!(target: EVENTS, "NMI ($2000): LINE {} DOT {}", self.lines, self.cycles);
         }
 
         self.ctrl = ctrl;
+        self.t = (self.t & !0x0C00) | ((data as u16 & 0x03) << 10);
 
         Ok(())
     }
 
-    pub fn write_mask(&mut self, data: u8) -> Result<()> {
+    fn write_mask(&mut self, data: u8) -> Result<()> {
         self.mask = Mask(data);
 
         debug!("WRITE MASK: {:?}", self.mask);
@@ -679,13 +1225,7 @@ impl Ppu {
         Ok(())
     }
 
-    pub fn write_status(&mut self, data: u8) -> Result<()> {
-        self.status = Status(data);
-
-        Ok(())
-    }
-
-    pub fn write_oam_addr(&mut self, data: u8) -> Result<()> {
+    fn write_oam_addr(&mut self, data: u8) -> Result<()> {
         self.oam_addr = data;
 
         trace!("WRITE OAM ADDR: {:#02X}", data);
@@ -693,57 +1233,715 @@ impl Ppu {
         Ok(())
     }
 
-    pub fn write_oam_data(&mut self, data: u8) -> Result<()> {
+    fn write_oam_data(&mut self, data: u8) -> Result<()> {
         self.bus.oam[self.oam_addr as usize] = data;
 
         trace!("WRITE OAM: {:#04X} = {:#02X}", self.oam_addr, data);
 
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+
         Ok(())
     }
 
-    pub fn write_scroll(&mut self, data: u8) -> Result<()> {
-        self.write_buffer(data)?;
-
-        if self.buffer.len() == 2 {
-            self.scroll_x = self.buffer[0];
-            self.scroll_y = self.buffer[1];
+    fn write_scroll(&mut self, data: u8) -> Result<()> {
+        if self.w {
+            self.t =
+                (self.t & !0x73E0) | ((data as u16 & 0x07) << 12) | ((data as u16 & 0xF8) << 2);
+        } else {
+            self.t = (self.t & !0x001F) | (data as u16 >> 3);
+            self.fine_x = data & 0x07;
         }
 
+        self.w = !self.w;
+
         trace!(
-            "WRITE SCROLL: {} ({},{})",
+            "WRITE SCROLL: {} (T: {:#06X}, X: {})",
             data,
-            self.scroll_x,
-            self.scroll_y
+            self.t,
+            self.fine_x
         );
 
         Ok(())
     }
 
-    pub fn write_vram_addr(&mut self, data: u8) -> Result<()> {
-        self.write_buffer(data)
+    fn write_vram_addr(&mut self, data: u8) -> Result<()> {
+        // 2回目の書き込みでvに反映されるので、描画中でも以降のスクロールが変わる
+        if self.w {
+            self.t = (self.t & 0xFF00) | data as u16;
+            self.v = self.t;
+        } else {
+            self.t = (self.t & 0x00FF) | ((data as u16 & 0x3F) << 8);
+        }
+
+        self.w = !self.w;
+
+        trace!("WRITE VRAM ADDR: {} (T: {:#06X})", data, self.t);
+
+        Ok(())
     }
 
-    pub fn write_vram_data(&mut self, data: u8) -> Result<()> {
-        let addr = self.buffer_addr();
+    fn write_vram_data(&mut self, data: u8) -> Result<()> {
+        let addr = self.v & 0x3FFF;
         self.bus.write(addr, data)?;
 
         debug!("WRITE VRAM: {:#04X} = {:#02X}", addr, data);
 
-        self.set_buffer_addr(addr + if self.ctrl.addr_inc_32() { 32 } else { 1 });
+        self.increment_vram_addr();
 
         Ok(())
     }
 
     pub fn write_oam_dma(&mut self, data: u8) -> Result<()> {
-        self.dma_addr = (data as u16) << 8;
+        self.bus.oam[self.oam_addr as usize] = data;
 
-        self.bus.request_dma(self.dma_addr, self.oam_addr)?;
+        trace!("WRITE OAM DMA: {:#04X} = {:#02X}", self.oam_addr, data);
 
-        debug!(
-            "REQUEST DMA: {:#04X} -> {:#04X}",
-            self.dma_addr, self.oam_addr
-        );
+        self.oam_addr = self.oam_addr.wrapping_add(1);
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{mmc::new_mmc, rom::Rom};
+
+    // タイル0は透明、タイル1は全部カラー1
+    fn chr() -> Vec<u8> {
+        let mut chr = vec![0; 0x2000];
+
+        chr[0x10..0x18].fill(0xFF);
+
+        chr
+    }
+
+    // ウォームアップを済ませて、スプライトを全部画面外に置いたPPU
+    fn ppu(accuracy: Accuracy) -> Ppu {
+        let rom = Rom::from_parts(vec![0; 0x4000], chr(), 0, Mirroring::Horizontal).unwrap();
+        let mmc = Rc::new(RefCell::new(new_mmc(rom).unwrap()));
+
+        let mut ppu = Ppu::new(PpuBus::new(mmc), accuracy);
+
+        ppu.warm_up = false;
+        ppu.bus.oam.fill(0xFF);
+
+        ppu
+    }
+
+    fn run_until(ppu: &mut Ppu, line: usize, dot: usize) {
+        while (ppu.lines, ppu.cycles) != (line, dot) {
+            ppu.tick().unwrap();
+        }
+    }
+
+    #[test]
+    fn evaluation_copies_in_range_sprites_to_secondary_oam() {
+        for accuracy in [Accuracy::Fast, Accuracy::Balanced, Accuracy::Cycle] {
+            let mut ppu = ppu(accuracy);
+
+            ppu.mask = Mask(0x18);
+
+            // ライン10に掛かるのは2, 5, 9番
+            let sprites = [
+                (2, [5, 0x01, 0x00, 0x10]),
+                (5, [10, 0x02, 0x41, 0x20]),
+                (7, [11, 0x03, 0x00, 0x30]),
+                (9, [3, 0x04, 0x80, 0x40]),
+            ];
+
+            for (n, sprite) in sprites.iter() {
+                ppu.bus.oam[(n * 4)..((n + 1) * 4)].copy_from_slice(sprite);
+            }
+
+            run_until(&mut ppu, 10, 257);
+
+            let mut expected = [0xFF; 32];
+
+            expected[0..4].copy_from_slice(&sprites[0].1);
+            expected[4..8].copy_from_slice(&sprites[1].1);
+            expected[8..12].copy_from_slice(&sprites[3].1);
+
+            assert_eq!(ppu.secondary_oam[..32], expected, "{:?}", accuracy);
+            assert_eq!(ppu.sprite_count, 3, "{:?}", accuracy);
+            assert!(!ppu.sprite_zero_next, "{:?}", accuracy);
+        }
+    }
+
+    #[test]
+    fn sprite_zero_hit_needs_opaque_pixels_in_the_visible_area() {
+        // (背景のタイル, スプライトのタイル, スプライトのX, マスク, 当たるか)
+        let cases = [
+            (1, 1, 40, 0x1E, true),
+            (0, 1, 40, 0x1E, false),
+            (1, 0, 40, 0x1E, false),
+            (1, 1, 255, 0x1E, false),
+            (1, 1, 0, 0x18, false),
+            (1, 1, 0, 0x1A, false),
+            (1, 1, 0, 0x1E, true),
+        ];
+
+        for &(bg_tile, sprite_tile, x, mask, hit) in cases.iter() {
+            let mut ppu = ppu(Accuracy::Cycle);
+
+            ppu.mask = Mask(mask);
+            ppu.bus.vram[..0x03C0].fill(bg_tile);
+            ppu.bus.oam[..4].copy_from_slice(&[20, sprite_tile, 0x00, x]);
+
+            run_until(&mut ppu, 30, 0);
+
+            assert_eq!(
+                ppu.status.oam_0_hit(),
+                hit,
+                "bg {} sprite {} x {} mask {:#04X}",
+                bg_tile,
+                sprite_tile,
+                x,
+                mask
+            );
+        }
+    }
+
+    thread_local! {
+        static EVENT_LOG: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // rnes::ppu::events だけをスレッドごとに集めるロガー
+    struct EventLogger;
+
+    impl log::Log for EventLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.target() == EVENTS
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                EVENT_LOG.with(|events| events.borrow_mut().push(record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_events<F: FnOnce()>(f: F) -> Vec<String> {
+        static LOGGER: EventLogger = EventLogger;
+        static INIT: std::sync::Once = std::sync::Once::new();
+
+        INIT.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+
+        EVENT_LOG.with(|events| events.borrow_mut().clear());
+
+        f();
+
+        EVENT_LOG.with(|events| events.take())
+    }
+
+    #[test]
+    fn sprite_zero_hit_event_has_the_hit_dot() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        ppu.mask = Mask(0x1E);
+        ppu.bus.vram[..0x03C0].fill(1);
+        ppu.bus.oam[..4].copy_from_slice(&[20, 1, 0x00, 40]);
+
+        let events = capture_events(|| run_until(&mut ppu, 242, 0));
+
+        let hits = events
+            .iter()
+            .filter(|event| event.starts_with("SPRITE 0 HIT"))
+            .collect::<Vec<_>>();
+
+        // Y=20のスプライトは次のライン21から、X=40はドット41に描かれる
+        assert_eq!(hits, ["SPRITE 0 HIT: LINE 21 DOT 41"]);
+        assert!(events
+            .iter()
+            .any(|event| event == "VBLANK SET: LINE 241 DOT 1"));
+    }
+
+    #[test]
+    fn frame_blend_mixes_the_previous_frame() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        ppu.set_frame_blend(true);
+
+        // 描画を無効にして、背景色だけのフレームを2枚作る
+        for &color in [0x0F, 0x30].iter() {
+            ppu.bus.palette[0] = color;
+
+            let frames = ppu.frames;
+
+            while ppu.frames == frames {
+                ppu.tick().unwrap();
+            }
+        }
+
+        let black = COLORS[0x0F];
+        let white = COLORS[0x30];
+
+        let frame = ppu.render().unwrap();
+
+        for c in 0..3 {
+            let expected = ((black[c] as u16 + white[c] as u16) / 2) as u8;
+
+            assert_eq!(frame[c], expected);
+            assert_eq!(frame[frame.len() - 4 + c], expected);
+        }
+    }
+
+    #[test]
+    fn sprite_flags_stay_set_until_pre_render_dot_1() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        ppu.mask = Mask(0x1E);
+        ppu.bus.vram[..0x03C0].fill(1);
+
+        // スプライト0で当たり判定、9個並べてオーバーフローを起こす
+        for n in 0..9 {
+            ppu.bus.oam[(n * 4)..((n + 1) * 4)].copy_from_slice(&[20, 1, 0x00, 40]);
+        }
+
+        run_until(&mut ppu, 30, 0);
+
+        // $2002を読んでも消えない
+        for _ in 0..2 {
+            assert_eq!(ppu.read_status().unwrap() & 0x60, 0x60);
+        }
+
+        run_until(&mut ppu, PRE_RENDER_LINE, 1);
+
+        assert!(ppu.status.oam_0_hit());
+        assert!(ppu.status.oam_overflow());
+
+        ppu.tick().unwrap();
+
+        assert!(!ppu.status.oam_0_hit());
+        assert!(!ppu.status.oam_overflow());
+    }
+
+    #[test]
+    fn palette_read_fills_the_buffer_from_the_name_table_below() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        ppu.bus.write(0x2F05, 0x77).unwrap();
+        ppu.bus.write(0x3F05, 0x21).unwrap();
+
+        ppu.write_register(0x2006, 0x3F).unwrap();
+        ppu.write_register(0x2006, 0x05).unwrap();
+
+        assert_eq!(ppu.read_vram_data().unwrap(), 0x21);
+
+        ppu.write_register(0x2006, 0x20).unwrap();
+        ppu.write_register(0x2006, 0x00).unwrap();
+
+        // バッファには$3F05ではなく、裏にある$2F05が入っている
+        assert_eq!(ppu.read_vram_data().unwrap(), 0x77);
+    }
+
+    #[test]
+    fn forced_blank_output_follows_the_toggle() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        ppu.bus.palette[0x00] = 0x21;
+        ppu.bus.palette[0x05] = 0x16;
+
+        // (設定, vの指すアドレス, 出る色)
+        let cases = [
+            (ForcedBlank::HardwareAccurate, 0x2000, COLORS[0x21]),
+            (ForcedBlank::HardwareAccurate, 0x3F05, COLORS[0x16]),
+            (ForcedBlank::ForceBlack, 0x3F05, [0x00, 0x00, 0x00, 0xFF]),
+        ];
+
+        for &(forced_blank, v, color) in cases.iter() {
+            ppu.set_forced_blank(forced_blank);
+            ppu.v = v;
+
+            let frames = ppu.frames;
+
+            while ppu.frames == frames {
+                ppu.tick().unwrap();
+            }
+
+            let frame = ppu.render().unwrap();
+
+            assert_eq!(frame[..4], color, "{:?} {:#06X}", forced_blank, v);
+            assert_eq!(
+                frame[frame.len() - 4..],
+                color,
+                "{:?} {:#06X}",
+                forced_blank,
+                v
+            );
+        }
+    }
+
+    #[test]
+    fn position_and_frame_count_follow_the_ticks() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        for _ in 0..(WIDTH * 5 + 10) {
+            ppu.tick().unwrap();
+        }
+
+        assert_eq!(ppu.position(), (5, 10));
+        assert_eq!(ppu.frame_count(), 0);
+
+        for _ in 0..(WIDTH * (HEIGHT - 5) - 10) {
+            ppu.tick().unwrap();
+        }
+
+        assert_eq!(ppu.position(), (0, 0));
+        assert_eq!(ppu.frame_count(), 1);
+
+        // 奇数フレームで描画が有効だと1ドット短くなる
+        ppu.mask = Mask(0x08);
+
+        for _ in 0..(WIDTH * HEIGHT - 1) {
+            ppu.tick().unwrap();
+        }
+
+        assert_eq!(ppu.position(), (0, 0));
+        assert_eq!(ppu.frame_count(), 2);
+    }
+
+    #[test]
+    fn mid_frame_2006_pair_splits_the_scroll() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        // 水平ミラーリングなので、$2000は全部タイル1、$2800は全部タイル0
+        ppu.bus.vram.fill(0);
+        ppu.bus.vram[..0x03C0].fill(1);
+        ppu.bus.palette[0x00] = 0x0F;
+        ppu.bus.palette[0x01] = 0x30;
+        ppu.mask = Mask(0x0A);
+
+        run_until(&mut ppu, 120, 300);
+
+        // HBlank中に$2006を2回書いて、下半分を$2800から描かせる
+        ppu.write_register(0x2006, 0x28).unwrap();
+        ppu.write_register(0x2006, 0x00).unwrap();
+
+        run_until(&mut ppu, 0, 0);
+
+        let frame = ppu.render().unwrap();
+        let pixel = |x: usize, y: usize| frame[(y * VISIBLE_WIDTH + x) * 4..][..4].to_vec();
+
+        assert_eq!(pixel(10, 50), COLORS[0x30]);
+        assert_eq!(pixel(10, 120), COLORS[0x30]);
+        assert_eq!(pixel(10, 121), COLORS[0x0F]);
+        assert_eq!(pixel(200, 230), COLORS[0x0F]);
+    }
+
+    #[test]
+    fn nonzero_oamaddr_corrupts_oam_at_frame_start() {
+        // (OAMADDR, 先頭にコピーされるか)
+        for &(oam_addr, corrupted) in [(0x21, true), (0x04, false)].iter() {
+            let mut ppu = ppu(Accuracy::Cycle);
+
+            for (i, byte) in ppu.bus.oam.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+
+            run_until(&mut ppu, PRE_RENDER_LINE, 1);
+
+            ppu.mask = Mask(0x18);
+            ppu.oam_addr = oam_addr;
+
+            ppu.tick().unwrap();
+
+            let expected = if corrupted {
+                [0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27]
+            } else {
+                [0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07]
+            };
+
+            assert_eq!(ppu.bus.oam[..8], expected, "{:#04X}", oam_addr);
+            assert_eq!(ppu.bus.oam[0x20], 0x20, "{:#04X}", oam_addr);
+        }
+    }
+
+    #[test]
+    fn disabling_rendering_stops_v_and_the_odd_frame_skip() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        ppu.mask = Mask(0x08);
+
+        run_until(&mut ppu, 10, 100);
+
+        let v = ppu.v;
+
+        run_until(&mut ppu, 10, 120);
+
+        assert_ne!(ppu.v, v);
+
+        // ドット120で描画を止めると、それ以降vは進まない
+        ppu.write_register(0x2001, 0x00).unwrap();
+
+        let v = ppu.v;
+
+        run_until(&mut ppu, 12, 300);
+
+        assert_eq!(ppu.v, v);
+
+        // 奇数フレームでも、プリレンダーラインの途中で止めればドットは飛ばさない
+        ppu.frames = 1;
+        ppu.mask = Mask(0x08);
+
+        run_until(&mut ppu, PRE_RENDER_LINE, 300);
+
+        ppu.write_register(0x2001, 0x00).unwrap();
+
+        let mut dots = 0;
+
+        while ppu.position() != (0, 0) {
+            ppu.tick().unwrap();
+            dots += 1;
+        }
+
+        assert_eq!(dots, WIDTH - 300);
+    }
+
+    #[test]
+    fn ctrl_name_table_bits_land_in_t() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        ppu.t = 0x7FFF & !0x0C00;
+
+        ppu.write_register(0x2000, 0x03).unwrap();
+
+        assert_eq!(ppu.t, 0x7FFF);
+
+        // 他のビットはそのままで、$2000の下位2ビットだけが入る
+        ppu.write_register(0x2000, 0xFD).unwrap();
+
+        assert_eq!(ppu.t, 0x7FFF & !0x0800);
+        assert!(!ppu.w);
+    }
+
+    #[test]
+    fn mode_follows_the_dot_numbering() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        let cases = [
+            (0, Mode::Idle),
+            (1, Mode::Drawing),
+            (256, Mode::Drawing),
+            (257, Mode::OamScan),
+            (320, Mode::OamScan),
+            (321, Mode::PostIdle),
+            (340, Mode::PostIdle),
+        ];
+
+        for (dot, mode) in cases {
+            run_until(&mut ppu, 10, dot);
+
+            // tickがそのドットを処理したときのモード
+            ppu.tick().unwrap();
+
+            assert_eq!(ppu.mode, mode, "dot {}", dot);
+        }
+
+        assert_eq!(ppu.position(), (11, 0));
+    }
+
+    #[test]
+    fn status_read_around_vblank_set_suppresses_the_flag_or_nmi() {
+        // (読むドット, 読めるVBlank, NMIが起きるか)
+        // ドットはまだ処理していない次のドットなので、1で読むとフラグが立つ直前になる
+        let cases = [
+            (0, 0x00, true),
+            (1, 0x00, false),
+            (2, 0x80, false),
+            (3, 0x80, false),
+            (4, 0x80, true),
+        ];
+
+        for &(dot, vblank, nmi) in cases.iter() {
+            let mut ppu = ppu(Accuracy::Cycle);
+
+            ppu.ctrl = Ctrl(0x80);
+
+            run_until(&mut ppu, VBLANK_LINE, dot);
+
+            assert_eq!(ppu.read_status().unwrap() & 0x80, vblank, "dot {}", dot);
+
+            run_until(&mut ppu, VBLANK_LINE, 10);
+
+            assert_eq!(ppu.nmi, nmi, "dot {}", dot);
+            assert_eq!(ppu.status.irq_vblank(), dot == 0, "dot {}", dot);
+        }
+    }
+
+    #[test]
+    fn register_writes_follow_the_timing_gate() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        ppu.warm_up = true;
+
+        // ウォームアップ中は$2000/$2001/$2005/$2006が無視され、$2003と$2004は通る
+        for &addr in [0x2000, 0x2001, 0x2005, 0x2006].iter() {
+            ppu.write_register(addr, 0xFF).unwrap();
+        }
+
+        ppu.write_register(0x2003, 0x11).unwrap();
+        ppu.write_register(0x2004, 0xAB).unwrap();
+
+        assert_eq!(
+            (ppu.ctrl.0, ppu.mask.0, ppu.t, ppu.w),
+            (0x00, 0x00, 0x0000, false)
+        );
+        assert_eq!((ppu.bus.oam[0x11], ppu.oam_addr), (0xAB, 0x12));
+
+        // プリレンダーラインのドット1で解ける
+        run_until(&mut ppu, PRE_RENDER_LINE, 1);
+        ppu.write_register(0x2000, 0x01).unwrap();
+        assert_eq!(ppu.ctrl.0, 0x00);
+
+        ppu.tick().unwrap();
+        ppu.write_register(0x2000, 0x01).unwrap();
+        assert_eq!(ppu.ctrl.0, 0x01);
+
+        // 描画中の$2004はOAMに書かず、OAMADDRを4進める
+        ppu.write_register(0x2001, 0x18).unwrap();
+        run_until(&mut ppu, 10, 100);
+
+        ppu.oam_addr = 0x20;
+        ppu.write_register(0x2004, 0xCD).unwrap();
+
+        assert_eq!((ppu.bus.oam[0x20], ppu.oam_addr), (0xFF, 0x24));
+
+        // VBlank中は普通に書ける
+        run_until(&mut ppu, VBLANK_LINE + 1, 0);
+
+        ppu.oam_addr = 0x20;
+        ppu.write_register(0x2004, 0xCD).unwrap();
+
+        assert_eq!((ppu.bus.oam[0x20], ppu.oam_addr), (0xCD, 0x21));
+    }
+
+    #[test]
+    fn resolved_palette_maps_entries_through_the_color_table() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        ppu.write_register(0x2006, 0x3F).unwrap();
+        ppu.write_register(0x2006, 0x00).unwrap();
+
+        for &color in [0x0F, 0x16, 0x2A, 0x30].iter() {
+            ppu.write_register(0x2007, color).unwrap();
+        }
+
+        // $3F10は$3F00のミラー
+        ppu.write_register(0x2006, 0x3F).unwrap();
+        ppu.write_register(0x2006, 0x10).unwrap();
+        ppu.write_register(0x2007, 0x21).unwrap();
+
+        assert_eq!(ppu.palette_ram()[..4], [0x21, 0x16, 0x2A, 0x30]);
+
+        let palette = ppu.resolved_palette();
+
+        assert_eq!(palette[0], COLORS[0x21][..3]);
+        assert_eq!(palette[1], COLORS[0x16][..3]);
+        assert_eq!(palette[2], COLORS[0x2A][..3]);
+        assert_eq!(palette[3], COLORS[0x30][..3]);
+    }
+
+    #[test]
+    fn scroll_write_between_2006_writes_shares_the_latch() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        // $2006と$2005はwラッチとtを共有するので、$2005が2回目の書き込みになる
+        ppu.write_register(0x2006, 0x21).unwrap();
+        ppu.write_register(0x2005, 0x5E).unwrap();
+
+        assert!(!ppu.w);
+        assert_eq!(ppu.t, 0x6160);
+        assert_eq!(ppu.v, 0x0000);
+
+        // 続く$2006は上位バイトとして扱われ、次の書き込みでvに反映される
+        ppu.write_register(0x2006, 0x08).unwrap();
+
+        assert_eq!(ppu.t, 0x0860);
+        assert_eq!(ppu.v, 0x0000);
+
+        ppu.write_register(0x2006, 0x40).unwrap();
+
+        assert!(!ppu.w);
+        assert_eq!(ppu.v, 0x0840);
+    }
+
+    #[test]
+    fn debug_sprite_boxes_outline_the_sprite_rectangle() {
+        let mut ppu = ppu(Accuracy::Cycle);
+
+        ppu.bus.oam.fill(0xFF);
+        ppu.bus.oam[..8].copy_from_slice(&[0x3F, 0x00, 0x00, 0x20, 0x7F, 0x00, 0x00, 0xFC]);
+
+        let plain = ppu.render().unwrap();
+
+        ppu.set_debug_sprite_boxes(true);
+
+        let frame = ppu.render().unwrap();
+        let pixel = |x: usize, y: usize| frame[(y * VISIBLE_WIDTH + x) * 4..][..4].to_vec();
+
+        // スプライト0はY=$3F+1からの8x8、OAM番号ごとに色が変わる
+        for &(x, y) in [(32, 64), (39, 64), (32, 71), (39, 71), (35, 64), (32, 68)].iter() {
+            assert_eq!(pixel(x, y), COLORS[0x11], "({}, {})", x, y);
+        }
+
+        for &(x, y) in [(35, 67), (31, 64), (40, 64), (32, 63), (32, 72)].iter() {
+            assert_ne!(pixel(x, y), COLORS[0x11], "({}, {})", x, y);
+        }
+
+        // 右端を越える箱は画面端で切れる
+        assert_eq!(pixel(252, 128), COLORS[0x12]);
+        assert_eq!(pixel(255, 132), COLORS[0x12]);
+
+        // 8x16では下辺が8ライン下がる
+        ppu.ctrl = Ctrl(0x20);
+
+        let frame = ppu.render().unwrap();
+
+        assert_eq!(frame[(79 * VISIBLE_WIDTH + 35) * 4..][..4], COLORS[0x11]);
+        assert_ne!(frame[(71 * VISIBLE_WIDTH + 35) * 4..][..4], COLORS[0x11]);
+
+        ppu.set_debug_sprite_boxes(false);
+
+        assert_eq!(ppu.render().unwrap(), plain);
+    }
+
+    #[test]
+    fn oam_data_reads_ff_while_secondary_oam_is_cleared() {
+        let cases = [
+            (10, 0, 0x18, 0x42),
+            (10, 1, 0x18, 0xFF),
+            (10, 20, 0x18, 0xFF),
+            (10, 64, 0x18, 0xFF),
+            (10, 65, 0x18, 0x42),
+            // 描画していなければ、またはプリレンダーラインではクリアしない
+            (10, 20, 0x00, 0x42),
+            (PRE_RENDER_LINE, 20, 0x18, 0x42),
+        ];
+
+        for &(line, dot, mask, data) in cases.iter() {
+            let mut ppu = ppu(Accuracy::Cycle);
+
+            ppu.bus.oam.fill(0x42);
+            ppu.mask = Mask(mask);
+
+            run_until(&mut ppu, line, dot);
+
+            assert_eq!(
+                ppu.read_oam_data().unwrap(),
+                data,
+                "line {} dot {}",
+                line,
+                dot
+            );
+            assert_eq!(ppu.peek_register(0x2004).unwrap(), data);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::rom::CpuPpuTimingMode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub fn cpu_hz(&self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    // マスタークロックを何分周してCPUとPPUを動かすか
+    pub fn cpu_divider(&self) -> u64 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    pub fn ppu_divider(&self) -> u64 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal | Region::Dendy => 50.0070,
+        }
+    }
+}
+
+impl From<&CpuPpuTimingMode> for Region {
+    fn from(mode: &CpuPpuTimingMode) -> Self {
+        match mode {
+            CpuPpuTimingMode::Rp2C07 => Region::Pal,
+            CpuPpuTimingMode::Umc6527p => Region::Dendy,
+            _ => Region::Ntsc,
+        }
+    }
+}
+
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Region::Ntsc => write!(f, "NTSC"),
+            Region::Pal => write!(f, "PAL"),
+            Region::Dendy => write!(f, "Dendy"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_constants_match_the_documented_values() {
+        assert_eq!(Region::Ntsc.cpu_hz(), 1_789_773.0);
+        assert_eq!(Region::Pal.cpu_hz(), 1_662_607.0);
+
+        // (地域, マスタークロック, 1フレームのCPUサイクル)
+        let cases = [
+            (Region::Ntsc, 21_477_272.0, 29_780.5),
+            (Region::Pal, 26_601_712.0, 33_247.5),
+        ];
+
+        for &(region, master_hz, frame_cycles) in cases.iter() {
+            let cpu_hz = master_hz / region.cpu_divider() as f64;
+
+            assert!((region.cpu_hz() - cpu_hz).abs() < 1.0, "{}", region);
+            assert!(
+                (region.frame_rate() - region.cpu_hz() / frame_cycles).abs() < 0.001,
+                "{}",
+                region
+            );
+        }
+    }
+}
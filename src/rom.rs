@@ -1,15 +1,23 @@
 use anyhow::{bail, Context, Result};
 use bitfield::bitfield;
 use core::fmt;
+use log::warn;
+
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use std::{
-    fmt::{Debug, Formatter},
+    fmt::{Debug, Display, Formatter},
     fs::File,
     io::BufReader,
     io::Read,
 };
 
+use crate::{hash, region::Region};
+
+const INST_ROM_SIZE: usize = 0x2000;
+const PROM_SIZE: usize = 0x0020;
+const PLAYCHOICE_SIZE: usize = INST_ROM_SIZE + PROM_SIZE;
+
 bitfield! {
     pub struct Flag1(u8);
     impl Debug;
@@ -24,14 +32,15 @@ bitfield! {
     pub struct Flag2(u8);
     impl Debug;
     u16, mapper_type_middle, _: 7, 4;
+    pub u8, nes2_identifier, _: 3, 2;
     pub u8, into ConsoleType, console_type, _: 1, 0;
 }
 
 bitfield! {
     struct MapperSubmapper(u8);
     impl Debug;
-    u16, mapper_type_high, _: 7, 4;
-    u8, into SubmapperType, submapper_type, _: 3, 0;
+    u8, submapper_type, _: 7, 4;
+    u16, mapper_type_high, _: 3, 0;
 }
 
 bitfield! {
@@ -71,7 +80,31 @@ bitfield! {
 bitfield! {
     pub struct ExtendedConsoleType(u8);
     impl Debug;
-    console_type, _: 3, 0;
+    pub u8, into ExtendedConsole, console_type, _: 3, 0;
+}
+
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendedConsole {
+    Regular = 0x0,
+    VsSystem = 0x1,
+    Playchoice10 = 0x2,
+    FamicloneDecimalMode = 0x3,
+    EpsmModule = 0x4,
+    Vt01 = 0x5,
+    Vt02 = 0x6,
+    Vt03 = 0x7,
+    Vt09 = 0x8,
+    Vt32 = 0x9,
+    Vt369 = 0xA,
+    Um6578 = 0xB,
+    FamicomNetworkSystem = 0xC,
+    Unknown,
+}
+
+impl From<u8> for ExtendedConsole {
+    fn from(v: u8) -> Self {
+        FromPrimitive::from_u8(v).unwrap_or(ExtendedConsole::Unknown)
+    }
 }
 
 bitfield! {
@@ -98,17 +131,44 @@ impl From<u8> for ConsoleType {
 pub enum MapperType {
     Mmc0 = 0,
     Mmc1 = 1,
+    Uxrom = 2,
+    Cnrom = 3,
+    Gxrom = 66,
     Unknown,
 }
 
-#[derive(FromPrimitive, Debug)]
+impl MapperType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MapperType::Mmc0 => "NROM",
+            MapperType::Mmc1 => "MMC1",
+            MapperType::Uxrom => "UxROM",
+            MapperType::Cnrom => "CNROM",
+            MapperType::Gxrom => "GxROM",
+            MapperType::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SubmapperType {
-    Unknown,
+    Default,
+    // SEROM, SHROM, SH1ROM
+    Mmc1FixedPrg,
+    UxromNoBusConflicts,
+    UxromBusConflicts,
+    Unknown(u8),
 }
 
-impl From<u8> for SubmapperType {
-    fn from(v: u8) -> Self {
-        FromPrimitive::from_u8(v).unwrap_or(SubmapperType::Unknown)
+impl SubmapperType {
+    fn new(mapper: &MapperType, v: u8) -> Self {
+        match (mapper, v) {
+            (_, 0) => SubmapperType::Default,
+            (MapperType::Mmc1, 5) => SubmapperType::Mmc1FixedPrg,
+            (MapperType::Uxrom, 1) => SubmapperType::UxromNoBusConflicts,
+            (MapperType::Uxrom, 2) => SubmapperType::UxromBusConflicts,
+            _ => SubmapperType::Unknown(v),
+        }
     }
 }
 
@@ -139,6 +199,56 @@ impl From<u8> for ExpansionDeviceType {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen,
+}
+
+impl Display for Mirroring {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Mirroring::Horizontal => write!(f, "horizontal"),
+            Mirroring::Vertical => write!(f, "vertical"),
+            Mirroring::SingleScreenLower => write!(f, "single-screen (lower)"),
+            Mirroring::SingleScreenUpper => write!(f, "single-screen (upper)"),
+            Mirroring::FourScreen => write!(f, "four-screen"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RomInfo {
+    pub mapper: String,
+    pub prg_kb: usize,
+    pub chr_kb: usize,
+    pub mirroring: Mirroring,
+    pub has_battery: bool,
+    pub region: Region,
+}
+
+impl Display for RomInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, PRG {}KB, CHR {}KB, {} mirroring, {}, {}",
+            self.mapper,
+            self.prg_kb,
+            self.chr_kb,
+            self.mirroring,
+            if self.has_battery {
+                "battery"
+            } else {
+                "no battery"
+            },
+            self.region
+        )
+    }
+}
+
 pub struct Rom {
     pub prg_size: usize,
     pub chr_size: usize,
@@ -155,6 +265,9 @@ pub struct Rom {
     pub extended_console_type: ExtendedConsoleType,
     pub expansion_device_type: ExpansionDeviceType,
 
+    inst_rom_size: usize,
+    prom_size: usize,
+
     data: Vec<u8>,
 }
 
@@ -166,7 +279,7 @@ impl Default for Rom {
             flag1: Flag1(0),
             flag2: Flag2(0),
             mapper: MapperType::Unknown,
-            submapper: SubmapperType::Unknown,
+            submapper: SubmapperType::Default,
             prg_ram_size: 0,
             prg_nvram_size: 0,
             chr_ram_size: 0,
@@ -176,6 +289,9 @@ impl Default for Rom {
             extended_console_type: ExtendedConsoleType(0),
             expansion_device_type: ExpansionDeviceType::Unspecified,
 
+            inst_rom_size: 0,
+            prom_size: 0,
+
             data: Vec::new(),
         }
     }
@@ -202,6 +318,8 @@ impl Debug for Rom {
             .field("prg", &self.prg().len())
             .field("chr", &self.chr().len())
             .field("trainer", &self.trainer())
+            .field("inst_rom", &self.inst_rom().map(|d| d.len()))
+            .field("prom", &self.prom().map(|d| d.len()))
             .field("misc", &self.misc().len())
             .finish()
     }
@@ -209,9 +327,81 @@ impl Debug for Rom {
 
 impl Rom {
     pub fn new(reader: &mut BufReader<File>) -> Result<Rom> {
-        let mut rom = Rom::default();
+        let mut data = Vec::new();
+
+        reader.read_to_end(&mut data)?;
+
+        Self::load(data, false)
+    }
+
+    // CHR-ROMが少しだけ足りないダンプは多いので、0で埋めて読み込む
+    pub fn new_lenient(reader: &mut BufReader<File>) -> Result<Rom> {
+        let mut data = Vec::new();
+
+        reader.read_to_end(&mut data)?;
+
+        Self::load(data, true)
+    }
 
-        reader.read_to_end(&mut rom.data)?;
+    pub fn from_bytes(data: Vec<u8>) -> Result<Rom> {
+        Self::load(data, false)
+    }
+
+    pub fn from_bytes_lenient(data: Vec<u8>) -> Result<Rom> {
+        Self::load(data, true)
+    }
+
+    // テスト用に、PRGとCHRからiNESのヘッダーを組み立てて読み込む
+    // 1画面ミラーリングはヘッダーで表せないので水平ミラーリングになる
+    pub fn from_parts(prg: Vec<u8>, chr: Vec<u8>, mapper: u8, mirroring: Mirroring) -> Result<Rom> {
+        if prg.is_empty() || prg.len() & 0x3FFF != 0 {
+            bail!("PRG size {:#X} is not a multiple of 16KB", prg.len());
+        }
+
+        if chr.len() & 0x1FFF != 0 {
+            bail!("CHR size {:#X} is not a multiple of 8KB", chr.len());
+        }
+
+        let prg_banks = prg.len() / 0x4000;
+        let chr_banks = chr.len() / 0x2000;
+
+        if prg_banks > 0xFF || chr_banks > 0xFF {
+            bail!("PRG or CHR is too large for an iNES header");
+        }
+
+        let mirroring = match mirroring {
+            Mirroring::Vertical => 0b0001,
+            Mirroring::FourScreen => 0b1000,
+            _ => 0b0000,
+        };
+
+        let mut data = vec![
+            b'N',
+            b'E',
+            b'S',
+            0x1A,
+            prg_banks as u8,
+            chr_banks as u8,
+            ((mapper & 0x0F) << 4) | mirroring,
+            mapper & 0xF0,
+        ];
+
+        data.resize(0x10, 0);
+        data.extend(prg);
+        data.extend(chr);
+
+        Self::load(data, false)
+    }
+
+    fn load(data: Vec<u8>, lenient: bool) -> Result<Rom> {
+        let mut rom = Rom {
+            data,
+            ..Default::default()
+        };
+
+        if rom.data.len() < 0x0010 {
+            bail!("missing NES 2.0 header");
+        }
 
         if rom.data[0x0000..0x0004] != b"NES\x1A"[..] {
             bail!("missing NES 2.0 header");
@@ -223,14 +413,20 @@ impl Rom {
         rom.flag1 = Flag1(rom.data[0x0006]);
         rom.flag2 = Flag2(rom.data[0x0007]);
 
-        let mapper_submapper = MapperSubmapper(rom.data[0x0008]);
-
-        rom.submapper = mapper_submapper.submapper_type();
+        // iNES 1.0ではバイト8以降にゴミが入っていることがあるので、NES 2.0のときだけ読む
+        let mapper_submapper = if rom.is_nes2() {
+            MapperSubmapper(rom.data[0x0008])
+        } else {
+            MapperSubmapper(0)
+        };
 
         let mut mapper = rom.flag1.mapper_type_low();
         mapper += rom.flag2.mapper_type_middle() << 4;
         mapper += mapper_submapper.mapper_type_high() << 8;
-        rom.mapper = FromPrimitive::from_u16(mapper).context("unknown mapper type")?;
+        rom.mapper = FromPrimitive::from_u16(mapper)
+            .with_context(|| format!("unsupported mapper {}", mapper))?;
+
+        rom.submapper = SubmapperType::new(&rom.mapper, mapper_submapper.submapper_type());
 
         let prg_chr_rom_num = PrgChrRomNum(rom.data[0x0009]);
 
@@ -240,6 +436,30 @@ impl Rom {
         rom.prg_size = prg_num * 16 * 1024;
         rom.chr_size = chr_num * 8 * 1024;
 
+        if rom.data.len() < rom.chr_offset() {
+            bail!(
+                "truncated PRG-ROM ({:#X} of {:#X} bytes)",
+                rom.data.len().saturating_sub(rom.prg_offset()),
+                rom.prg_size
+            );
+        }
+
+        if rom.data.len() < rom.inst_rom_offset() {
+            let missing = rom.inst_rom_offset() - rom.data.len();
+
+            if !lenient {
+                bail!(
+                    "truncated CHR-ROM ({:#X} of {:#X} bytes)",
+                    rom.chr_size - missing,
+                    rom.chr_size
+                );
+            }
+
+            warn!("CHR-ROM TRUNCATED: PADDING {:#X} BYTES", missing);
+
+            rom.data.resize(rom.inst_rom_offset(), 0);
+        }
+
         let prg_ram_eeprom_size = PrgRamEepromSize(rom.data[0x000A]);
 
         if prg_ram_eeprom_size.ram_shift_count() > 0 {
@@ -270,6 +490,14 @@ impl Rom {
             }
             ConsoleType::Extended => {
                 rom.extended_console_type = ExtendedConsoleType(rom.data[0x000D]);
+
+                // 通常のNESとして動かすので、違いがあるものは知らせておく
+                match rom.extended_console_type.console_type() {
+                    ExtendedConsole::Regular
+                    | ExtendedConsole::VsSystem
+                    | ExtendedConsole::Playchoice10 => {}
+                    console => warn!("EXTENDED CONSOLE NOT EMULATED: {:?}", console),
+                }
             }
             _ => {}
         };
@@ -278,6 +506,22 @@ impl Rom {
 
         rom.expansion_device_type = default_expansion_device.device_type();
 
+        if let ConsoleType::Playchoice10 = rom.flag2.console_type() {
+            let remaining = rom.data.len().saturating_sub(rom.inst_rom_offset());
+
+            match remaining {
+                0 => {}
+                n if n >= PLAYCHOICE_SIZE => {
+                    rom.inst_rom_size = INST_ROM_SIZE;
+                    rom.prom_size = PROM_SIZE;
+                }
+                n if n >= INST_ROM_SIZE => {
+                    rom.inst_rom_size = INST_ROM_SIZE;
+                }
+                n => bail!("truncated PlayChoice INST-ROM ({:#X} bytes)", n),
+            }
+        }
+
         Ok(rom)
     }
 
@@ -315,13 +559,267 @@ impl Rom {
         &self.data[offset..(offset + self.chr_size)]
     }
 
-    fn misc_offset(&self) -> usize {
+    fn inst_rom_offset(&self) -> usize {
         self.chr_offset() + self.chr_size
     }
 
+    pub fn inst_rom(&self) -> Option<&[u8]> {
+        let offset = self.inst_rom_offset();
+
+        if self.inst_rom_size > 0 {
+            Some(&self.data[offset..(offset + self.inst_rom_size)])
+        } else {
+            None
+        }
+    }
+
+    fn prom_offset(&self) -> usize {
+        self.inst_rom_offset() + self.inst_rom_size
+    }
+
+    pub fn prom(&self) -> Option<&[u8]> {
+        let offset = self.prom_offset();
+
+        if self.prom_size > 0 {
+            Some(&self.data[offset..(offset + self.prom_size)])
+        } else {
+            None
+        }
+    }
+
+    fn misc_offset(&self) -> usize {
+        self.prom_offset() + self.prom_size
+    }
+
     pub fn misc(&self) -> &[u8] {
         let offset = self.misc_offset();
 
         &self.data[offset..]
     }
+
+    pub fn is_nes2(&self) -> bool {
+        self.flag2.nes2_identifier() == 0b10
+    }
+
+    pub fn has_prg_ram(&self) -> bool {
+        self.total_prg_ram_size() > 0
+    }
+
+    // iNES 1.0のヘッダーにはサイズがないので8KBとみなす
+    pub fn total_prg_ram_size(&self) -> usize {
+        if self.is_nes2() {
+            self.prg_ram_size + self.prg_nvram_size
+        } else {
+            0x2000
+        }
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        if self.flag1.four_screen_mode() {
+            Mirroring::FourScreen
+        } else if self.flag1.mirroring() {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    // 慣例に従って、ヘッダーを除いたPRGとCHRから計算する
+    fn hash_data(&self) -> &[u8] {
+        &self.data[self.prg_offset()..self.inst_rom_offset()]
+    }
+
+    pub fn crc32(&self) -> u32 {
+        hash::crc32(self.hash_data())
+    }
+
+    pub fn md5(&self) -> [u8; 16] {
+        hash::md5(self.hash_data())
+    }
+
+    pub fn info(&self) -> RomInfo {
+        RomInfo {
+            mapper: self.mapper.name().to_string(),
+            prg_kb: self.prg_size / 1024,
+            chr_kb: self.chr_size / 1024,
+            mirroring: self.mirroring(),
+            has_battery: self.flag1.has_battery(),
+            region: Region::from(&self.timing_mode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // PRG 16KB, CHR 8KB のiNESファイル
+    fn ines(flags6: u8) -> (Vec<u8>, Vec<u8>) {
+        let body = (0..0x6000).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+
+        let mut data = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, flags6];
+
+        data.resize(0x10, 0);
+        data.extend_from_slice(&body);
+
+        (data, body)
+    }
+
+    // ヘッダーのバンク数の分だけPRGとCHRを置き、後ろにtrailingバイト足したファイル
+    fn image(header: &[u8], trailing: usize) -> Vec<u8> {
+        let mut data = header.to_vec();
+
+        data.resize(0x10, 0);
+
+        let body = data[4] as usize * 0x4000 + data[5] as usize * 0x2000 + trailing;
+
+        data.extend((0..body).map(|i| (i >> 8) as u8));
+
+        data
+    }
+
+    #[test]
+    fn hashes_exclude_the_header() {
+        let (horizontal, body) = ines(0x00);
+        let (vertical, _) = ines(0x01);
+
+        let horizontal = Rom::from_bytes(horizontal).unwrap();
+        let vertical = Rom::from_bytes(vertical).unwrap();
+
+        assert_eq!(horizontal.crc32(), hash::crc32(&body));
+        assert_eq!(horizontal.md5(), hash::md5(&body));
+        assert_eq!(vertical.crc32(), horizontal.crc32());
+    }
+
+    #[test]
+    fn playchoice_trailing_data_splits_into_inst_rom_and_prom() {
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x02];
+
+        let rom = Rom::from_bytes(image(&header, PLAYCHOICE_SIZE + 0x10)).unwrap();
+
+        let inst_rom = rom.inst_rom().unwrap();
+        let prom = rom.prom().unwrap();
+
+        assert_eq!(inst_rom.len(), INST_ROM_SIZE);
+        assert_eq!(inst_rom[0], (0x6000 >> 8) as u8);
+        assert_eq!(prom.len(), PROM_SIZE);
+        assert_eq!(prom[0], (0x8000 >> 8) as u8);
+        assert_eq!(rom.misc().len(), 0x10);
+
+        // PROMがなければINST-ROMだけ
+        let rom = Rom::from_bytes(image(&header, INST_ROM_SIZE)).unwrap();
+
+        assert_eq!(rom.inst_rom().map(|d| d.len()), Some(INST_ROM_SIZE));
+        assert!(rom.prom().is_none());
+
+        // INST-ROMが途中で切れているのはエラー
+        assert!(Rom::from_bytes(image(&header, 0x100)).is_err());
+
+        // PlayChoiceでなければ後ろのデータはmiscのまま
+        let mut header = header;
+
+        header[7] = 0x00;
+
+        let rom = Rom::from_bytes(image(&header, PLAYCHOICE_SIZE)).unwrap();
+
+        assert!(rom.inst_rom().is_none());
+        assert_eq!(rom.misc().len(), PLAYCHOICE_SIZE);
+    }
+
+    #[test]
+    fn info_summarizes_the_header() {
+        // MMC1、垂直ミラーリング、バッテリー付き
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0x08, 0x02, 0x13, 0x00];
+
+        let info = Rom::from_bytes(image(&header, 0)).unwrap().info();
+
+        assert_eq!(info.prg_kb, 128);
+        assert_eq!(info.chr_kb, 16);
+        assert!(info.has_battery);
+        assert_eq!(
+            info.to_string(),
+            "MMC1, PRG 128KB, CHR 16KB, vertical mirroring, battery, NTSC"
+        );
+
+        // NES 2.0でPALのタイミング
+        let header = [
+            0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x20, 0x08, 0, 0, 0, 0, 0x01,
+        ];
+
+        assert_eq!(
+            Rom::from_bytes(image(&header, 0))
+                .unwrap()
+                .info()
+                .to_string(),
+            "UxROM, PRG 16KB, CHR 0KB, horizontal mirroring, no battery, PAL"
+        );
+    }
+
+    #[test]
+    fn extended_console_type_decodes_the_low_nibble() {
+        let cases = [
+            (0x00, ExtendedConsole::Regular),
+            (0x01, ExtendedConsole::VsSystem),
+            (0x03, ExtendedConsole::FamicloneDecimalMode),
+            (0x05, ExtendedConsole::Vt01),
+            (0x0C, ExtendedConsole::FamicomNetworkSystem),
+            (0x0F, ExtendedConsole::Unknown),
+            // 上位ニブルは予約なので無視する
+            (0xF4, ExtendedConsole::EpsmModule),
+        ];
+
+        for &(byte, console) in cases.iter() {
+            // NES 2.0で、コンソールタイプが拡張
+            let header = [
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x0B, 0, 0, 0, 0, 0, byte,
+            ];
+
+            let rom = Rom::from_bytes(image(&header, 0)).unwrap();
+
+            assert!(matches!(rom.flag2.console_type(), ConsoleType::Extended));
+            assert_eq!(
+                rom.extended_console_type.console_type(),
+                console,
+                "{:#04X}",
+                byte
+            );
+        }
+
+        // 拡張でなければ$0Dは拡張コンソールタイプとして読まない
+        let header = [
+            0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x09, 0, 0, 0, 0, 0, 0x05,
+        ];
+
+        let rom = Rom::from_bytes(image(&header, 0)).unwrap();
+
+        assert_eq!(
+            rom.extended_console_type.console_type(),
+            ExtendedConsole::Regular
+        );
+    }
+
+    #[test]
+    fn truncated_chr_is_padded_only_when_lenient() {
+        let header = [0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01];
+
+        let mut data = image(&header, 0);
+
+        data.truncate(data.len() - 5);
+
+        let error = Rom::from_bytes(data.clone()).err().unwrap();
+
+        assert_eq!(
+            error.to_string(),
+            "truncated CHR-ROM (0x1FFB of 0x2000 bytes)"
+        );
+
+        let rom = Rom::from_bytes_lenient(data).unwrap();
+
+        assert_eq!(rom.chr().len(), 0x2000);
+        assert_eq!(rom.chr()[0x1FFA], (0x5FFA >> 8) as u8);
+        assert_eq!(rom.chr()[0x1FFB..], [0; 5]);
+
+        // PRGまで足りないのは緩くしてもエラー
+        assert!(Rom::from_bytes_lenient(image(&header, 0)[..0x3000].to_vec()).is_err());
+    }
 }
@@ -0,0 +1,90 @@
+use crate::region::Region;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    Cpu,
+    Ppu,
+}
+
+// マスタークロックを進めて、CPUとPPUをそれぞれの分周比で順番に動かす
+// (APUはCPUのクロックで動く)
+pub struct Scheduler {
+    master: u64,
+
+    cpu_divider: u64,
+    ppu_divider: u64,
+
+    next_cpu: u64,
+    next_ppu: u64,
+
+    cpu_cycles: u64,
+    ppu_dots: u64,
+}
+
+impl Scheduler {
+    pub fn new(region: Region) -> Self {
+        Self {
+            master: 0,
+
+            cpu_divider: region.cpu_divider(),
+            ppu_divider: region.ppu_divider(),
+
+            next_cpu: 0,
+            next_ppu: 0,
+
+            cpu_cycles: 0,
+            ppu_dots: 0,
+        }
+    }
+
+    // 同じ時刻ならCPUを先に動かす
+    pub fn step(&mut self) -> Clock {
+        if self.next_cpu <= self.next_ppu {
+            self.master = self.next_cpu;
+            self.next_cpu += self.cpu_divider;
+            self.cpu_cycles += 1;
+
+            Clock::Cpu
+        } else {
+            self.master = self.next_ppu;
+            self.next_ppu += self.ppu_divider;
+            self.ppu_dots += 1;
+
+            Clock::Ppu
+        }
+    }
+
+    pub fn master(&self) -> u64 {
+        self.master
+    }
+
+    pub fn cpu_cycles(&self) -> u64 {
+        self.cpu_cycles
+    }
+
+    pub fn ppu_dots(&self) -> u64 {
+        self.ppu_dots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu_cycles_in(region: Region, dots: u64) -> u64 {
+        let mut scheduler = Scheduler::new(region);
+
+        while scheduler.ppu_dots() < dots {
+            scheduler.step();
+        }
+
+        scheduler.cpu_cycles()
+    }
+
+    #[test]
+    fn one_frame_dispatches_cpu_cycles_by_divider() {
+        // NTSCは341x262-1ドット、PALは341x312ドット
+        assert_eq!(cpu_cycles_in(Region::Ntsc, 89342), 29781);
+        assert_eq!(cpu_cycles_in(Region::Pal, 106392), 33248);
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::mpsc::{SyncSender, TrySendError};
+
+// フレームが出来上がるたびにNesから呼ばれる (RGBAでwidth * height * 4バイト)
+pub trait FrameSink {
+    fn push_frame(&mut self, frame: &[u8], width: usize, height: usize);
+
+    // 表示のタイミングで溜めたフレームを送り出す。受け取り側がいなくなっていたらfalseを返す
+    fn flush(&mut self) -> bool {
+        true
+    }
+}
+
+// 別スレッドにフレームを送る。早送りで何フレーム進んでも送るのはflush時点の最新の1枚だけで、
+// 受け取り側が追いついていなければそれも捨てる
+pub struct ChannelSink<T> {
+    sender: SyncSender<T>,
+    pending: Option<Vec<u8>>,
+}
+
+impl<T: From<Vec<u8>>> ChannelSink<T> {
+    pub fn new(sender: SyncSender<T>) -> Self {
+        Self {
+            sender,
+            pending: None,
+        }
+    }
+}
+
+impl<T: From<Vec<u8>>> FrameSink for ChannelSink<T> {
+    fn push_frame(&mut self, frame: &[u8], _width: usize, _height: usize) {
+        let pending = self.pending.get_or_insert_with(Vec::new);
+
+        pending.clear();
+        pending.extend_from_slice(frame);
+    }
+
+    fn flush(&mut self) -> bool {
+        match self.pending.take() {
+            Some(frame) => !matches!(
+                self.sender.try_send(T::from(frame)),
+                Err(TrySendError::Disconnected(_))
+            ),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn channel_sink_sends_the_latest_frame_on_flush() {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(1);
+        let mut sink = ChannelSink::new(sender);
+
+        for frame in 1..=3 {
+            sink.push_frame(&[frame; 4], 1, 1);
+        }
+
+        assert!(receiver.try_recv().is_err());
+        assert!(sink.flush());
+        assert_eq!(receiver.try_recv().unwrap(), vec![3; 4]);
+
+        // 送るものがなければ何もしない
+        assert!(sink.flush());
+        assert!(receiver.try_recv().is_err());
+
+        drop(receiver);
+
+        sink.push_frame(&[4; 4], 1, 1);
+
+        assert!(!sink.flush());
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use image::{ImageBuffer, Rgba};
+
+use crate::rom::Rom;
+
+const TILE_SIZE: usize = 8;
+const TILE_BYTES: usize = 16;
+const TILES_PER_ROW: usize = 16;
+
+pub const GRAYSCALE: [[u8; 4]; 4] = [
+    [0x00, 0x00, 0x00, 0xFF],
+    [0x55, 0x55, 0x55, 0xFF],
+    [0xAA, 0xAA, 0xAA, 0xFF],
+    [0xFF, 0xFF, 0xFF, 0xFF],
+];
+
+pub fn render_chr(chr: &[u8], palette: [[u8; 4]; 4]) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let tiles = chr.len() / TILE_BYTES;
+    let rows = tiles.div_ceil(TILES_PER_ROW);
+
+    let mut image = ImageBuffer::new(
+        (TILES_PER_ROW * TILE_SIZE) as u32,
+        (rows.max(1) * TILE_SIZE) as u32,
+    );
+
+    for (i, tile) in chr.chunks_exact(TILE_BYTES).enumerate() {
+        let base_x = (i % TILES_PER_ROW) * TILE_SIZE;
+        let base_y = (i / TILES_PER_ROW) * TILE_SIZE;
+
+        for row in 0..TILE_SIZE {
+            let low = tile[row];
+            let high = tile[row + TILE_SIZE];
+
+            for col in 0..TILE_SIZE {
+                let shift = 7 - col;
+                let index = ((low >> shift) & 1) | (((high >> shift) & 1) << 1);
+
+                image.put_pixel(
+                    (base_x + col) as u32,
+                    (base_y + row) as u32,
+                    Rgba(palette[index as usize]),
+                );
+            }
+        }
+    }
+
+    image
+}
+
+pub fn export_chr_png<P: AsRef<Path>>(rom: &Rom, path: P, palette: [[u8; 4]; 4]) -> Result<()> {
+    let path = path.as_ref();
+
+    render_chr(rom.chr(), palette)
+        .save(path)
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+const FRAME_WIDTH: u32 = 256;
+const FRAME_HEIGHT: u32 = 240;
+
+pub fn diff_frame(
+    actual: &[u8],
+    expected: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+) -> Result<Option<ImageBuffer<Rgba<u8>, Vec<u8>>>> {
+    if expected.dimensions() != (FRAME_WIDTH, FRAME_HEIGHT) {
+        bail!("unexpected reference size {:?}", expected.dimensions());
+    }
+
+    let actual = ImageBuffer::<Rgba<u8>, _>::from_raw(FRAME_WIDTH, FRAME_HEIGHT, actual)
+        .context("unexpected frame size")?;
+
+    let mut diff = ImageBuffer::new(FRAME_WIDTH, FRAME_HEIGHT);
+    let mut mismatched = false;
+
+    for (x, y, pixel) in actual.enumerate_pixels() {
+        // 一致したドットは暗く、違うドットは赤で描く
+        let color = if pixel == expected.get_pixel(x, y) {
+            Rgba([pixel[0] / 4, pixel[1] / 4, pixel[2] / 4, 0xFF])
+        } else {
+            mismatched = true;
+            Rgba([0xFF, 0x00, 0x00, 0xFF])
+        };
+
+        diff.put_pixel(x, y, color);
+    }
+
+    Ok(if mismatched { Some(diff) } else { None })
+}
+
+pub const UPSCALED_WIDTH: u32 = FRAME_WIDTH * 2;
+pub const UPSCALED_HEIGHT: u32 = FRAME_HEIGHT * 2;
+
+// フロントエンド用に2倍に拡大する
+// smoothは隣のドットと混ぜ、scanlinesは奇数ラインを暗くしてCRTっぽくする
+pub fn upscale_frame(frame: &[u8], out: &mut [u8], scanlines: bool, smooth: bool) {
+    let width = FRAME_WIDTH as usize;
+    let height = FRAME_HEIGHT as usize;
+
+    let pixel = |x: usize, y: usize| &frame[(y * width + x) * 4..][..3];
+
+    for (i, dst) in out.chunks_exact_mut(4).enumerate() {
+        let (ox, oy) = (i % (width * 2), i / (width * 2));
+        let (x, y) = (ox / 2, oy / 2);
+
+        let dx = (smooth && ox & 1 == 1 && x + 1 < width) as usize;
+        let dy = (smooth && oy & 1 == 1 && y + 1 < height) as usize;
+
+        for (c, channel) in dst.iter_mut().take(3).enumerate() {
+            let sum = pixel(x, y)[c] as u32
+                + pixel(x + dx, y)[c] as u32
+                + pixel(x, y + dy)[c] as u32
+                + pixel(x + dx, y + dy)[c] as u32;
+
+            let value = if scanlines && oy & 1 == 1 {
+                sum * 3 / 20
+            } else {
+                sum / 4
+            };
+
+            *channel = value as u8;
+        }
+
+        dst[3] = 0xFF;
+    }
+}
+
+pub struct TraceWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    lines: usize,
+    max_lines: usize,
+}
+
+impl TraceWriter {
+    pub fn new<P: AsRef<Path>>(path: P, max_lines: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file =
+            File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            lines: 0,
+            max_lines,
+        })
+    }
+
+    // 上限に達したら直前のトレースを .1 に退避して書き直す
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+
+        fs::rename(&self.path, rotated)?;
+
+        self.writer = BufWriter::new(File::create(&self.path)?);
+        self.lines = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for TraceWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.lines >= self.max_lines {
+            self.rotate()?;
+        }
+
+        let written = self.writer.write(buf)?;
+
+        self.lines += buf[..written].iter().filter(|&&b| b == b'\n').count();
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{nes::Nes, rom::Mirroring};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rnes-{}-{}", std::process::id(), name));
+
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    #[test]
+    fn exported_chr_is_16_tiles_wide() {
+        let path = temp_path("chr.png");
+        let rom = Rom::from_parts(
+            vec![0; 0x4000],
+            vec![0xFF; 0x2000],
+            0,
+            Mirroring::Horizontal,
+        )
+        .unwrap();
+
+        export_chr_png(&rom, &path, GRAYSCALE).unwrap();
+
+        // 8KBは512タイルなので、16x32タイルになる
+        let image = image::open(&path).unwrap().to_rgba();
+
+        assert_eq!(image.dimensions(), (128, 256));
+        assert_eq!(image.get_pixel(127, 255), &Rgba(GRAYSCALE[3]));
+
+        // 端数のタイルは1行に切り上げる
+        assert_eq!(render_chr(&[0; 17 * 16], GRAYSCALE).dimensions(), (128, 16));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn trace_writes_one_nestest_line_per_instruction() {
+        let path = temp_path("trace.log");
+
+        let mut prg = vec![0xEA; 0x8000];
+
+        // $8000: LDA #$42; STA $0200; JMP $8000
+        prg[..8].copy_from_slice(&[0xA9, 0x42, 0x8D, 0x00, 0x02, 0x4C, 0x00, 0x80]);
+        prg[0x7FFC] = 0x00;
+        prg[0x7FFD] = 0x80;
+
+        let mut nes = Nes::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Horizontal).unwrap();
+
+        nes.set_trace(Some(Box::new(TraceWriter::new(&path, 100).unwrap())));
+
+        for _ in 0..4 {
+            nes.step_instruction().unwrap();
+        }
+
+        nes.set_trace(None);
+
+        let trace = fs::read_to_string(&path).unwrap();
+        let lines = trace.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            &lines[0][..48],
+            "8000  A9 42     LDA #$42                        "
+        );
+        assert_eq!(&lines[1][..26], "8002  8D 00 02  STA $0200 ");
+        assert_eq!(&lines[2][..25], "8005  4C 00 80  JMP $8000");
+        assert_eq!(&lines[3][..16], "8000  A9 42     ");
+
+        // A〜SPの後ろにPPUのライン・ドットとCPUのサイクルが続く
+        let registers = &lines[1][48..];
+
+        assert!(registers.starts_with("A:42 X:00 Y:00 P:24 SP:FD PPU:"));
+        assert_eq!(&lines[0][74..], "PPU:  0,  0 CYC:7");
+        assert!(registers.ends_with(" CYC:9"), "{}", registers);
+        assert!(lines[2].ends_with(" CYC:13"), "{}", lines[2]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn trace_writer_keeps_one_rotated_file() {
+        let path = temp_path("rotate.log");
+
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+
+        let mut writer = TraceWriter::new(&path, 3).unwrap();
+
+        for i in 0..7 {
+            writeln!(writer, "{}", i).unwrap();
+        }
+
+        writer.flush().unwrap();
+
+        // 上限の3行ごとに書き直すので、.1には直前の3行だけが残る
+        assert_eq!(fs::read_to_string(&rotated).unwrap(), "3\n4\n5\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "6\n");
+
+        let mut stale = path.clone().into_os_string();
+        stale.push(".2");
+
+        assert!(!Path::new(&stale).exists());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+
+    #[test]
+    fn upscale_doubles_blends_and_darkens_scanlines() {
+        let width = FRAME_WIDTH as usize;
+        let mut frame = vec![0; width * FRAME_HEIGHT as usize * 4];
+
+        frame[..4].copy_from_slice(&[200, 100, 40, 0]);
+        frame[(width - 1) * 4..][..4].copy_from_slice(&[80, 80, 80, 0]);
+
+        let upscale = |scanlines, smooth| {
+            let mut out = vec![0; (UPSCALED_WIDTH * UPSCALED_HEIGHT * 4) as usize];
+
+            upscale_frame(&frame, &mut out, scanlines, smooth);
+
+            move |x: usize, y: usize| out[(y * width * 2 + x) * 4..][..4].to_vec()
+        };
+
+        // 1ドットが2x2になる
+        let pixel = upscale(false, false);
+
+        for &(x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+            assert_eq!(pixel(x, y), [200, 100, 40, 0xFF], "({}, {})", x, y);
+        }
+
+        assert_eq!(pixel(2, 0), [0, 0, 0, 0xFF]);
+
+        // 奇数ラインは6割の明るさ
+        let pixel = upscale(true, false);
+
+        assert_eq!(pixel(1, 0), [200, 100, 40, 0xFF]);
+        assert_eq!(pixel(1, 1), [120, 60, 24, 0xFF]);
+
+        // 奇数の列と行は右と下のドットと混ぜる。端では混ぜる相手がいない
+        let pixel = upscale(false, true);
+
+        assert_eq!(pixel(0, 0), [200, 100, 40, 0xFF]);
+        assert_eq!(pixel(1, 0), [100, 50, 20, 0xFF]);
+        assert_eq!(pixel(1, 1), [50, 25, 10, 0xFF]);
+        assert_eq!(pixel(width * 2 - 1, 0), [80, 80, 80, 0xFF]);
+    }
+}
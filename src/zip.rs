@@ -0,0 +1,249 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054B50;
+const CENTRAL_DIRECTORY: u32 = 0x02014B50;
+const LOCAL_FILE_HEADER: u32 = 0x04034B50;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+struct Entry {
+    name: String,
+    method: u16,
+    compressed_size: usize,
+    local_header: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data
+        .get(offset..(offset + 2))
+        .context("truncated zip archive")?;
+
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..(offset + 4))
+        .context("truncated zip archive")?;
+
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// コメントがあるとEOCDの位置がずれるので、後ろから署名を探す
+fn find_end_of_central_directory(data: &[u8]) -> Result<usize> {
+    if data.len() < 22 {
+        bail!("not a zip archive");
+    }
+
+    (0..=(data.len() - 22))
+        .rev()
+        .find(|&offset| read_u32(data, offset).ok() == Some(END_OF_CENTRAL_DIRECTORY))
+        .context("not a zip archive")
+}
+
+fn entries(data: &[u8]) -> Result<Vec<Entry>> {
+    let eocd = find_end_of_central_directory(data)?;
+
+    let count = read_u16(data, eocd + 10)? as usize;
+    let mut offset = read_u32(data, eocd + 16)? as usize;
+
+    let mut entries = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        if read_u32(data, offset)? != CENTRAL_DIRECTORY {
+            bail!("corrupted zip central directory");
+        }
+
+        let method = read_u16(data, offset + 10)?;
+        let compressed_size = read_u32(data, offset + 20)? as usize;
+        let name_len = read_u16(data, offset + 28)? as usize;
+        let extra_len = read_u16(data, offset + 30)? as usize;
+        let comment_len = read_u16(data, offset + 32)? as usize;
+        let local_header = read_u32(data, offset + 42)? as usize;
+
+        let name = data
+            .get((offset + 46)..(offset + 46 + name_len))
+            .context("truncated zip archive")?;
+
+        entries.push(Entry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method,
+            compressed_size,
+            local_header,
+        });
+
+        offset += 46 + name_len + extra_len + comment_len;
+    }
+
+    Ok(entries)
+}
+
+fn extract(data: &[u8], entry: &Entry) -> Result<Vec<u8>> {
+    let offset = entry.local_header;
+
+    if read_u32(data, offset)? != LOCAL_FILE_HEADER {
+        bail!("corrupted zip entry {}", entry.name);
+    }
+
+    // サイズはデータディスクリプタの場合もあるので中央ディレクトリのものを使う
+    let name_len = read_u16(data, offset + 26)? as usize;
+    let extra_len = read_u16(data, offset + 28)? as usize;
+    let start = offset + 30 + name_len + extra_len;
+
+    let compressed = data
+        .get(start..(start + entry.compressed_size))
+        .with_context(|| format!("truncated zip entry {}", entry.name))?;
+
+    match entry.method {
+        STORED => Ok(compressed.to_vec()),
+        DEFLATED => inflate::inflate_bytes(compressed)
+            .map_err(|e| anyhow::anyhow!(e))
+            .with_context(|| format!("failed to inflate {}", entry.name)),
+        method => bail!(
+            "unsupported compression method {} for {}",
+            method,
+            entry.name
+        ),
+    }
+}
+
+pub fn is_zip(data: &[u8]) -> bool {
+    read_u32(data, 0).ok() == Some(LOCAL_FILE_HEADER)
+}
+
+pub fn read_nes(data: &[u8]) -> Result<Vec<u8>> {
+    let entries = entries(data)?;
+
+    let mut roms = entries
+        .iter()
+        .filter(|entry| entry.name.to_ascii_lowercase().ends_with(".nes"));
+
+    let rom = roms.next().context("no .nes file in zip archive")?;
+
+    for other in roms {
+        warn!("MULTIPLE ROMS IN ZIP: IGNORING {}", other.name);
+    }
+
+    debug!("LOAD ROM FROM ZIP: {}", rom.name);
+
+    extract(data, rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "NES\x1A"の後ろに$00が60バイト続くデータを生のdeflateで圧縮したもの
+    const DEFLATED_NES: [u8; 9] = [0xF3, 0x73, 0x0D, 0x96, 0x62, 0xA0, 0x00, 0x00, 0x00];
+
+    fn deflated_nes() -> Vec<u8> {
+        let mut data = b"NES\x1A".to_vec();
+
+        data.resize(64, 0);
+
+        data
+    }
+
+    // (名前, 圧縮方法, 格納するデータ) からアーカイブを組み立てる
+    fn archive(files: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut central = Vec::new();
+
+        for &(name, method, body) in files.iter() {
+            let local_header = data.len() as u32;
+
+            data.extend_from_slice(&LOCAL_FILE_HEADER.to_le_bytes());
+            data.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(&method.to_le_bytes());
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(&[0; 2]);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(body);
+
+            central.extend_from_slice(&CENTRAL_DIRECTORY.to_le_bytes());
+            central.extend_from_slice(&[0; 6]);
+            central.extend_from_slice(&method.to_le_bytes());
+            central.extend_from_slice(&[0; 8]);
+            central.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            central.extend_from_slice(&[0; 4]);
+            central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            central.extend_from_slice(&[0; 12]);
+            central.extend_from_slice(&local_header.to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+        }
+
+        let central_offset = data.len() as u32;
+
+        data.extend_from_slice(&central);
+
+        data.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        data.extend_from_slice(&[0; 6]);
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        data.extend_from_slice(&central_offset.to_le_bytes());
+        data.extend_from_slice(&[0; 2]);
+
+        data
+    }
+
+    #[test]
+    fn reads_stored_and_deflated_entries() {
+        let stored = archive(&[
+            ("readme.txt", STORED, b"hello"),
+            ("game.nes", STORED, b"NES"),
+        ]);
+
+        assert!(is_zip(&stored));
+        assert_eq!(read_nes(&stored).unwrap(), b"NES");
+
+        let deflated = archive(&[("GAME.NES", DEFLATED, &DEFLATED_NES)]);
+
+        assert_eq!(read_nes(&deflated).unwrap(), deflated_nes());
+    }
+
+    #[test]
+    fn first_nes_entry_wins() {
+        let data = archive(&[
+            ("a.txt", STORED, b"text"),
+            ("first.nes", STORED, b"first"),
+            ("second.nes", DEFLATED, &DEFLATED_NES),
+        ]);
+
+        assert_eq!(read_nes(&data).unwrap(), b"first");
+    }
+
+    #[test]
+    fn rejects_empty_and_invalid_archives() {
+        let empty = archive(&[]);
+
+        assert_eq!(
+            read_nes(&empty).unwrap_err().to_string(),
+            "no .nes file in zip archive"
+        );
+        assert!(!is_zip(&empty));
+
+        assert!(read_nes(&[]).is_err());
+        assert!(read_nes(b"NES\x1A not a zip archive at all").is_err());
+
+        let mut corrupted = archive(&[("game.nes", DEFLATED, &[0xFF; 4])]);
+
+        assert!(read_nes(&corrupted).is_err());
+
+        // 中央ディレクトリの署名が壊れている
+        let central = corrupted.len() - 22 - 54;
+
+        corrupted[central] = 0;
+
+        assert!(read_nes(&corrupted).is_err());
+
+        let unsupported = archive(&[("game.nes", 12, b"bzip2")]);
+
+        assert_eq!(
+            read_nes(&unsupported).unwrap_err().to_string(),
+            "unsupported compression method 12 for game.nes"
+        );
+    }
+}
@@ -0,0 +1,30 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use rnes::{nes::Nes, tools::diff_frame};
+
+// 今のフレームを参照画像と比べ、違ったら隣に .diff.png を書き出して失敗する
+pub fn assert_frame_matches<P: AsRef<Path>>(nes: &mut Nes, expected_png: P) -> Result<()> {
+    let path = expected_png.as_ref();
+
+    let expected = image::open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?
+        .to_rgba();
+
+    let frame = nes.render()?;
+
+    if let Some(diff) = diff_frame(&frame, &expected)? {
+        let diff_path = path.with_extension("diff.png");
+
+        diff.save(&diff_path)
+            .with_context(|| format!("failed to write {}", diff_path.display()))?;
+
+        bail!(
+            "frame does not match {} (see {})",
+            path.display(),
+            diff_path.display()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use anyhow::Result;
+use rnes::{nes::Nes, rom::Mirroring};
+
+// VBlankを2回待ってパレットとネームテーブルを書き、背景を表示して止まる
+const PROGRAM: [u8; 0x54] = [
+    0x78, // SEI
+    0xA9, 0x00, // LDA #$00
+    0x8D, 0x00, 0x20, // STA $2000
+    0x8D, 0x01, 0x20, // STA $2001
+    0x2C, 0x02, 0x20, // BIT $2002
+    0x10, 0xFB, // BPL $8009
+    0x2C, 0x02, 0x20, // BIT $2002
+    0x10, 0xFB, // BPL $800E
+    0xA9, 0x3F, // LDA #$3F
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA9, 0x00, // LDA #$00
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA2, 0x00, // LDX #$00
+    0xBD, 0x00, 0x81, // LDA $8100,X
+    0x8D, 0x07, 0x20, // STA $2007
+    0xE8, // INX
+    0xE0, 0x20, // CPX #$20
+    0xD0, 0xF5, // BNE $801F
+    0xA9, 0x20, // LDA #$20
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA9, 0x00, // LDA #$00
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA0, 0x04, // LDY #$04
+    0xA2, 0x00, // LDX #$00
+    0x8A, // TXA
+    0x29, 0x03, // AND #$03
+    0x8D, 0x07, 0x20, // STA $2007
+    0xE8, // INX
+    0xD0, 0xF7, // BNE $8038
+    0x88, // DEY
+    0xD0, 0xF2, // BNE $8036
+    0xA9, 0x00, // LDA #$00
+    0x8D, 0x05, 0x20, // STA $2005
+    0x8D, 0x05, 0x20, // STA $2005
+    0xA9, 0x0A, // LDA #$0A
+    0x8D, 0x01, 0x20, // STA $2001
+    0x4C, 0x51, 0x80, // JMP $8051
+];
+
+const PALETTE: [u8; 0x10] = [
+    0x0F, 0x16, 0x27, 0x18, 0x0F, 0x1A, 0x2A, 0x3A, 0x0F, 0x12, 0x22, 0x32, 0x0F, 0x14, 0x24, 0x34,
+];
+
+fn prg() -> Vec<u8> {
+    let mut prg = vec![0xEA; 0x8000];
+
+    prg[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+    prg[0x100..0x110].copy_from_slice(&PALETTE);
+    prg[0x110..0x120].copy_from_slice(&PALETTE);
+    prg[0x7FFC] = 0x00;
+    prg[0x7FFD] = 0x80;
+
+    prg
+}
+
+// タイル0は空、1は塗りつぶし、2は市松模様、3は斜め線
+fn chr() -> Vec<u8> {
+    let mut chr = vec![0; 0x2000];
+
+    for row in 0..8 {
+        chr[0x10 + row] = 0xFF;
+        chr[0x20 + row] = if row % 2 == 0 { 0xAA } else { 0x55 };
+        chr[0x28 + row] = if row % 2 == 0 { 0x55 } else { 0xAA };
+        chr[0x30 + row] = 0x80 >> row;
+        chr[0x38 + row] = 0x01 << row;
+    }
+
+    chr
+}
+
+#[test]
+fn background_tiles() -> Result<()> {
+    let mut nes = Nes::from_parts(prg(), chr(), 0, Mirroring::Horizontal)?;
+
+    for _ in 0..5 {
+        nes.run_frame()?;
+    }
+
+    common::assert_frame_matches(
+        &mut nes,
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/golden/background_tiles.png"
+        ),
+    )
+}